@@ -1,16 +1,25 @@
 use std::{
+	borrow::Cow,
+	fs,
 	io::{self, Read, Write},
 	path::{Path, PathBuf},
 	time::Instant,
 };
 
-use common::{FontStyle, FontVariant};
-use serde_json::Value;
+use common::{FontStyle, FontVariant, FontWeight};
+use serde::Serialize;
+use serde_json::{Map, Value};
 use std::fs::File;
+use textual::woff;
+use thiserror::Error;
 
 pub struct FontFamily {
 	pub face: String,
 	pub variants: Vec<(FontVariant, String)>,
+	/// Like serif or handwriting. Only known for fonts from the catalog
+	pub category: Option<String>,
+	/// Scripts the family covers, like latin or cyrillic
+	pub subsets: Vec<String>,
 }
 
 impl FontFamily {
@@ -18,6 +27,8 @@ impl FontFamily {
 		FontFamily {
 			face: face.into(),
 			variants: vec![],
+			category: None,
+			subsets: vec![],
 		}
 	}
 
@@ -75,7 +86,14 @@ impl FontCache {
 				let mut buffer = vec![];
 				file.read_to_end(&mut buffer).unwrap();
 
-				return Some(buffer);
+				// Fonts saved before we unpacked them may still be WOFF
+				return match unpack(&buffer) {
+					Ok(font) => Some(font),
+					Err(e) => {
+						eprintln!("Unable to unpack cached font {}: {}", path, e);
+						None
+					}
+				};
 			}
 		}
 
@@ -164,6 +182,65 @@ impl FontCache {
 	}
 }
 
+/// Which families to list. Everything that's set has to match.
+#[derive(Default)]
+pub struct FontFilter {
+	/// Part of the family's name, ignoring case
+	pub search: Option<String>,
+	pub category: Option<String>,
+	/// A script the family has to cover, like latin
+	pub subset: Option<String>,
+	/// The family needs a variant in this weight...
+	pub weight: Option<FontWeight>,
+	/// ...and style
+	pub style: Option<FontStyle>,
+}
+
+impl FontFilter {
+	fn matches(&self, family: &FontFamily) -> bool {
+		let search = self
+			.search
+			.as_ref()
+			.is_none_or(|search| family.face.to_lowercase().contains(&search.to_lowercase()));
+		let category = self.category.as_ref().is_none_or(|category| {
+			family
+				.category
+				.as_ref()
+				.is_some_and(|c| c.eq_ignore_ascii_case(category))
+		});
+		let subset = self.subset.as_ref().is_none_or(|subset| {
+			family
+				.subsets
+				.iter()
+				.any(|s| s.eq_ignore_ascii_case(subset))
+		});
+		let variant = family.variants.iter().any(|(variant, _)| {
+			self.weight.is_none_or(|weight| variant.weight == weight)
+				&& self.style.is_none_or(|style| variant.style == style)
+		});
+
+		search && category && subset && variant
+	}
+}
+
+/// What we know about a family, for listing it.
+#[derive(Serialize)]
+pub struct FamilyInfo<'a> {
+	pub family: &'a str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub category: Option<&'a str>,
+	pub subsets: &'a [String],
+	pub variants: Vec<VariantInfo>,
+}
+
+#[derive(Serialize)]
+pub struct VariantInfo {
+	pub weight: String,
+	pub style: String,
+	/// Whether we have it without going to Google
+	pub cached: bool,
+}
+
 pub struct FontProvider {
 	//default: Arc<Font>,
 	fonts: Vec<FontFamily>,
@@ -171,18 +248,46 @@ pub struct FontProvider {
 }
 
 impl FontProvider {
-	pub fn new<P: AsRef<Path>>(fontcache: P) -> Self {
-		let google = get_fonts_from_google().unwrap();
+	/// The font list comes from the snapshot at `catalog` if there is one.
+	/// Otherwise we ask Google and save what we get there. Without either,
+	/// only cached fonts are served.
+	pub fn new<P: AsRef<Path>, C: AsRef<Path>>(fontcache: P, catalog: C) -> Self {
+		let catalog = catalog.as_ref();
+
+		let fonts = match load_catalog(catalog) {
+			Ok(fonts) => {
+				tracing::info!("loaded {} families from {}", fonts.len(), catalog.display());
+				fonts
+			}
+			Err(e) => {
+				tracing::warn!("no font catalog at {}: {e}", catalog.display());
+
+				match sync_catalog(catalog) {
+					Ok(fonts) => fonts,
+					Err(e) => {
+						tracing::error!(
+							"failed to get the font catalog, only cached fonts will work: {e}"
+						);
+						vec![]
+					}
+				}
+			}
+		};
 
 		Self {
 			/*default: Arc::new(
 				fontster::parse_font(include_bytes!("../Cabin-Regular.ttf")).unwrap(),
 			),*/
-			fonts: google,
+			fonts,
 			font_cache: FontCache::new(fontcache.as_ref()).unwrap(),
 		}
 	}
 
+	/// Replace the font list, after getting a new one with [sync_catalog].
+	pub fn set_fonts(&mut self, fonts: Vec<FontFamily>) {
+		self.fonts = fonts;
+	}
+
 	pub fn cached(&self) -> usize {
 		self.font_cache
 			.fonts
@@ -204,6 +309,56 @@ impl FontProvider {
 		None
 	}
 
+	/// Every family that matches the filter, sorted by name. Families we only
+	/// have in the cache are listed too.
+	pub fn search(&self, filter: &FontFilter) -> Vec<FamilyInfo<'_>> {
+		let cached_only = self
+			.font_cache
+			.fonts
+			.iter()
+			.filter(|fam| self.family(&fam.face).is_none());
+
+		let mut found: Vec<FamilyInfo> = self
+			.fonts
+			.iter()
+			.chain(cached_only)
+			.filter(|fam| filter.matches(fam))
+			.map(|fam| self.info(fam))
+			.collect();
+
+		found.sort_unstable_by(|a, b| a.family.cmp(b.family));
+		found
+	}
+
+	/// Everything we know about one family, if we know it at all.
+	pub fn describe<S: AsRef<str>>(&self, family: S) -> Option<FamilyInfo<'_>> {
+		self.family(family.as_ref())
+			.or_else(|| self.font_cache.family(family.as_ref()))
+			.map(|fam| self.info(fam))
+	}
+
+	fn info<'a>(&'a self, family: &'a FontFamily) -> FamilyInfo<'a> {
+		let cached = self.font_cache.family(&family.face);
+
+		let mut variants: Vec<FontVariant> = family.variants.iter().map(|(v, _)| *v).collect();
+		variants.sort_unstable_by_key(|v| (v.style as u8, v.weight.into_weight_number()));
+		variants.dedup();
+
+		FamilyInfo {
+			family: &family.face,
+			category: family.category.as_deref(),
+			subsets: &family.subsets,
+			variants: variants
+				.into_iter()
+				.map(|variant| VariantInfo {
+					weight: variant.weight.to_string(),
+					style: variant.style.to_string(),
+					cached: cached.and_then(|fam| fam.variant_path(variant)).is_some(),
+				})
+				.collect(),
+		}
+	}
+
 	pub fn variant_cached<F: Into<String>>(&self, family: F, variant: FontVariant) -> CachedFont {
 		let family_string = family.into();
 
@@ -234,6 +389,15 @@ impl FontProvider {
 				let mut buffer: Vec<u8> = Vec::new();
 				response.into_reader().read_to_end(&mut buffer).unwrap();
 
+				// Cache and pass on what's inside WOFF and WOFF2, so it's only unpacked once
+				let buffer = match unpack(&buffer) {
+					Ok(font) => font,
+					Err(e) => {
+						eprintln!("Unable to unpack {} {}: {}", family_string, variant, e);
+						return None;
+					}
+				};
+
 				self.font_cache.save_font(family_string, variant, &buffer);
 
 				return Some(buffer);
@@ -248,6 +412,11 @@ impl FontProvider {
 	}
 }
 
+/// Unpack WOFF and WOFF2 fonts into their sfnt. Anything else is kept as it is.
+fn unpack(data: &[u8]) -> Result<Vec<u8>, woff::WoffError> {
+	woff::decode(data).map(Cow::into_owned)
+}
+
 pub enum CachedFont {
 	/// We have it in the cache, here it is
 	Available { font: Vec<u8> },
@@ -257,28 +426,102 @@ pub enum CachedFont {
 	Unknown,
 }
 
-fn get_fonts_from_google() -> Result<Vec<FontFamily>, ureq::Error> {
+/// What we keep of each family when saving a snapshot of the font list.
+const SNAPSHOT_FIELDS: &[&str] = &[
+	"family",
+	"category",
+	"subsets",
+	"variants",
+	"files",
+	"lastModified",
+];
+
+/// Read a snapshot of the font list.
+pub fn load_catalog<P: AsRef<Path>>(path: P) -> Result<Vec<FontFamily>, CatalogError> {
+	parse_catalog(&fs::read_to_string(path)?)
+}
+
+/// Get the font list from Google and save a snapshot of it to `path`. This
+/// blocks on the network.
+pub fn sync_catalog<P: AsRef<Path>>(path: P) -> Result<Vec<FontFamily>, CatalogError> {
+	let snapshot = download_catalog()?;
+	let fonts = parse_catalog(&snapshot)?;
+
+	// Written aside and moved into place so nobody reads half a file
+	let path = path.as_ref();
+	let temporary = path.with_extension("tmp");
+	fs::write(&temporary, snapshot)?;
+	fs::rename(&temporary, path)?;
+
+	Ok(fonts)
+}
+
+/// The API's response with only the fields we use.
+fn download_catalog() -> Result<String, CatalogError> {
 	let api_str = format!(
 		"https://www.googleapis.com/webfonts/v1/webfonts?key={}",
 		include_str!("webfont.key")
 	);
 
 	let before = Instant::now();
-	let response = ureq::get(&api_str).call()?;
-	let json: Value = serde_json::from_str(&response.into_string()?).unwrap();
+	let response = ureq::get(&api_str).call().map_err(Box::new)?;
+	let json: Value = serde_json::from_str(&response.into_string()?)?;
+
+	let items: Vec<Value> = json["items"]
+		.as_array()
+		.ok_or(CatalogError::NoItems)?
+		.iter()
+		.filter_map(|item| item.as_object())
+		.map(|item| {
+			let kept: Map<String, Value> = item
+				.iter()
+				.filter(|(key, _)| SNAPSHOT_FIELDS.contains(&key.as_str()))
+				.map(|(key, value)| (key.clone(), value.clone()))
+				.collect();
+
+			Value::Object(kept)
+		})
+		.collect();
+
+	tracing::info!(
+		"getting font list took {}s",
+		Instant::now().duration_since(before).as_secs()
+	);
+
+	Ok(serde_json::to_string_pretty(
+		&serde_json::json!({ "items": items }),
+	)?)
+}
+
+/// The families in a Google Fonts API response, or a snapshot of one.
+fn parse_catalog(snapshot: &str) -> Result<Vec<FontFamily>, CatalogError> {
+	let json: Value = serde_json::from_str(snapshot)?;
 
 	let fonts = match &json["items"] {
 		Value::Array(fonts) => fonts,
-		_ => panic!(),
+		_ => return Err(CatalogError::NoItems),
 	};
 
 	let mut ret = vec![];
 
 	for item in fonts {
-		let name = item["family"].as_str().unwrap();
+		let (name, files) = match (item["family"].as_str(), item["files"].as_object()) {
+			(Some(name), Some(files)) => (name, files),
+			_ => continue,
+		};
 		let mut family = FontFamily::new(name);
-
-		for (style, filepath) in item["files"].as_object().unwrap() {
+		family.category = item["category"].as_str().map(<_>::to_owned);
+		family.subsets = item["subsets"]
+			.as_array()
+			.map(|subsets| {
+				subsets
+					.iter()
+					.filter_map(|subset| subset.as_str().map(<_>::to_owned))
+					.collect()
+			})
+			.unwrap_or_default();
+
+		for (style, filepath) in files {
 			// Font styles can be one of three things...
 			let variant = if style == "regular" {
 				// ...just the word "regular" which means normal weight and style
@@ -288,19 +531,31 @@ fn get_fonts_from_google() -> Result<Vec<FontFamily>, ureq::Error> {
 				FontVariant::new(weight.parse().unwrap_or_default(), FontStyle::Italic)
 			} else {
 				// ...just the weight
-				FontVariant::with_weight(style.parse().unwrap())
+				match style.parse() {
+					Ok(weight) => FontVariant::with_weight(weight),
+					Err(_) => continue,
+				}
 			};
 
-			family.push(variant, filepath.as_str().unwrap());
+			if let Some(filepath) = filepath.as_str() {
+				family.push(variant, filepath);
+			}
 		}
 
 		ret.push(family);
 	}
 
-	println!(
-		"getting font list took {}s",
-		Instant::now().duration_since(before).as_secs()
-	);
-
 	Ok(ret)
 }
+
+#[derive(Debug, Error)]
+pub enum CatalogError {
+	#[error("{0}")]
+	Io(#[from] io::Error),
+	#[error("{0}")]
+	Request(#[from] Box<ureq::Error>),
+	#[error("{0}")]
+	Json(#[from] serde_json::Error),
+	#[error("the font list has no items")]
+	NoItems,
+}
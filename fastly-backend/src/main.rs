@@ -1,15 +1,15 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
 	body::{Bytes, Full},
-	extract::Path,
+	extract::{Path, Query},
 	http::StatusCode,
 	response::{IntoResponse, Response},
 	routing::get,
-	Extension, Router,
+	Extension, Json, Router,
 };
 use common::{FontStyle, FontVariant, FontWeight};
-use fontprovider::CachedFont;
+use fontprovider::{CachedFont, FontFilter};
 use serde::Deserialize;
 use tokio::sync::RwLock;
 
@@ -21,12 +21,33 @@ mod fontprovider;
 async fn main() {
 	tracing_subscriber::fmt::init();
 
-	let provider = FontProvider::new("fonts");
+	// `textual fonts sync` writes this, or we make it the first time we start
+	let catalog = PathBuf::from(
+		std::env::var("TEXTUAL_FONT_CATALOG").unwrap_or_else(|_| "catalog.json".into()),
+	);
+	let provider = {
+		let catalog = catalog.clone();
+		tokio::task::spawn_blocking(move || FontProvider::new("fonts", catalog))
+			.await
+			.unwrap()
+	};
+	let provider = Arc::new(RwLock::new(provider));
+
+	let refresh = std::env::var("TEXTUAL_FONT_CATALOG_REFRESH")
+		.ok()
+		.and_then(|secs| secs.parse().ok())
+		.filter(|secs| *secs > 0)
+		.map(Duration::from_secs);
+	if let Some(every) = refresh {
+		tokio::spawn(refresh_catalog(provider.clone(), catalog, every));
+	}
 
 	let app = Router::new()
 		.route("/font/:family/:style/:weight", get(fonts))
+		.route("/fonts", get(list))
+		.route("/fonts/:family", get(family))
 		.route("/ping", get(ping))
-		.layer(Extension(Arc::new(RwLock::new(provider))));
+		.layer(Extension(provider));
 
 	let addr = SocketAddr::from(([0, 0, 0, 0], 2561));
 	tracing::debug!("listening on {addr}");
@@ -85,6 +106,90 @@ async fn fonts(
 		.into_response()
 }
 
+/// Get the font list from Google every so often, keeping the old one if that
+/// fails.
+async fn refresh_catalog(provider: Arc<RwLock<FontProvider>>, catalog: PathBuf, every: Duration) {
+	loop {
+		tokio::time::sleep(every).await;
+
+		let path = catalog.clone();
+		match tokio::task::spawn_blocking(move || fontprovider::sync_catalog(path)).await {
+			Ok(Ok(fonts)) => {
+				tracing::info!("refreshed the font catalog, {} families", fonts.len());
+				provider.write().await.set_fonts(fonts);
+			}
+			Ok(Err(e)) => tracing::warn!("failed to refresh the font catalog: {e}"),
+			Err(e) => tracing::warn!("failed to refresh the font catalog: {e}"),
+		}
+	}
+}
+
+/// How many families /fonts lists at a time, unless asked for more.
+const FONTS_PER_PAGE: usize = 50;
+
+/// The most families /fonts will list at a time.
+const MAX_FONTS_PER_PAGE: usize = 500;
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+	search: Option<String>,
+	category: Option<String>,
+	subset: Option<String>,
+	weight: Option<FontWeight>,
+	style: Option<FontStyle>,
+	page: Option<usize>,
+	per_page: Option<usize>,
+}
+
+/// The font families we know of, a page at a time. They can be filtered by
+/// name, category, subset, and the weights and styles they come in.
+async fn list(
+	provider: Extension<Arc<RwLock<FontProvider>>>,
+	Query(query): Query<ListQuery>,
+) -> Response {
+	let page = query.page.unwrap_or(1).max(1);
+	let per_page = query
+		.per_page
+		.unwrap_or(FONTS_PER_PAGE)
+		.clamp(1, MAX_FONTS_PER_PAGE);
+
+	let filter = FontFilter {
+		search: query.search,
+		category: query.category,
+		subset: query.subset,
+		weight: query.weight,
+		style: query.style,
+	};
+
+	let provider = provider.read().await;
+	let found = provider.search(&filter);
+	let total = found.len();
+	let fonts: Vec<_> = found
+		.into_iter()
+		.skip((page - 1).saturating_mul(per_page))
+		.take(per_page)
+		.collect();
+
+	Json(serde_json::json!({
+		"total": total,
+		"page": page,
+		"per_page": per_page,
+		"fonts": fonts,
+	}))
+	.into_response()
+}
+
+/// Everything we know about a font family.
+async fn family(
+	provider: Extension<Arc<RwLock<FontProvider>>>,
+	Path(family): Path<String>,
+) -> Response {
+	match provider.read().await.describe(&family) {
+		Some(info) => Json(info).into_response(),
+		None => (StatusCode::NOT_FOUND, "not found").into_response(),
+	}
+}
+
 async fn ping() -> Response {
 	tracing::debug!("pinged!");
 
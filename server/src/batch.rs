@@ -0,0 +1,388 @@
+use std::{
+	fs, io,
+	path::{Component, Path, PathBuf},
+	sync::Arc,
+};
+
+use mavourings::query::Query;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::sync::{RwLock, Semaphore};
+
+use crate::color::Gamma;
+use crate::config::Config;
+use crate::fontprovider::{Catalog, FontProvider};
+use crate::raster::Rasterizer;
+use crate::text::{Limits, Operation};
+
+/// One image to draw and where it goes.
+struct Job {
+	/// Where the job came from, for messages
+	source: String,
+	output: PathBuf,
+	op: Operation,
+}
+
+/// A line of a JSONL manifest. Each has an output and either a query or a
+/// whole operation.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestLine {
+	output: String,
+	query: Option<String>,
+	operation: Option<Operation>,
+}
+
+/// Render every input into `out`, `jobs` at a time, without the server.
+/// Returns how many images failed.
+///
+/// An input is a query string, a JSON operation file, or a manifest. CSV
+/// manifests are `output,query` lines and JSONL manifests are objects like
+/// `{"output": "title.png", "query": "text=Hi"}` or with an `operation`
+/// instead of a query.
+pub async fn render(config: &Config, inputs: &[String], out: &Path, jobs: usize) -> usize {
+	let mut work = vec![];
+	let mut failed = 0;
+
+	for input in inputs {
+		match read_input(input) {
+			Ok(mut read) => work.append(&mut read),
+			Err(e) => {
+				eprintln!("{}", e);
+				failed += 1;
+			}
+		}
+	}
+
+	if let Err(e) = fs::create_dir_all(out) {
+		eprintln!("Failed to create {}: {}", out.display(), e);
+		return failed + work.len();
+	}
+
+	let mut provider = match FontProvider::new(
+		config.font_cache_path(),
+		config.font_cache_size(),
+		config.font_memory_cache(),
+	) {
+		Ok(provider) => provider,
+		Err(e) => {
+			eprintln!("{}", e);
+			return failed + work.len();
+		}
+	};
+
+	// Without a key we only have the fonts on disk, which is usually the point
+	let catalog = match config.google_fonts_key() {
+		None => Catalog::default(),
+		Some(key) => {
+			let key = key.to_owned();
+			match tokio::task::spawn_blocking(move || Catalog::fetch(&key)).await {
+				Ok(Ok(catalog)) => catalog,
+				Ok(Err(e)) => {
+					eprintln!("{}, only cached fonts can be used", e);
+					Catalog::default()
+				}
+				Err(e) => {
+					eprintln!("Failed to get the font catalog: {}", e);
+					Catalog::default()
+				}
+			}
+		}
+	};
+	provider.set_catalog(catalog);
+	provider.set_local(crate::localfonts::scan(
+		config.font_directories(),
+		config.system_fonts(),
+	));
+
+	let provider = Arc::new(RwLock::new(provider));
+	let rasterizer = Arc::new(Rasterizer::new(
+		Gamma::new(config.gamma(), config.contrast()),
+		config.hint_below(),
+		config.glyph_cache(),
+	));
+	let limits = config.limits();
+	let permits = Arc::new(Semaphore::new(jobs.max(1)));
+
+	let mut handles = vec![];
+	for job in work {
+		let provider = provider.clone();
+		let rasterizer = rasterizer.clone();
+		let permits = permits.clone();
+		let output = out.join(out_path(&job.output));
+
+		handles.push(tokio::spawn(async move {
+			let _permit = permits.acquire_owned().await;
+			let result = render_job(job.op, &provider, &rasterizer, &limits)
+				.await
+				.and_then(|png| {
+					write(&output, &png).map_err(|e| format!("Failed to write: {}", e))
+				});
+
+			(job.source, output, result)
+		}));
+	}
+
+	for handle in handles {
+		match handle.await {
+			Ok((_, output, Ok(()))) => println!("{}", output.display()),
+			Ok((source, _, Err(e))) => {
+				eprintln!("{}: {}", source, e);
+				failed += 1;
+			}
+			Err(e) => {
+				eprintln!("{}", e);
+				failed += 1;
+			}
+		}
+	}
+
+	failed
+}
+
+async fn render_job(
+	op: Operation,
+	provider: &RwLock<FontProvider>,
+	rasterizer: &Rasterizer,
+	limits: &Limits,
+) -> Result<Vec<u8>, String> {
+	let (image, _counts) = op
+		.make_image(provider, rasterizer, limits)
+		.await
+		.map_err(|e| e.to_string())?;
+
+	crate::encode_png(&image)
+		.map(|bytes| bytes.to_vec())
+		.map_err(|e| e.to_string())
+}
+
+/// Manifests can put images in subdirectories.
+fn write(path: &Path, png: &[u8]) -> io::Result<()> {
+	if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+
+	fs::write(path, png)
+}
+
+/// Manifest outputs are relative to the output directory and get a .png
+/// extension if they don't have one.
+fn out_path(output: &Path) -> PathBuf {
+	if output.extension().is_some() {
+		output.to_owned()
+	} else {
+		output.with_extension("png")
+	}
+}
+
+/// Turn an input from the command line into jobs. Existing files are read by
+/// their extension, anything else has to be a query string.
+fn read_input(input: &str) -> Result<Vec<Job>, BatchError> {
+	let path = Path::new(input);
+
+	if !path.is_file() {
+		if !input.contains('=') {
+			return Err(BatchError::UnknownInput(input.into()));
+		}
+
+		let op = parse_query(input)?;
+		return Ok(vec![Job {
+			source: input.into(),
+			output: PathBuf::from(query_name(&op)),
+			op,
+		}]);
+	}
+
+	let read =
+		|path: &Path| fs::read_to_string(path).map_err(|e| BatchError::Read(path.to_owned(), e));
+
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some("json") => {
+			let op = serde_json::from_str(&read(path)?).map_err(|e| BatchError::Manifest {
+				at: input.into(),
+				reason: e.to_string(),
+			})?;
+
+			Ok(vec![Job {
+				source: input.into(),
+				output: PathBuf::from(path.file_stem().unwrap_or_default()),
+				op,
+			}])
+		}
+		Some("csv") => parse_csv(input, &read(path)?),
+		Some("jsonl") => parse_jsonl(input, &read(path)?),
+		_ => Err(BatchError::UnknownInput(input.into())),
+	}
+}
+
+fn parse_query(query: &str) -> Result<Operation, BatchError> {
+	let query: Query = query
+		.trim_start_matches('?')
+		.parse()
+		.map_err(|_| BatchError::InvalidQuery(query.into()))?;
+
+	// Like the server we draw what we can, but say what we couldn't
+	let (op, problems) = Operation::parse(query);
+	for problem in problems {
+		eprintln!("warning: {}", problem);
+	}
+
+	Ok(op)
+}
+
+/// Queries from the command line are named after what they draw so running
+/// the same one twice doesn't make two images.
+fn query_name(op: &Operation) -> String {
+	Sha256::digest(op.canonical_query().as_bytes())
+		.iter()
+		.take(8)
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
+/// `output,query` lines. Blank lines, `#` comments, and an `output,query`
+/// header are skipped. The query may be in double quotes.
+fn parse_csv(source: &str, manifest: &str) -> Result<Vec<Job>, BatchError> {
+	let mut jobs = vec![];
+
+	for (number, line) in manifest.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') || line.eq_ignore_ascii_case("output,query") {
+			continue;
+		}
+
+		let source = format!("{}:{}", source, number + 1);
+		let (output, query) = line.split_once(',').ok_or_else(|| BatchError::Manifest {
+			at: source.clone(),
+			reason: "expected output,query".into(),
+		})?;
+
+		let query = query.trim();
+		let query = match query.strip_prefix('"').and_then(|q| q.strip_suffix('"')) {
+			Some(quoted) => quoted.replace("\"\"", "\""),
+			None => query.to_owned(),
+		};
+
+		jobs.push(Job {
+			output: manifest_output(&source, output.trim())?,
+			op: parse_query(&query)?,
+			source,
+		});
+	}
+
+	Ok(jobs)
+}
+
+/// Outputs can go in subdirectories, but they can't leave the output directory.
+fn manifest_output(source: &str, output: &str) -> Result<PathBuf, BatchError> {
+	let path = PathBuf::from(output);
+
+	let inside = path
+		.components()
+		.all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+	if !inside {
+		return Err(BatchError::Manifest {
+			at: source.into(),
+			reason: format!("the output '{}' isn't inside the output directory", output),
+		});
+	}
+
+	Ok(path)
+}
+
+fn parse_jsonl(source: &str, manifest: &str) -> Result<Vec<Job>, BatchError> {
+	let mut jobs = vec![];
+
+	for (number, line) in manifest.lines().enumerate() {
+		if line.trim().is_empty() {
+			continue;
+		}
+
+		let source = format!("{}:{}", source, number + 1);
+		let invalid = |reason: String| BatchError::Manifest {
+			at: source.clone(),
+			reason,
+		};
+
+		let line: ManifestLine = serde_json::from_str(line).map_err(|e| invalid(e.to_string()))?;
+		let op = match (line.query, line.operation) {
+			(Some(query), None) => parse_query(&query)?,
+			(None, Some(op)) => op,
+			_ => return Err(invalid("expected either a query or an operation".into())),
+		};
+
+		jobs.push(Job {
+			output: manifest_output(&source, &line.output)?,
+			op,
+			source,
+		});
+	}
+
+	Ok(jobs)
+}
+
+#[derive(Debug, Error)]
+pub enum BatchError {
+	#[error("Failed to read {}: {1}", .0.display())]
+	Read(PathBuf, io::Error),
+	#[error("'{0}' isn't a query string or a .json, .csv, or .jsonl file")]
+	UnknownInput(String),
+	#[error("Could not parse the query string '{0}'")]
+	InvalidQuery(String),
+	#[error("{at}: {reason}")]
+	Manifest { at: String, reason: String },
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn csv_manifest() {
+		let manifest = "output,query\n\
+			# titles\n\
+			\n\
+			hello,text=Hello\n\
+			quoted.png,\"text=a,b\"\n";
+
+		let jobs = parse_csv("titles.csv", manifest).unwrap();
+		assert_eq!(jobs.len(), 2);
+		assert_eq!(jobs[0].output, PathBuf::from("hello"));
+		assert_eq!(jobs[0].source, "titles.csv:4");
+		assert_eq!(jobs[1].output, PathBuf::from("quoted.png"));
+		assert_eq!(jobs[1].op.full_text(), "a,b");
+	}
+
+	#[test]
+	fn jsonl_manifest() {
+		let manifest = r#"{"output": "a", "query": "text=A"}
+			{"output": "b", "operation": {"texts": [{"text": "B"}]}}"#;
+
+		let jobs = parse_jsonl("titles.jsonl", manifest).unwrap();
+		assert_eq!(jobs[0].op.full_text(), "A");
+		assert_eq!(jobs[1].op.full_text(), "B");
+
+		let neither = r#"{"output": "c"}"#;
+		assert!(parse_jsonl("titles.jsonl", neither).is_err());
+	}
+
+	#[test]
+	fn outputs_stay_inside() {
+		assert!(parse_csv("titles.csv", "cards/hello,text=Hi").is_ok());
+		assert!(parse_csv("titles.csv", "/etc/hello,text=Hi").is_err());
+		assert!(parse_csv("titles.csv", "cards/../../hello,text=Hi").is_err());
+
+		let escaping = r#"{"output": "../hello", "query": "text=Hi"}"#;
+		assert!(matches!(
+			parse_jsonl("titles.jsonl", escaping),
+			Err(BatchError::Manifest { .. })
+		));
+	}
+
+	#[test]
+	fn output_names() {
+		assert_eq!(out_path(Path::new("title")), PathBuf::from("title.png"));
+		assert_eq!(out_path(Path::new("title.png")), PathBuf::from("title.png"));
+	}
+}
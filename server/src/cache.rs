@@ -0,0 +1,274 @@
+use std::{
+	fs, io,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::SystemTime,
+};
+
+use hyper::body::Bytes;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::lru::Lru;
+
+/// Keeps encoded images around so identical requests don't render again.
+/// Images are kept in memory and, if configured, on disk.
+pub struct ResponseCache {
+	/// Mixed into every key so changing how we render invalidates old entries
+	salt: String,
+	memory: Mutex<Lru<String, Bytes>>,
+	disk: Option<Arc<DiskCache>>,
+}
+
+impl ResponseCache {
+	pub fn new<S: Into<String>>(salt: S, memory_budget: usize, disk: Option<DiskCache>) -> Self {
+		Self {
+			salt: salt.into(),
+			memory: Mutex::new(Lru::new(memory_budget)),
+			disk: disk.map(Arc::new),
+		}
+	}
+
+	/// Change how many bytes of images to keep in memory. The disk cache's
+	/// budget is fixed when it's opened.
+	pub fn set_memory_budget(&self, budget: usize) {
+		self.memory.lock().unwrap().set_budget(budget);
+	}
+
+	/// Get the key for an operation's canonical query and the mime type it
+	/// will be encoded as. This doubles as the response's strong ETag.
+	pub fn key(&self, canonical: &str, mime: &str) -> String {
+		let mut hasher = Sha256::new();
+		hasher.update(self.salt.as_bytes());
+		hasher.update(b"\n");
+		hasher.update(mime.as_bytes());
+		hasher.update(b"\n");
+		hasher.update(canonical.as_bytes());
+
+		hasher
+			.finalize()
+			.iter()
+			.map(|byte| format!("{:02x}", byte))
+			.collect()
+	}
+
+	/// Look in memory first, then on disk. Disk hits are brought into memory.
+	pub async fn get(&self, key: &str) -> Option<Bytes> {
+		if let Some(bytes) = self.memory.lock().unwrap().get(key) {
+			return Some(bytes.clone());
+		}
+
+		let disk = self.disk.clone()?;
+		let owned = key.to_owned();
+		let data = tokio::task::spawn_blocking(move || disk.get(&owned))
+			.await
+			.ok()??;
+
+		let bytes = Bytes::from(data);
+		self.memory
+			.lock()
+			.unwrap()
+			.insert(key.to_owned(), bytes.clone(), bytes.len());
+
+		Some(bytes)
+	}
+
+	/// The disk is written in the background. Until it's done the image is
+	/// still in memory.
+	pub fn insert(&self, key: String, bytes: Bytes) {
+		if let Some(disk) = self.disk.clone() {
+			let (key, bytes) = (key.clone(), bytes.clone());
+			tokio::task::spawn_blocking(move || {
+				if let Err(e) = disk.insert(&key, &bytes) {
+					warn!(%key, error = %e, "failed to write to the disk cache");
+				}
+			});
+		}
+
+		let len = bytes.len();
+		self.memory.lock().unwrap().insert(key, bytes, len);
+	}
+}
+
+/// Encoded images stored in a directory, named by their key.
+pub struct DiskCache {
+	location: PathBuf,
+	index: Mutex<Lru<String, ()>>,
+}
+
+impl DiskCache {
+	/// Index what's already in `location`, oldest first, so the least recently
+	/// written files are evicted first. Files over the budget are removed, as
+	/// are writes a crash left unfinished.
+	pub fn new<P: Into<PathBuf>>(location: P, budget: usize) -> io::Result<Self> {
+		let location = location.into();
+		let mut found: Vec<(SystemTime, String, usize)> = vec![];
+
+		for entry in fs::read_dir(&location)? {
+			let entry = entry?;
+			let meta = entry.metadata()?;
+
+			let name = match entry.file_name().into_string() {
+				Ok(name) if meta.is_file() => name,
+				_ => continue,
+			};
+
+			match name.strip_suffix(".tmp") {
+				Some(key) if Self::is_key(key) => {
+					Self::remove_file(&location, &name);
+					continue;
+				}
+				_ if !Self::is_key(&name) => continue,
+				_ => (),
+			}
+
+			found.push((
+				meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+				name,
+				meta.len() as usize,
+			));
+		}
+
+		found.sort();
+
+		let mut index = Lru::new(budget);
+		for (_, key, size) in found {
+			for (evicted, _) in index.insert(key, (), size) {
+				Self::remove_file(&location, &evicted);
+			}
+		}
+
+		info!(images = index.len(), "loaded the disk cache");
+
+		Ok(Self {
+			location,
+			index: Mutex::new(index),
+		})
+	}
+
+	fn get(&self, key: &str) -> Option<Vec<u8>> {
+		self.index.lock().unwrap().get(key)?;
+
+		match fs::read(self.location.join(key)) {
+			Ok(data) => Some(data),
+			Err(_) => {
+				// Someone removed it out from under us
+				self.index.lock().unwrap().remove(key);
+				None
+			}
+		}
+	}
+
+	fn insert(&self, key: &str, data: &[u8]) -> io::Result<()> {
+		// Write somewhere else first so a crash never leaves a partial image
+		// under a real key.
+		let temporary = self.location.join(format!("{}.tmp", key));
+		fs::write(&temporary, data)?;
+		fs::rename(&temporary, self.location.join(key))?;
+
+		let evicted = self
+			.index
+			.lock()
+			.unwrap()
+			.insert(key.to_owned(), (), data.len());
+
+		for (evicted, _) in evicted {
+			Self::remove_file(&self.location, &evicted);
+		}
+
+		Ok(())
+	}
+
+	fn remove_file(location: &Path, name: &str) {
+		if let Err(e) = fs::remove_file(location.join(name)) {
+			warn!(%name, error = %e, "failed to remove from the disk cache");
+		}
+	}
+
+	/// Keys are hex encoded SHA-256 hashes. Anything else in the directory
+	/// isn't ours.
+	fn is_key(name: &str) -> bool {
+		name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// A directory of its own for a test, removed afterwards.
+	struct Scratch(PathBuf);
+
+	impl Scratch {
+		fn new(test: &str) -> Self {
+			let path = std::env::temp_dir().join(format!(
+				"textual-responses-{}-{}",
+				test,
+				std::process::id()
+			));
+			let _ = fs::remove_dir_all(&path);
+			fs::create_dir_all(&path).unwrap();
+			Self(path)
+		}
+
+		fn files(&self) -> Vec<String> {
+			let mut files: Vec<String> = fs::read_dir(&self.0)
+				.unwrap()
+				.map(|dirent| dirent.unwrap().file_name().to_string_lossy().into_owned())
+				.collect();
+			files.sort();
+			files
+		}
+	}
+
+	impl Drop for Scratch {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	#[test]
+	fn removes_unfinished_writes() {
+		let scratch = Scratch::new("unfinished");
+		let key = "a".repeat(64);
+		fs::write(scratch.0.join(&key), b"image").unwrap();
+		fs::write(scratch.0.join(format!("{}.tmp", key)), b"ima").unwrap();
+		fs::write(scratch.0.join("notes.tmp"), b"not ours").unwrap();
+
+		let disk = DiskCache::new(&scratch.0, 1024).unwrap();
+		assert_eq!(scratch.files(), vec![key.clone(), "notes.tmp".to_owned()]);
+		assert_eq!(disk.get(&key).unwrap(), b"image");
+	}
+
+	#[tokio::test]
+	async fn reads_back_from_disk() {
+		let scratch = Scratch::new("reads-back");
+		let key = "b".repeat(64);
+		{
+			let responses = ResponseCache::new(
+				"salt",
+				1024,
+				Some(DiskCache::new(&scratch.0, 1024).unwrap()),
+			);
+			responses.insert(key.clone(), Bytes::from_static(b"image"));
+			assert_eq!(responses.get(&key).await.unwrap(), &b"image"[..]);
+		}
+
+		// Wait for the write, which happens in the background
+		for _ in 0..100 {
+			if scratch.files() == vec![key.clone()] {
+				break;
+			}
+			tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+		}
+
+		// Nothing in memory now, so it has to come from disk
+		let responses = ResponseCache::new(
+			"salt",
+			1024,
+			Some(DiskCache::new(&scratch.0, 1024).unwrap()),
+		);
+		assert_eq!(responses.get(&key).await.unwrap(), &b"image"[..]);
+		assert!(responses.get(&"c".repeat(64)).await.is_none());
+	}
+}
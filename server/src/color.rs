@@ -30,6 +30,58 @@ impl Color {
 	}
 }
 
+/// Moves colour components between their gamma-encoded form, which is what
+/// we store in images, and linear light, which is where blending should happen.
+#[derive(Debug, Clone)]
+pub struct Gamma {
+	gamma: f32,
+	contrast: f32,
+	to_linear: [f32; 256],
+}
+
+impl Gamma {
+	/// `gamma` is the exponent used to decode components, 2.2 being close to
+	/// sRGB. `contrast` is clamped to 0.0..=1.0 and thickens glyph coverage to
+	/// make up for linear blending thinning out light-on-dark text.
+	pub fn new(gamma: f32, contrast: f32) -> Self {
+		let mut to_linear = [0.0; 256];
+		for (value, linear) in to_linear.iter_mut().enumerate() {
+			*linear = (value as f32 / 255.0).powf(gamma);
+		}
+
+		Self {
+			gamma,
+			contrast: contrast.clamp(0.0, 1.0),
+			to_linear,
+		}
+	}
+
+	/// Decode a gamma-encoded component into linear light.
+	pub fn decode(&self, component: u8) -> f32 {
+		self.to_linear[component as usize]
+	}
+
+	/// Encode linear light back into a component.
+	pub fn encode(&self, linear: f32) -> u8 {
+		(linear.clamp(0.0, 1.0).powf(1.0 / self.gamma) * 255.0).round() as u8
+	}
+
+	/// Adjust glyph coverage by our contrast. Fully covered and uncovered
+	/// pixels are left alone, the partially covered edges are pushed up.
+	pub fn coverage(&self, coverage: u8) -> u8 {
+		let cov = coverage as f32 / 255.0;
+		let adjusted = cov + self.contrast * cov * (1.0 - cov);
+
+		(adjusted * 255.0).round() as u8
+	}
+}
+
+impl Default for Gamma {
+	fn default() -> Self {
+		Self::new(2.2, 0.0)
+	}
+}
+
 impl From<Color> for [u8; 4] {
 	fn from(col: Color) -> Self {
 		[col.r, col.g, col.b, col.a]
@@ -89,4 +141,23 @@ mod test {
 		assert_eq!(Color::BLUE.as_hex(), "0000FFFF");
 		assert_eq!(Color::TRANSPARENT.as_hex(), "00000000");
 	}
+
+	#[test]
+	fn gamma_roundtrip() {
+		let gamma = Gamma::default();
+
+		for component in 0..=255u8 {
+			assert_eq!(gamma.encode(gamma.decode(component)), component);
+		}
+	}
+
+	#[test]
+	fn gamma_coverage() {
+		let gamma = Gamma::new(2.2, 0.5);
+
+		assert_eq!(gamma.coverage(0), 0);
+		assert_eq!(gamma.coverage(255), 255);
+		assert!(gamma.coverage(128) > 128);
+		assert_eq!(Gamma::default().coverage(128), 128);
+	}
 }
@@ -1,26 +1,359 @@
 use std::{
-	net::{AddrParseError, IpAddr, Ipv4Addr},
+	net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr},
 	num::ParseIntError,
 	path::{Path, PathBuf},
+	time::Duration,
 };
 
 use confindent::{Confindent, ParseError};
-use getopts::{Fail, Options};
+use getopts::{Fail, Matches, Options};
 use thiserror::Error;
+use tracing_subscriber::EnvFilter;
+
+use crate::text::Limits;
 
 pub struct Config {
+	location: PathBuf,
 	font_cache_path: PathBuf,
+	font_cache_size: usize,
+	font_memory_cache: usize,
+	font_directories: Vec<PathBuf>,
+	system_fonts: bool,
 	listen: IpAddr,
 	port: u16,
+	unix_socket: Option<PathBuf>,
+	tls: Option<TlsFiles>,
 	scheme: Option<String>,
 	meta_host: Option<String>,
+	gamma: f32,
+	contrast: f32,
+	hint_below: f32,
+	glyph_cache: usize,
+	response_cache: usize,
+	response_disk_cache: Option<PathBuf>,
+	response_disk_cache_size: usize,
+	max_age: u64,
+	limits: Limits,
+	trusted_proxies: Vec<IpAddr>,
+	render_rate: f64,
+	render_burst: f64,
+	font_rate: f64,
+	font_burst: f64,
+	signing_secret: Option<String>,
+	google_fonts_key: Option<String>,
+	font_catalog: Option<PathBuf>,
+	font_catalog_refresh: Option<Duration>,
+	cors_origins: Vec<String>,
+	metrics_file: Option<PathBuf>,
+	log_level: String,
+	log_format: LogFormat,
+	command: Option<Command>,
+}
+
+/// Where to find the certificate chain and private key, both PEM.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+	pub certificate: PathBuf,
+	pub key: PathBuf,
+}
+
+/// How log lines are written.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LogFormat {
+	/// Human readable lines
+	Text,
+	/// One JSON object per line
+	Json,
+}
+
+/// Something to do instead of running the server.
+pub enum Command {
+	/// Sign a query and print it, optionally expiring after a number of seconds
+	Sign { query: String, expires: Option<u64> },
+	/// Print every setting as we understand it
+	PrintConfig,
+	/// Draw images straight to disk. Jobs is how many to draw at once
+	Render {
+		inputs: Vec<String>,
+		out: PathBuf,
+		jobs: Option<usize>,
+	},
+	/// Save the font catalog to FontCatalog so we don't need the API to start
+	FontsSync,
+	/// Check every cached font and remove the broken ones
+	CacheVerify,
+	/// Remove files in the font cache that it doesn't know about
+	CachePrune,
+}
+
+/// Where settings come from. Command line flags win over `TEXTUAL_*`
+/// environment variables, which win over the config file.
+struct Layers {
+	matches: Matches,
+	conf: Option<Confindent>,
+}
+
+impl Layers {
+	/// A config file that was asked for has to exist. The default one
+	/// doesn't.
+	fn new(matches: Matches, location: PathBuf, explicit: bool) -> Result<Self, ConfigError> {
+		let conf = if explicit || location.exists() {
+			Some(Confindent::from_file(&location)?)
+		} else {
+			None
+		};
+
+		Ok(Self { matches, conf })
+	}
+
+	/// A config key from the environment or the file.
+	fn value(&self, key: &str) -> Option<String> {
+		env_value(key).or_else(|| {
+			self.conf
+				.as_ref()
+				.and_then(|conf| conf.child_value(key))
+				.map(|s| s.to_owned())
+		})
+	}
+
+	/// A config key that can also be set with a command line flag.
+	fn flag(&self, flag: &str, key: &str) -> Option<String> {
+		self.matches.opt_str(flag).or_else(|| self.value(key))
+	}
+}
+
+/// The environment variable for a config key. MetaHost is TEXTUAL_META_HOST.
+pub fn env_name(key: &str) -> String {
+	let mut name = String::from("TEXTUAL");
+
+	for c in key.chars() {
+		if c.is_ascii_uppercase() {
+			name.push('_');
+		}
+		name.push(c.to_ascii_uppercase());
+	}
+
+	name
+}
+
+/// A config key's environment variable, if it's set and not empty.
+fn env_value(key: &str) -> Option<String> {
+	std::env::var(env_name(key))
+		.ok()
+		.filter(|value| !value.is_empty())
+}
+
+/// Settings that only take effect when the server starts.
+const RESTART_REQUIRED: &[&str] = &[
+	"Listen",
+	"Port",
+	"UnixSocket",
+	"TlsCertificate",
+	"TlsKey",
+	"FontCache",
+	"FontCacheSize",
+	"FontMemoryCache",
+	"FontDirectories",
+	"SystemFonts",
+	"Gamma",
+	"Contrast",
+	"HintBelow",
+	"ResponseDiskCache",
+	"ResponseDiskCacheSize",
+	"MetricsFile",
+	"LogFormat",
+	"GoogleFontsKey",
+	"FontCatalog",
+];
+
+/// Settings that are never shown, only whether they're set.
+const SECRETS: &[&str] = &["SigningSecret", "GoogleFontsKey"];
+
+/// A setting that's different in a reloaded config.
+pub struct Change {
+	pub key: &'static str,
+	pub old: String,
+	pub new: String,
+}
+
+impl Change {
+	/// Whether the server has to restart for this to take effect.
+	pub fn needs_restart(&self) -> bool {
+		RESTART_REQUIRED.contains(&self.key)
+	}
 }
 
 impl Config {
+	/// The config file this was read from.
+	pub fn location(&self) -> &Path {
+		&self.location
+	}
+
+	/// Every setting by its config key, formatted for people. The signing
+	/// secret is never shown.
+	pub fn settings(&self) -> Vec<(&'static str, String)> {
+		let secret = |secret: &Option<String>| match secret {
+			Some(_) => "set".to_owned(),
+			None => "unset".to_owned(),
+		};
+
+		let path = |path: Option<&Path>| match path {
+			Some(path) => path.display().to_string(),
+			None => "unset".to_owned(),
+		};
+
+		let meta_host = match (self.scheme(), self.meta_host()) {
+			(Some(scheme), Some(host)) => format!("{}://{}", scheme, host),
+			(None, Some(host)) => host.to_owned(),
+			_ => "unset".into(),
+		};
+
+		let join = |list: Vec<String>| {
+			if list.is_empty() {
+				"none".to_owned()
+			} else {
+				list.join(" ")
+			}
+		};
+
+		vec![
+			("FontCache", self.font_cache_path.display().to_string()),
+			("FontCacheSize", self.font_cache_size.to_string()),
+			("FontMemoryCache", self.font_memory_cache.to_string()),
+			(
+				"FontDirectories",
+				join(
+					self.font_directories
+						.iter()
+						.map(|dir| dir.display().to_string())
+						.collect(),
+				),
+			),
+			("SystemFonts", self.system_fonts.to_string()),
+			("Listen", self.listen.to_string()),
+			("Port", self.port.to_string()),
+			("UnixSocket", path(self.unix_socket())),
+			(
+				"TlsCertificate",
+				path(self.tls().map(|tls| tls.certificate.as_path())),
+			),
+			("TlsKey", path(self.tls().map(|tls| tls.key.as_path()))),
+			("MetaHost", meta_host),
+			("Gamma", self.gamma.to_string()),
+			("Contrast", self.contrast.to_string()),
+			("HintBelow", self.hint_below.to_string()),
+			("GlyphCache", self.glyph_cache.to_string()),
+			("ResponseCache", self.response_cache.to_string()),
+			("ResponseDiskCache", path(self.response_disk_cache())),
+			(
+				"ResponseDiskCacheSize",
+				self.response_disk_cache_size.to_string(),
+			),
+			("MaxAge", self.max_age.to_string()),
+			("MaxPixels", self.limits.max_pixels.to_string()),
+			("MaxDimension", self.limits.max_dimension.to_string()),
+			("MaxGlyphs", self.limits.max_glyphs.to_string()),
+			("MaxTexts", self.limits.max_texts.to_string()),
+			("MaxFontSize", self.limits.max_font_size.to_string()),
+			(
+				"RenderTime",
+				self.limits.render_time.as_millis().to_string(),
+			),
+			(
+				"TrustedProxies",
+				join(
+					self.trusted_proxies
+						.iter()
+						.map(|ip| ip.to_string())
+						.collect(),
+				),
+			),
+			("RenderRate", self.render_rate.to_string()),
+			("RenderBurst", self.render_burst.to_string()),
+			("FontRate", self.font_rate.to_string()),
+			("FontBurst", self.font_burst.to_string()),
+			("SigningSecret", secret(&self.signing_secret)),
+			("GoogleFontsKey", secret(&self.google_fonts_key)),
+			("FontCatalog", path(self.font_catalog())),
+			(
+				"FontCatalogRefresh",
+				self.font_catalog_refresh
+					.map(|every| every.as_secs())
+					.unwrap_or(0)
+					.to_string(),
+			),
+			("CorsOrigins", join(self.cors_origins.clone())),
+			("MetricsFile", path(self.metrics_file())),
+			("LogLevel", self.log_level.clone()),
+			("LogFormat", format!("{:?}", self.log_format).to_lowercase()),
+		]
+	}
+
+	/// What's different in `new`. Changed secrets are reported without
+	/// showing them.
+	pub fn changes(&self, new: &Config) -> Vec<Change> {
+		let mut changes: Vec<Change> = self
+			.settings()
+			.into_iter()
+			.zip(new.settings())
+			.filter(|((_, old), (_, new))| old != new)
+			.map(|((key, old), (_, new))| Change { key, old, new })
+			.collect();
+
+		let secrets = [
+			(&self.signing_secret, &new.signing_secret),
+			(&self.google_fonts_key, &new.google_fonts_key),
+		];
+
+		for (key, (old, new)) in SECRETS.iter().zip(secrets.iter()) {
+			if old.is_some() && new.is_some() && old != new {
+				changes.push(Change {
+					key,
+					old: "set".into(),
+					new: "changed".into(),
+				});
+			}
+		}
+
+		changes
+	}
+
+	/// Every setting as it would be written in the config file, with where
+	/// else it could have come from.
+	pub fn print(&self) -> String {
+		let mut out = String::new();
+
+		for (key, value) in self.settings() {
+			out.push_str(&format!("{:<22} {:<32} # {}\n", key, value, env_name(key)));
+		}
+
+		out
+	}
+
 	pub fn font_cache_path(&self) -> &Path {
 		&self.font_cache_path
 	}
 
+	/// How many bytes of downloaded fonts to keep on disk.
+	pub fn font_cache_size(&self) -> usize {
+		self.font_cache_size
+	}
+
+	/// How many bytes of fonts to keep parsed in memory.
+	pub fn font_memory_cache(&self) -> usize {
+		self.font_memory_cache
+	}
+
+	/// Directories of fonts to use before the cache or the catalog.
+	pub fn font_directories(&self) -> &[PathBuf] {
+		&self.font_directories
+	}
+
+	/// Whether to use the fonts installed on this machine too.
+	pub fn system_fonts(&self) -> bool {
+		self.system_fonts
+	}
+
 	pub fn listen(&self) -> IpAddr {
 		self.listen
 	}
@@ -29,6 +362,16 @@ impl Config {
 		self.port
 	}
 
+	/// Listen here instead of on an IP and port.
+	pub fn unix_socket(&self) -> Option<&Path> {
+		self.unix_socket.as_deref()
+	}
+
+	/// Terminate TLS ourselves with these.
+	pub fn tls(&self) -> Option<&TlsFiles> {
+		self.tls.as_ref()
+	}
+
 	pub fn scheme(&self) -> Option<&str> {
 		self.scheme.as_deref()
 	}
@@ -37,8 +380,207 @@ impl Config {
 		self.meta_host.as_deref()
 	}
 
+	/// Exponent used to move colours into linear light for blending.
+	pub fn gamma(&self) -> f32 {
+		self.gamma
+	}
+
+	/// How much to thicken glyph edges, from 0.0 to 1.0.
+	pub fn contrast(&self) -> f32 {
+		self.contrast
+	}
+
+	/// Font sizes, in pixels, under which glyphs are snapped to the pixel grid.
+	pub fn hint_below(&self) -> f32 {
+		self.hint_below
+	}
+
+	/// How many bytes of rasterized glyphs to keep between requests.
+	pub fn glyph_cache(&self) -> usize {
+		self.glyph_cache
+	}
+
+	/// How many bytes of encoded images to keep in memory.
+	pub fn response_cache(&self) -> usize {
+		self.response_cache
+	}
+
+	/// Where to keep encoded images on disk, if anywhere.
+	pub fn response_disk_cache(&self) -> Option<&Path> {
+		self.response_disk_cache.as_deref()
+	}
+
+	/// How many bytes of encoded images to keep on disk.
+	pub fn response_disk_cache_size(&self) -> usize {
+		self.response_disk_cache_size
+	}
+
+	/// Seconds clients may cache an image before revalidating it.
+	pub fn max_age(&self) -> u64 {
+		self.max_age
+	}
+
+	/// How much work a single render may do.
+	pub fn limits(&self) -> Limits {
+		self.limits
+	}
+
+	/// Proxies whose X-Forwarded-For we believe.
+	pub fn trusted_proxies(&self) -> &[IpAddr] {
+		&self.trusted_proxies
+	}
+
+	/// Where the font catalog snapshot is. Loaded instead of asking the API
+	/// when we start.
+	pub fn font_catalog(&self) -> Option<&Path> {
+		self.font_catalog.as_deref()
+	}
+
+	/// How often to get a new font catalog and update the snapshot. None is
+	/// never.
+	pub fn font_catalog_refresh(&self) -> Option<Duration> {
+		self.font_catalog_refresh
+	}
+
+	/// Renders that miss the cache a client may make per minute. 0 is unlimited.
+	pub fn render_rate(&self) -> f64 {
+		self.render_rate
+	}
+
+	/// How many renders a client may make at once before being limited.
+	pub fn render_burst(&self) -> f64 {
+		self.render_burst
+	}
+
+	/// Fonts a client may have us download per minute. 0 is unlimited.
+	pub fn font_rate(&self) -> f64 {
+		self.font_rate
+	}
+
+	/// How many fonts a client may have us download at once.
+	pub fn font_burst(&self) -> f64 {
+		self.font_burst
+	}
+
+	/// Secret to verify signed links with. If set, every render must be signed.
+	pub fn signing_secret(&self) -> Option<&str> {
+		self.signing_secret.as_deref()
+	}
+
+	/// Origins browsers may fetch from us from. `*` allows any.
+	pub fn cors_origins(&self) -> &[String] {
+		&self.cors_origins
+	}
+
+	/// For the Google Fonts API. Without it only cached fonts can be used.
+	pub fn google_fonts_key(&self) -> Option<&str> {
+		self.google_fonts_key.as_deref()
+	}
+
+	/// Where metrics are saved so they survive a restart.
+	pub fn metrics_file(&self) -> Option<&Path> {
+		self.metrics_file.as_deref()
+	}
+
+	/// Which logs to write, as a tracing filter like `info` or
+	/// `textual=debug`.
+	pub fn log_level(&self) -> &str {
+		&self.log_level
+	}
+
+	pub fn log_format(&self) -> LogFormat {
+		self.log_format
+	}
+
+	pub fn command(&self) -> Option<&Command> {
+		self.command.as_ref()
+	}
+
+	/// Parse a size in bytes, optionally suffixed with K, M, or G.
+	fn parse_size<S: AsRef<str>>(string: S) -> Result<usize, ConfigError> {
+		let string = string.as_ref().trim();
+
+		let (number, multiplier) = match string.char_indices().last() {
+			Some((idx, 'K')) | Some((idx, 'k')) => (&string[..idx], 1024),
+			Some((idx, 'M')) | Some((idx, 'm')) => (&string[..idx], 1024 * 1024),
+			Some((idx, 'G')) | Some((idx, 'g')) => (&string[..idx], 1024 * 1024 * 1024),
+			_ => (string, 1),
+		};
+
+		number
+			.trim()
+			.parse::<usize>()
+			.ok()
+			.and_then(|n| n.checked_mul(multiplier))
+			.ok_or_else(|| ConfigError::InvalidSize(string.into()))
+	}
+
+	fn parse_limits(layers: &Layers) -> Result<Limits, ConfigError> {
+		let defaults = Limits::default();
+
+		let invalid = |key: &str, value: &str| ConfigError::InvalidLimit {
+			key: key.into(),
+			value: value.into(),
+		};
+
+		let count = |key: &str, default: usize| match layers.value(key) {
+			Some(string) => string.trim().parse().map_err(|_| invalid(key, &string)),
+			None => Ok(default),
+		};
+
+		let max_font_size = match layers.value("MaxFontSize") {
+			Some(string) => string
+				.trim()
+				.parse()
+				.map_err(|_| invalid("MaxFontSize", &string))?,
+			None => defaults.max_font_size,
+		};
+
+		let render_time = match layers.value("RenderTime") {
+			Some(string) => Duration::from_millis(
+				string
+					.trim()
+					.parse()
+					.map_err(|_| invalid("RenderTime", &string))?,
+			),
+			None => defaults.render_time,
+		};
+
+		Ok(Limits {
+			max_pixels: count("MaxPixels", defaults.max_pixels)?,
+			max_dimension: count("MaxDimension", defaults.max_dimension)?,
+			max_glyphs: count("MaxGlyphs", defaults.max_glyphs)?,
+			max_texts: count("MaxTexts", defaults.max_texts)?,
+			max_font_size,
+			render_time,
+		})
+	}
+
 	fn usage(opts: &Options) {
-		print!("{}", opts.usage("Usage: textual [options]"))
+		print!(
+			"{}",
+			opts.usage(
+				"Usage: textual [options]\n       textual [options] sign QUERY\n       \
+				textual [options] render [--out DIR] [--jobs N] INPUT...\n       \
+				textual [options] fonts sync\n       \
+				textual [options] cache verify|prune\n\n\
+				render draws images without the server. An INPUT is a query string, a JSON\n\
+				operation file, or a .csv manifest of output,query lines or a .jsonl manifest\n\
+				of {\"output\": ..., \"query\": ...} objects. Fonts come from the font cache.\n\n\
+				Fonts in FontDirectories, and installed fonts if SystemFonts is true, are\n\
+				used before Google's. They're named by what's inside them, not their files.\n\n\
+				fonts sync saves the list of fonts from Google to FontCatalog. With it we\n\
+				start without asking Google, and only ask again every FontCatalogRefresh\n\
+				seconds if that's set.\n\n\
+				cache verify reads every font in the font cache and removes the ones that are\n\
+				broken so they're downloaded again. cache prune removes files the cache\n\
+				doesn't know about, like unfinished downloads.\n\n\
+				Every config key can also be set in the environment as TEXTUAL_ and the\n\
+				key in capitals with words split by underscores, so MetaHost is\n\
+				TEXTUAL_META_HOST. Flags win over the environment, which wins over the\n\
+				config file."
+			)
+		)
 	}
 
 	fn parse_hostname<S: AsRef<str>>(string: S) -> Result<(Option<String>, String), ConfigError> {
@@ -65,14 +607,22 @@ impl Config {
 	}
 
 	pub fn get() -> Result<Option<Self>, ConfigError> {
-		let args: Vec<String> = std::env::args().collect();
+		Self::from_args(std::env::args().collect())
+	}
 
+	/// [Config::get] with the arguments given, not the ones we were run with.
+	pub(crate) fn from_args(args: Vec<String>) -> Result<Option<Self>, ConfigError> {
 		let mut opts = Options::new();
 		opts.optflag("h", "help", "Print this message and exit");
+		opts.optflag(
+			"",
+			"print-config",
+			"Print every setting, after reading flags, the environment and the config file, then exit",
+		);
 		opts.optopt(
 			"c",
 			"config",
-			"An alternate config file\nDefaults to /etc/textual/textual.conf",
+			"An alternate config file\nEnvironment: TEXTUAL_CONFIG\nDefaults to /etc/textual/textual.conf",
 			"FILE",
 		);
 		opts.optopt(
@@ -93,6 +643,12 @@ impl Config {
 			"What part the server should listen on\nConfig key: Port\nDefaults to 30211",
 			"PORT",
 		);
+		opts.optopt(
+			"",
+			"unix-socket",
+			"Listen on a Unix socket instead of an IP and port\nConfig key: UnixSocket",
+			"PATH",
+		);
 		opts.optopt(
 			"",
 			"meta-host",
@@ -102,6 +658,36 @@ impl Config {
 			Default is the host header, or localhost if missing",
 			"HOSTNAME",
 		);
+		opts.optopt(
+			"",
+			"log-level",
+			"Which logs to write, like info or textual=debug\nConfig key: LogLevel\nDefaults to info",
+			"FILTER",
+		);
+		opts.optopt(
+			"",
+			"log-format",
+			"How to write logs, text or json\nConfig key: LogFormat\nDefaults to text",
+			"FORMAT",
+		);
+		opts.optopt(
+			"",
+			"expires",
+			"With sign, how many seconds the link is valid for.\nDefaults to forever",
+			"SECONDS",
+		);
+		opts.optopt(
+			"o",
+			"out",
+			"With render, where to write the images.\nDefaults to the current directory",
+			"DIR",
+		);
+		opts.optopt(
+			"j",
+			"jobs",
+			"With render, how many images to draw at once.\nDefaults to the number of CPUs",
+			"N",
+		);
 		let matches = opts.parse(&args[1..])?;
 
 		if matches.opt_present("help") {
@@ -109,15 +695,82 @@ impl Config {
 			return Ok(None);
 		}
 
-		let config_location = matches
-			.opt_str("config")
-			.unwrap_or("/etc/textual/textual.conf".into());
-		let conf = Confindent::from_file(config_location)?;
+		// It's fine for the default config file not to exist, everything can
+		// come from the environment instead
+		let explicit = matches.opt_str("config").or_else(|| env_value("Config"));
+		let location = PathBuf::from(
+			explicit
+				.clone()
+				.unwrap_or_else(|| "/etc/textual/textual.conf".into()),
+		);
+		let explicit = explicit.is_some();
+		let layers = Layers::new(matches, location.clone(), explicit)?;
+		let matches = &layers.matches;
+
+		let command = match matches.free.first().map(|s| s.as_str()) {
+			None if matches.opt_present("print-config") => Some(Command::PrintConfig),
+			None => None,
+			Some("sign") => {
+				let query = matches
+					.free
+					.get(1)
+					.ok_or(ConfigError::MissingArgument("sign", "QUERY"))?;
+
+				let expires = match matches.opt_str("expires") {
+					Some(string) => Some(
+						string
+							.parse()
+							.map_err(|_| ConfigError::InvalidExpiry(string))?,
+					),
+					None => None,
+				};
+
+				Some(Command::Sign {
+					query: query.trim_start_matches('?').to_owned(),
+					expires,
+				})
+			}
+			Some("render") => {
+				let inputs = matches.free[1..].to_vec();
+				if inputs.is_empty() {
+					return Err(ConfigError::MissingArgument("render", "INPUT"));
+				}
+
+				let jobs = match matches.opt_str("jobs") {
+					Some(string) => match string.parse() {
+						Ok(jobs) if jobs > 0 => Some(jobs),
+						_ => return Err(ConfigError::InvalidJobs(string)),
+					},
+					None => None,
+				};
+
+				Some(Command::Render {
+					inputs,
+					out: PathBuf::from(matches.opt_str("out").unwrap_or(".".into())),
+					jobs,
+				})
+			}
+			Some("fonts") => match matches.free.get(1).map(|s| s.as_str()) {
+				Some("sync") => Some(Command::FontsSync),
+				Some(unknown) => {
+					return Err(ConfigError::UnknownCommand(format!("fonts {}", unknown)))
+				}
+				None => return Err(ConfigError::MissingArgument("fonts", "subcommand")),
+			},
+			Some("cache") => match matches.free.get(1).map(|s| s.as_str()) {
+				Some("verify") => Some(Command::CacheVerify),
+				Some("prune") => Some(Command::CachePrune),
+				Some(unknown) => {
+					return Err(ConfigError::UnknownCommand(format!("cache {}", unknown)))
+				}
+				None => return Err(ConfigError::MissingArgument("cache", "subcommand")),
+			},
+			Some(unknown) => return Err(ConfigError::UnknownCommand(unknown.into())),
+		};
 
 		let font_cache_path = PathBuf::from(
-			matches
-				.opt_str("font-cache")
-				.or(conf.child_value("FontCache").map(|s| s.into()))
+			layers
+				.flag("font-cache", "FontCache")
 				.unwrap_or("/var/lib/textual/fontcache".into()),
 		);
 
@@ -125,9 +778,37 @@ impl Config {
 			return Err(ConfigError::InvalidFontCache(font_cache_path));
 		}
 
-		let listen_string = matches
-			.opt_str("listen")
-			.or(conf.child_value("Listen").map(|s| s.into()));
+		let font_cache_size = match layers.value("FontCacheSize") {
+			Some(string) => Self::parse_size(&string)?,
+			None => 1024 * 1024 * 1024,
+		};
+
+		let font_memory_cache = match layers.value("FontMemoryCache") {
+			Some(string) => Self::parse_size(&string)?,
+			None => 64 * 1024 * 1024,
+		};
+
+		let font_directories: Vec<PathBuf> = layers
+			.value("FontDirectories")
+			.map(|s| s.split_whitespace().map(PathBuf::from).collect())
+			.unwrap_or_default();
+
+		if let Some(missing) = font_directories.iter().find(|dir| !dir.is_dir()) {
+			return Err(ConfigError::InvalidFontDirectory(missing.clone()));
+		}
+
+		let system_fonts = match layers.value("SystemFonts").as_deref() {
+			Some("true") | Some("yes") => true,
+			Some("false") | Some("no") | None => false,
+			Some(other) => {
+				return Err(ConfigError::InvalidLimit {
+					key: "SystemFonts".into(),
+					value: other.into(),
+				})
+			}
+		};
+
+		let listen_string = layers.flag("listen", "Listen");
 
 		let listen = if let Some(string) = listen_string {
 			string.parse()?
@@ -135,9 +816,7 @@ impl Config {
 			IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
 		};
 
-		let port_string = matches
-			.opt_str("port")
-			.or(conf.child_value("Port").map(|s| s.into()));
+		let port_string = layers.flag("port", "Port");
 
 		let port = if let Some(string) = port_string {
 			string.parse()?
@@ -145,9 +824,23 @@ impl Config {
 			30211
 		};
 
-		let metahost_string = matches
-			.opt_str("meta-host")
-			.or(conf.child_value("MetaHost").map(|s| s.into()));
+		let unix_socket = layers.flag("unix-socket", "UnixSocket").map(PathBuf::from);
+
+		let tls = match (layers.value("TlsCertificate"), layers.value("TlsKey")) {
+			(None, None) => None,
+			(Some(certificate), Some(key)) => Some(TlsFiles {
+				certificate: certificate.into(),
+				key: key.into(),
+			}),
+			_ => return Err(ConfigError::IncompleteTls),
+		};
+
+		// Whatever is in front of the socket is on this machine and can do it
+		if tls.is_some() && unix_socket.is_some() {
+			return Err(ConfigError::TlsOnUnixSocket);
+		}
+
+		let metahost_string = layers.flag("meta-host", "MetaHost");
 
 		let (scheme, meta_host) = match metahost_string {
 			Some(s) => {
@@ -157,12 +850,182 @@ impl Config {
 			None => (None, None),
 		};
 
+		let gamma = match layers.value("Gamma") {
+			Some(string) => match string.trim().parse().unwrap_or(f32::NAN) {
+				gamma if f32::is_finite(gamma) && gamma > 0.0 => gamma,
+				_ => {
+					return Err(ConfigError::InvalidLimit {
+						key: "Gamma".into(),
+						value: string,
+					})
+				}
+			},
+			None => 2.2,
+		};
+
+		let contrast = match layers.value("Contrast") {
+			Some(string) => match string.trim().parse().unwrap_or(f32::NAN) {
+				contrast if (0.0..=1.0).contains(&contrast) => contrast,
+				_ => {
+					return Err(ConfigError::InvalidLimit {
+						key: "Contrast".into(),
+						value: string,
+					})
+				}
+			},
+			None => 0.0,
+		};
+
+		let hint_below = match layers.value("HintBelow") {
+			Some(string) => match string.trim().parse().unwrap_or(f32::NAN) {
+				size if f32::is_finite(size) && size >= 0.0 => size,
+				_ => {
+					return Err(ConfigError::InvalidLimit {
+						key: "HintBelow".into(),
+						value: string,
+					})
+				}
+			},
+			None => 0.0,
+		};
+
+		let glyph_cache = match layers.value("GlyphCache") {
+			Some(string) => Self::parse_size(&string)?,
+			None => 16 * 1024 * 1024,
+		};
+
+		let response_cache = match layers.value("ResponseCache") {
+			Some(string) => Self::parse_size(&string)?,
+			None => 64 * 1024 * 1024,
+		};
+
+		let response_disk_cache = layers.value("ResponseDiskCache").map(PathBuf::from);
+		if let Some(path) = response_disk_cache.as_ref() {
+			if !path.is_dir() {
+				return Err(ConfigError::InvalidResponseCache(path.clone()));
+			}
+		}
+
+		let response_disk_cache_size = match layers.value("ResponseDiskCacheSize") {
+			Some(string) => Self::parse_size(&string)?,
+			None => 1024 * 1024 * 1024,
+		};
+
+		let max_age = match layers.value("MaxAge") {
+			Some(string) => string
+				.trim()
+				.parse()
+				.map_err(|_| ConfigError::InvalidLimit {
+					key: "MaxAge".into(),
+					value: string,
+				})?,
+			None => 86400,
+		};
+
+		let limits = Self::parse_limits(&layers)?;
+		let signing_secret = layers.value("SigningSecret");
+		let google_fonts_key = layers.value("GoogleFontsKey");
+		let font_catalog = layers.value("FontCatalog").map(PathBuf::from);
+
+		let font_catalog_refresh = match layers.value("FontCatalogRefresh") {
+			Some(string) => match string.parse() {
+				Ok(0) => None,
+				Ok(secs) => Some(Duration::from_secs(secs)),
+				Err(_) => {
+					return Err(ConfigError::InvalidLimit {
+						key: "FontCatalogRefresh".into(),
+						value: string,
+					})
+				}
+			},
+			None => None,
+		};
+
+		let cors_origins = layers
+			.value("CorsOrigins")
+			.map(|s| s.split_whitespace().map(|o| o.to_owned()).collect())
+			.unwrap_or_default();
+
+		let metrics_file = layers.value("MetricsFile").map(PathBuf::from);
+
+		let log_level = layers
+			.flag("log-level", "LogLevel")
+			.unwrap_or("info".into());
+		if EnvFilter::try_new(&log_level).is_err() {
+			return Err(ConfigError::InvalidLogLevel(log_level));
+		}
+
+		let log_format = match layers.flag("log-format", "LogFormat").as_deref() {
+			None | Some("text") => LogFormat::Text,
+			Some("json") => LogFormat::Json,
+			Some(other) => return Err(ConfigError::InvalidLogFormat(other.into())),
+		};
+
+		// We're usually behind a reverse proxy on the same machine
+		let trusted_proxies = match layers.value("TrustedProxies") {
+			Some(string) => string
+				.split_whitespace()
+				.map(|ip| ip.parse().map_err(|_| ConfigError::InvalidProxy(ip.into())))
+				.collect::<Result<Vec<IpAddr>, ConfigError>>()?,
+			None => vec![
+				IpAddr::V4(Ipv4Addr::LOCALHOST),
+				IpAddr::V6(Ipv6Addr::LOCALHOST),
+			],
+		};
+
+		// A rate of 0 turns limiting off, so only negative numbers are wrong
+		let rate = |key: &str, default: f64| match layers.value(key) {
+			Some(string) => match string.trim().parse::<f64>() {
+				Ok(rate) if rate.is_finite() && rate >= 0.0 => Ok(rate),
+				_ => Err(ConfigError::InvalidLimit {
+					key: key.into(),
+					value: string,
+				}),
+			},
+			None => Ok(default),
+		};
+
+		let render_rate = rate("RenderRate", 60.0)?;
+		let render_burst = rate("RenderBurst", 30.0)?;
+		let font_rate = rate("FontRate", 10.0)?;
+		let font_burst = rate("FontBurst", 5.0)?;
+
 		Ok(Some(Self {
+			location,
 			font_cache_path,
+			font_cache_size,
+			font_memory_cache,
+			font_directories,
+			system_fonts,
 			listen,
 			port,
+			unix_socket,
+			tls,
 			scheme,
 			meta_host,
+			gamma,
+			contrast,
+			hint_below,
+			glyph_cache,
+			response_cache,
+			response_disk_cache,
+			response_disk_cache_size,
+			max_age,
+			limits,
+			trusted_proxies,
+			render_rate,
+			render_burst,
+			font_rate,
+			font_burst,
+			signing_secret,
+			google_fonts_key,
+			font_catalog,
+			font_catalog_refresh,
+			cors_origins,
+			metrics_file,
+			log_level,
+			log_format,
+			command,
 		}))
 	}
 }
@@ -175,12 +1038,105 @@ pub enum ConfigError {
 	ConfigParseError(#[from] ParseError),
 	#[error("The provided path for the font cache does not exist: '{0}'")]
 	InvalidFontCache(PathBuf),
+	#[error("The provided font directory does not exist: '{0}'")]
+	InvalidFontDirectory(PathBuf),
+	#[error("The provided path for the response cache does not exist: '{0}'")]
+	InvalidResponseCache(PathBuf),
+	#[error("TlsCertificate and TlsKey have to be set together")]
+	IncompleteTls,
+	#[error("TLS can't be used with UnixSocket, the proxy in front should do it")]
+	TlsOnUnixSocket,
 	#[error("Could not parse the hostname as a uri '{0}'")]
 	HostnameParseError(String),
 	#[error("Valid schemes are http and https. '{0}' is invalid")]
 	InvalidScheme(String),
 	#[error("Invalid port specified: '{0}'")]
 	InvalidPort(#[from] ParseIntError),
+	#[error("Invalid size '{0}'. Expected bytes with an optional K, M, or G suffix")]
+	InvalidSize(String),
+	#[error("Invalid value for {key}: '{value}'")]
+	InvalidLimit { key: String, value: String },
+	#[error("Invalid IP for a trusted proxy: '{0}'")]
+	InvalidProxy(String),
+	#[error("Invalid log level '{0}'. Expected something like info or textual=debug")]
+	InvalidLogLevel(String),
+	#[error("Invalid log format '{0}'. Expected text or json")]
+	InvalidLogFormat(String),
+	#[error("Unknown command '{0}'")]
+	UnknownCommand(String),
+	#[error("{0} needs a {1}")]
+	MissingArgument(&'static str, &'static str),
+	#[error("Invalid expiry '{0}'. Expected a number of seconds")]
+	InvalidExpiry(String),
+	#[error("Invalid number of jobs '{0}'. Expected a number above 0")]
+	InvalidJobs(String),
 	#[error("Invalid IP for listen: '{0}'")]
 	InvalidListen(#[from] AddrParseError),
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn env_names() {
+		assert_eq!(env_name("MetaHost"), "TEXTUAL_META_HOST");
+		assert_eq!(env_name("Port"), "TEXTUAL_PORT");
+		assert_eq!(
+			env_name("ResponseDiskCacheSize"),
+			"TEXTUAL_RESPONSE_DISK_CACHE_SIZE"
+		);
+	}
+
+	#[test]
+	fn parse_size() {
+		assert_eq!(Config::parse_size("512").unwrap(), 512);
+		assert_eq!(Config::parse_size("4K").unwrap(), 4096);
+		assert_eq!(Config::parse_size("16M").unwrap(), 16 * 1024 * 1024);
+		assert_eq!(Config::parse_size("1 G").unwrap(), 1024 * 1024 * 1024);
+		assert!(Config::parse_size("lots").is_err());
+		assert!(Config::parse_size(format!("{}G", usize::MAX / 1024)).is_err());
+	}
+
+	/// The error from reading a config file with `contents`, and a font cache
+	/// that exists.
+	fn config_error(name: &str, contents: &str) -> String {
+		let temp = std::env::temp_dir();
+		let path = temp.join(format!("textual-{}-{}", std::process::id(), name));
+		let file = format!("FontCache {}\n{}", temp.display(), contents);
+		std::fs::write(&path, file).unwrap();
+
+		let args = vec![
+			"textual".into(),
+			"--config".into(),
+			path.display().to_string(),
+		];
+		let result = Config::from_args(args);
+		std::fs::remove_file(&path).unwrap();
+
+		match result {
+			Ok(_) => panic!("'{}' was accepted", contents),
+			Err(e) => e.to_string(),
+		}
+	}
+
+	#[test]
+	fn invalid_numbers_name_their_key() {
+		assert_eq!(
+			config_error("max-age", "MaxAge abc"),
+			"Invalid value for MaxAge: 'abc'"
+		);
+		assert_eq!(
+			config_error("render-rate", "RenderRate fast"),
+			"Invalid value for RenderRate: 'fast'"
+		);
+		assert_eq!(
+			config_error("font-burst", "FontBurst -1"),
+			"Invalid value for FontBurst: '-1'"
+		);
+		assert_eq!(
+			config_error("gamma", "Gamma dark"),
+			"Invalid value for Gamma: 'dark'"
+		);
+	}
+}
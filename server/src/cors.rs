@@ -0,0 +1,171 @@
+use hyper::{
+	header::{
+		HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+		ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+		ACCESS_CONTROL_REQUEST_HEADERS, ALLOW, ORIGIN, VARY,
+	},
+	Body, Request, Response, StatusCode,
+};
+
+/// Every method we respond to.
+pub const ALLOWED_METHODS: &str = "GET, HEAD, POST, OPTIONS";
+
+/// Headers a cross-origin script is allowed to send if it doesn't ask for any
+/// in particular.
+const ALLOWED_HEADERS: &str = "Content-Type, If-None-Match";
+
+/// Headers of ours a cross-origin script may read.
+const EXPOSED_HEADERS: &str = "Content-Length, ETag, Retry-After, X-Textual-Warnings";
+
+/// How long, in seconds, browsers may remember a preflight.
+const PREFLIGHT_MAX_AGE: u32 = 86400;
+
+/// Which origins may fetch from us in a browser.
+pub struct Cors {
+	origins: Origins,
+}
+
+enum Origins {
+	None,
+	Any,
+	List(Vec<String>),
+}
+
+impl Cors {
+	/// Takes the allowed origins as they're written in the config. `*` allows
+	/// any origin and an empty list allows none.
+	pub fn new(origins: &[String]) -> Self {
+		let origins = if origins.is_empty() {
+			Origins::None
+		} else if origins.iter().any(|o| o == "*") {
+			Origins::Any
+		} else {
+			Origins::List(
+				origins
+					.iter()
+					.map(|o| o.trim_end_matches('/').to_owned())
+					.collect(),
+			)
+		};
+
+		Self { origins }
+	}
+
+	/// What to put in Access-Control-Allow-Origin for a request from `origin`,
+	/// or None if it's not allowed.
+	fn allow(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+		match &self.origins {
+			Origins::None => None,
+			Origins::Any => Some(HeaderValue::from_static("*")),
+			Origins::List(list) => {
+				let origin = origin?;
+				let allowed = list.iter().any(|o| origin.as_bytes() == o.as_bytes());
+
+				if allowed {
+					Some(origin.clone())
+				} else {
+					None
+				}
+			}
+		}
+	}
+
+	/// Add CORS headers to a response for a request from `origin`.
+	pub fn apply(&self, origin: Option<&HeaderValue>, response: &mut Response<Body>) {
+		let headers = response.headers_mut();
+
+		// The response changes with the origin unless we allow everyone
+		if let Origins::List(_) = self.origins {
+			headers.append(VARY, HeaderValue::from_static("Origin"));
+		}
+
+		if let Some(allow) = self.allow(origin) {
+			headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow);
+			headers.insert(
+				ACCESS_CONTROL_EXPOSE_HEADERS,
+				HeaderValue::from_static(EXPOSED_HEADERS),
+			);
+		}
+	}
+
+	/// Answer an OPTIONS request. Preflights from allowed origins are told what
+	/// they can do. Everyone else just gets the Allow header.
+	pub fn preflight(&self, req: &Request<Body>) -> Response<Body> {
+		let mut response = Response::new(Body::empty());
+		*response.status_mut() = StatusCode::NO_CONTENT;
+
+		let headers = response.headers_mut();
+		headers.insert(ALLOW, HeaderValue::from_static(ALLOWED_METHODS));
+
+		if self.allow(req.headers().get(ORIGIN)).is_some() {
+			let requested = req
+				.headers()
+				.get(ACCESS_CONTROL_REQUEST_HEADERS)
+				.cloned()
+				.unwrap_or_else(|| HeaderValue::from_static(ALLOWED_HEADERS));
+
+			headers.insert(
+				ACCESS_CONTROL_ALLOW_METHODS,
+				HeaderValue::from_static(ALLOWED_METHODS),
+			);
+			headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, requested);
+			headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(PREFLIGHT_MAX_AGE));
+		}
+
+		response
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn allowed(cors: &Cors, origin: &'static str) -> Option<HeaderValue> {
+		cors.allow(Some(&HeaderValue::from_static(origin)))
+	}
+
+	#[test]
+	fn origin_list() {
+		let cors = Cors::new(&["https://example.com/".into()]);
+
+		assert_eq!(
+			allowed(&cors, "https://example.com"),
+			Some(HeaderValue::from_static("https://example.com"))
+		);
+		assert_eq!(allowed(&cors, "https://example.org"), None);
+		assert_eq!(cors.allow(None), None);
+	}
+
+	#[test]
+	fn any_or_no_origin() {
+		let any = Cors::new(&["*".into()]);
+		assert_eq!(
+			allowed(&any, "https://example.org"),
+			Some(HeaderValue::from_static("*"))
+		);
+
+		let none = Cors::new(&[]);
+		assert_eq!(allowed(&none, "https://example.org"), None);
+	}
+
+	#[test]
+	fn preflight_headers() {
+		let cors = Cors::new(&["https://example.com".into()]);
+		let req = Request::builder()
+			.method("OPTIONS")
+			.header(ORIGIN, "https://example.com")
+			.body(Body::empty())
+			.unwrap();
+
+		let response = cors.preflight(&req);
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		assert_eq!(
+			response.headers().get(ACCESS_CONTROL_ALLOW_METHODS),
+			Some(&HeaderValue::from_static(ALLOWED_METHODS))
+		);
+		assert_eq!(
+			response.headers().get(ACCESS_CONTROL_ALLOW_HEADERS),
+			Some(&HeaderValue::from_static(ALLOWED_HEADERS))
+		);
+	}
+}
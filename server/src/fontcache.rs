@@ -0,0 +1,729 @@
+use std::{
+	collections::HashSet,
+	fs, io,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use fontster::Font;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::fontprovider::{self, FontError, FontVariant};
+use crate::lru::Lru;
+
+/// Lists what's in the cache and its checksums, least recently used first.
+const INDEX: &str = "index.json";
+
+type FontKey = (String, FontVariant);
+
+/// Local fonts aren't in the index, but they're parsed and kept in memory
+/// alongside the cached ones.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum ParsedKey {
+	Cached(FontKey),
+	/// The local font's path
+	Local(String),
+}
+
+/// Fonts we've downloaded. They're kept on disk with an index of their
+/// checksums, and the ones in use are kept parsed in memory. Both have a size
+/// limit and the least recently used fonts go first.
+pub struct FontCache {
+	location: PathBuf,
+	index: Mutex<Lru<FontKey, Entry>>,
+	parsed: Mutex<Lru<ParsedKey, Arc<Font>>>,
+}
+
+#[derive(Clone)]
+struct Entry {
+	file: String,
+	size: usize,
+	sha256: String,
+}
+
+/// The index as it's written to disk.
+#[derive(Deserialize, Serialize)]
+struct IndexFile {
+	fonts: Vec<IndexLine>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct IndexLine {
+	family: String,
+	weight: String,
+	style: String,
+	file: String,
+	size: usize,
+	sha256: String,
+}
+
+/// What `verify` or `prune` did.
+#[derive(Debug, Default)]
+pub struct Report {
+	pub checked: usize,
+	pub removed: usize,
+	/// Bytes no longer on disk
+	pub freed: usize,
+}
+
+impl FontCache {
+	/// Read the index in `location`, keeping at most `budget` bytes of fonts
+	/// on disk and `memory_budget` bytes of them parsed. Fonts cached before
+	/// there was an index are added to it.
+	pub fn open<P: Into<PathBuf>>(
+		location: P,
+		budget: usize,
+		memory_budget: usize,
+	) -> io::Result<Self> {
+		let location = location.into();
+		let mut index = Lru::new(budget);
+		let mut evicted = vec![];
+		let mut changed = false;
+
+		for (key, entry) in read_index(&location) {
+			// A file that isn't the size it was is a write that never finished
+			let size = fs::metadata(location.join(&entry.file)).map(|meta| meta.len() as usize);
+			if size.ok() != Some(entry.size) {
+				warn!(file = %entry.file, "cached font is missing or the wrong size");
+				remove_file(&location, &entry.file);
+				changed = true;
+				continue;
+			}
+
+			let size = entry.size;
+			evicted.append(&mut index.insert(key, entry, size));
+		}
+
+		let known: HashSet<String> = index.iter().map(|(_, entry)| entry.file.clone()).collect();
+		for dirent in fs::read_dir(&location)? {
+			let dirent = match dirent {
+				Ok(dirent) => dirent,
+				Err(e) => {
+					warn!(error = %e, "unable to read font cache entry");
+					continue;
+				}
+			};
+
+			let is_file = dirent.file_type().map(|ft| ft.is_file()).unwrap_or(false);
+			let name = match dirent.file_name().into_string() {
+				Ok(name) if is_file && !known.contains(&name) && !is_ours(&name) => name,
+				_ => continue,
+			};
+
+			let key = match parse_name(&name) {
+				Some(key) if index.peek(&key).is_none() => key,
+				_ => {
+					warn!(file = %name, "unknown file in font cache");
+					continue;
+				}
+			};
+
+			let data = match fs::read(dirent.path()) {
+				Ok(data) => data,
+				Err(e) => {
+					warn!(file = %name, error = %e, "unable to read cached font");
+					continue;
+				}
+			};
+
+			debug!(file = %name, "adding font to the cache index");
+			let entry = Entry {
+				file: name,
+				size: data.len(),
+				sha256: checksum(&data),
+			};
+			evicted.append(&mut index.insert(key, entry, data.len()));
+			changed = true;
+		}
+
+		for (_, entry) in &evicted {
+			remove_file(&location, &entry.file);
+		}
+
+		if changed || !evicted.is_empty() {
+			write_index(&location, &index)?;
+		}
+
+		info!(
+			fonts = index.len(),
+			bytes = index.used(),
+			evicted = evicted.len(),
+			"loaded font cache"
+		);
+
+		Ok(Self {
+			location,
+			index: Mutex::new(index),
+			parsed: Mutex::new(Lru::new(memory_budget)),
+		})
+	}
+
+	/// A font from memory, or from disk if its checksum is right. Fonts that
+	/// are broken are removed, so getting them again replaces them.
+	pub fn get(&self, family: &str, variant: FontVariant) -> Result<Option<Arc<Font>>, FontError> {
+		let key = (family.to_owned(), variant);
+
+		let entry = match self.index.lock().unwrap().get(&key) {
+			Some(entry) => entry.clone(),
+			None => return Ok(None),
+		};
+
+		if let Some(font) = self
+			.parsed
+			.lock()
+			.unwrap()
+			.get(&ParsedKey::Cached(key.clone()))
+		{
+			return Ok(Some(font.clone()));
+		}
+
+		let data = match fs::read(self.location.join(&entry.file)) {
+			Ok(data) => data,
+			Err(e) => {
+				self.remove(&key);
+				return Err(e.into());
+			}
+		};
+
+		if data.len() != entry.size || checksum(&data) != entry.sha256 {
+			self.remove(&key);
+			return Err(FontError::Corrupt {
+				family: family.to_owned(),
+				variant,
+			});
+		}
+
+		// Fonts we adopted from before the index may still be WOFF
+		let font = match fontprovider::parse_font(&data) {
+			Ok(font) => Arc::new(font),
+			Err(reason) => {
+				self.remove(&key);
+				return Err(FontError::Parse {
+					family: family.to_owned(),
+					reason,
+				});
+			}
+		};
+
+		self.parsed
+			.lock()
+			.unwrap()
+			.insert(ParsedKey::Cached(key), font.clone(), data.len());
+
+		Ok(Some(font))
+	}
+
+	/// A font that's already parsed in memory. It never reads the disk, so
+	/// it's fine to call on the executor.
+	pub fn get_parsed(&self, family: &str, variant: FontVariant) -> Option<Arc<Font>> {
+		let key = (family.to_owned(), variant);
+		let font = self
+			.parsed
+			.lock()
+			.unwrap()
+			.get(&ParsedKey::Cached(key.clone()))
+			.cloned()?;
+
+		// It's in use, so its file shouldn't be the next to go either
+		self.index.lock().unwrap().get(&key);
+
+		Some(font)
+	}
+
+	/// A local font that's already parsed. Like [FontCache::get_parsed] it
+	/// never reads the disk.
+	pub fn get_local(&self, path: &str) -> Option<Arc<Font>> {
+		self.parsed
+			.lock()
+			.unwrap()
+			.get(&ParsedKey::Local(path.to_owned()))
+			.cloned()
+	}
+
+	/// Keep a local font we've parsed in memory. `size` is how big its file
+	/// is, and counts against the memory budget.
+	pub fn insert_local(&self, path: &str, font: Arc<Font>, size: usize) {
+		self.parsed
+			.lock()
+			.unwrap()
+			.insert(ParsedKey::Local(path.to_owned()), font, size);
+	}
+
+	/// Save a font we've parsed, evicting others if that puts us over budget.
+	pub fn insert(
+		&self,
+		family: &str,
+		variant: FontVariant,
+		data: &[u8],
+		font: Arc<Font>,
+	) -> io::Result<()> {
+		let file = format!(
+			"{}-{} {}.{}",
+			family.replace('/', "_"),
+			variant.weight(),
+			variant.style(),
+			extension(data)
+		);
+
+		// Write somewhere else first so a crash never leaves half a font
+		write_atomic(&self.location.join(&file), data)?;
+
+		let key = (family.to_owned(), variant);
+		let entry = Entry {
+			file,
+			size: data.len(),
+			sha256: checksum(data),
+		};
+
+		let mut index = self.index.lock().unwrap();
+		let mut parsed = self.parsed.lock().unwrap();
+
+		// It might have been saved before in another format
+		if let Some(old) = index.remove(&key) {
+			if old.file != entry.file {
+				remove_file(&self.location, &old.file);
+			}
+		}
+
+		let evicted = index.insert(key.clone(), entry, data.len());
+		parsed.insert(ParsedKey::Cached(key), font, data.len());
+
+		for (key, entry) in evicted {
+			debug!(family = %key.0, variant = %key.1, "evicting font from the cache");
+			remove_file(&self.location, &entry.file);
+			parsed.remove(&ParsedKey::Cached(key));
+		}
+
+		debug!(family, %variant, "saved font");
+
+		write_index(&self.location, &index)
+	}
+
+	pub fn contains(&self, family: &str, variant: FontVariant) -> bool {
+		self.index
+			.lock()
+			.unwrap()
+			.peek(&(family.to_owned(), variant))
+			.is_some()
+	}
+
+	/// Every family with at least one variant cached.
+	pub fn families(&self) -> Vec<String> {
+		let mut families: Vec<String> = self
+			.index
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|((family, _), _)| family.clone())
+			.collect();
+
+		families.sort_unstable();
+		families.dedup();
+		families
+	}
+
+	pub fn variants(&self, family: &str) -> Vec<FontVariant> {
+		self.index
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|((fam, _), _)| fam == family)
+			.map(|((_, variant), _)| *variant)
+			.collect()
+	}
+
+	/// How many fonts are cached.
+	pub fn len(&self) -> usize {
+		self.index.lock().unwrap().len()
+	}
+
+	/// Read every font, removing those whose checksum is wrong or that
+	/// don't parse.
+	pub fn verify(&self) -> Report {
+		let entries: Vec<(FontKey, Entry)> = self
+			.index
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(key, entry)| (key.clone(), entry.clone()))
+			.collect();
+
+		let mut report = Report::default();
+		for (key, entry) in entries {
+			report.checked += 1;
+
+			let problem = match fs::read(self.location.join(&entry.file)) {
+				Err(e) => Some(e.to_string()),
+				Ok(data) if data.len() != entry.size || checksum(&data) != entry.sha256 => {
+					Some("the checksum doesn't match".into())
+				}
+				Ok(data) => fontprovider::parse_font(&data).err(),
+			};
+
+			if let Some(problem) = problem {
+				warn!(family = %key.0, variant = %key.1, file = %entry.file, %problem, "removing broken font");
+				self.remove(&key);
+				report.removed += 1;
+				report.freed += entry.size;
+			}
+		}
+
+		report
+	}
+
+	/// Remove files that aren't in the index, like unfinished writes.
+	pub fn prune(&self) -> io::Result<Report> {
+		let index = self.index.lock().unwrap();
+		let known: HashSet<&str> = index.iter().map(|(_, entry)| entry.file.as_str()).collect();
+
+		let mut report = Report::default();
+		for dirent in fs::read_dir(&self.location)? {
+			let dirent = dirent?;
+			let meta = dirent.metadata()?;
+			if !meta.is_file() {
+				continue;
+			}
+
+			report.checked += 1;
+
+			let name = dirent.file_name();
+			let name = name.to_string_lossy();
+			if name == INDEX || known.contains(name.as_ref()) {
+				continue;
+			}
+
+			debug!(file = %name, "pruning font cache");
+			fs::remove_file(dirent.path())?;
+			report.removed += 1;
+			report.freed += meta.len() as usize;
+		}
+
+		Ok(report)
+	}
+
+	fn remove(&self, key: &FontKey) {
+		let mut index = self.index.lock().unwrap();
+		self.parsed
+			.lock()
+			.unwrap()
+			.remove(&ParsedKey::Cached(key.clone()));
+
+		if let Some(entry) = index.remove(key) {
+			remove_file(&self.location, &entry.file);
+
+			if let Err(e) = write_index(&self.location, &index) {
+				warn!(error = %e, "failed to write the font cache index");
+			}
+		}
+	}
+}
+
+fn read_index(location: &Path) -> Vec<(FontKey, Entry)> {
+	let data = match fs::read(location.join(INDEX)) {
+		Ok(data) => data,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return vec![],
+		Err(e) => {
+			warn!(error = %e, "unable to read the font cache index, rebuilding it");
+			return vec![];
+		}
+	};
+
+	let index: IndexFile = match serde_json::from_slice(&data) {
+		Ok(index) => index,
+		Err(e) => {
+			warn!(error = %e, "unable to parse the font cache index, rebuilding it");
+			return vec![];
+		}
+	};
+
+	index
+		.fonts
+		.into_iter()
+		.filter_map(|line| {
+			let variant = match (line.weight.parse(), line.style.parse()) {
+				(Ok(weight), Ok(style)) => FontVariant::new(weight, style),
+				_ => {
+					warn!(file = %line.file, "unknown variant in the font cache index");
+					return None;
+				}
+			};
+
+			let entry = Entry {
+				file: line.file,
+				size: line.size,
+				sha256: line.sha256,
+			};
+
+			Some(((line.family, variant), entry))
+		})
+		.collect()
+}
+
+fn write_index(location: &Path, index: &Lru<FontKey, Entry>) -> io::Result<()> {
+	let index = IndexFile {
+		fonts: index
+			.iter()
+			.map(|((family, variant), entry)| IndexLine {
+				family: family.clone(),
+				weight: variant.weight().to_string(),
+				style: variant.style().to_string(),
+				file: entry.file.clone(),
+				size: entry.size,
+				sha256: entry.sha256.clone(),
+			})
+			.collect(),
+	};
+
+	write_atomic(&location.join(INDEX), &serde_json::to_vec(&index)?)
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+	let mut temporary = path.as_os_str().to_owned();
+	temporary.push(".tmp");
+
+	fs::write(&temporary, data)?;
+	fs::rename(&temporary, path)
+}
+
+fn remove_file(location: &Path, file: &str) {
+	if let Err(e) = fs::remove_file(location.join(file)) {
+		if e.kind() != io::ErrorKind::NotFound {
+			warn!(%file, error = %e, "failed to remove cached font");
+		}
+	}
+}
+
+/// The index and files we're still writing.
+fn is_ours(name: &str) -> bool {
+	name == INDEX || name.ends_with(".tmp")
+}
+
+fn checksum(data: &[u8]) -> String {
+	Sha256::digest(data)
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
+/// What kind of font this is, going by its first four bytes.
+fn extension(data: &[u8]) -> &'static str {
+	match data.get(..4) {
+		Some(b"OTTO") => "otf",
+		Some(b"ttcf") => "ttc",
+		Some(b"wOFF") => "woff",
+		Some(b"wOF2") => "woff2",
+		_ => "ttf",
+	}
+}
+
+/// Fonts are saved as `Family-weight style.ext`.
+fn parse_name(name: &str) -> Option<FontKey> {
+	let stem = Path::new(name).file_stem()?.to_str()?;
+	let (family, variant) = stem.rsplit_once('-')?;
+	let (weight, style) = variant.split_once(' ')?;
+
+	Some((
+		family.to_owned(),
+		FontVariant::new(weight.parse().ok()?, style.parse().ok()?),
+	))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::fontprovider::{FontStyle, FontWeight};
+
+	#[test]
+	fn file_names() {
+		assert_eq!(
+			parse_name("Open Sans-bold italic.woff2"),
+			Some((
+				"Open Sans".to_owned(),
+				FontVariant::new(FontWeight::Bold, FontStyle::Italic)
+			))
+		);
+		assert_eq!(
+			parse_name("Press-Start-regular normal.ttf").map(|(family, _)| family),
+			Some("Press-Start".to_owned())
+		);
+		assert_eq!(parse_name("Dosis.ttf"), None);
+		assert_eq!(parse_name("Dosis-chunky normal.ttf"), None);
+	}
+
+	#[test]
+	fn extensions() {
+		assert_eq!(extension(b"OTTO\0\0"), "otf");
+		assert_eq!(extension(b"wOF2\0\0"), "woff2");
+		assert_eq!(extension(b"\0\x01\0\0\0\0"), "ttf");
+		assert_eq!(extension(b""), "ttf");
+	}
+
+	const FONT: &[u8] = include_bytes!("../Cabin-Regular.ttf");
+
+	/// A cache directory of its own for a test, removed afterwards.
+	struct Scratch(PathBuf);
+
+	impl Scratch {
+		fn new(test: &str) -> Self {
+			let path = std::env::temp_dir().join(format!(
+				"textual-fontcache-{}-{}",
+				test,
+				std::process::id()
+			));
+			let _ = fs::remove_dir_all(&path);
+			fs::create_dir_all(&path).unwrap();
+			Self(path)
+		}
+
+		fn open(&self, budget: usize) -> FontCache {
+			FontCache::open(&self.0, budget, budget).unwrap()
+		}
+
+		fn files(&self) -> Vec<String> {
+			let mut files: Vec<String> = fs::read_dir(&self.0)
+				.unwrap()
+				.map(|dirent| dirent.unwrap().file_name().to_string_lossy().into_owned())
+				.collect();
+			files.sort();
+			files
+		}
+	}
+
+	impl Drop for Scratch {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	fn variant(weight: FontWeight) -> FontVariant {
+		FontVariant::new(weight, FontStyle::Normal)
+	}
+
+	fn insert(cache: &FontCache, weight: FontWeight) {
+		let font = Arc::new(fontster::parse_font(FONT).unwrap());
+		cache.insert("Cabin", variant(weight), FONT, font).unwrap();
+	}
+
+	#[test]
+	fn saves_and_reopens() {
+		let scratch = Scratch::new("reopen");
+		insert(&scratch.open(usize::MAX), FontWeight::Regular);
+
+		// Nothing is left half written
+		assert_eq!(scratch.files(), ["Cabin-regular normal.ttf", INDEX]);
+
+		let cache = scratch.open(usize::MAX);
+		assert!(cache.contains("Cabin", variant(FontWeight::Regular)));
+		assert!(cache
+			.get("Cabin", variant(FontWeight::Regular))
+			.unwrap()
+			.is_some());
+		assert!(cache
+			.get("Cabin", variant(FontWeight::Bold))
+			.unwrap()
+			.is_none());
+	}
+
+	#[test]
+	fn drops_unfinished_writes() {
+		let scratch = Scratch::new("truncated");
+		insert(&scratch.open(usize::MAX), FontWeight::Regular);
+
+		let file = scratch.0.join("Cabin-regular normal.ttf");
+		fs::write(&file, &FONT[..FONT.len() / 2]).unwrap();
+
+		let cache = scratch.open(usize::MAX);
+		assert!(!cache.contains("Cabin", variant(FontWeight::Regular)));
+		assert!(!file.exists());
+	}
+
+	#[test]
+	fn notices_corruption() {
+		let scratch = Scratch::new("corrupt");
+		insert(&scratch.open(usize::MAX), FontWeight::Regular);
+
+		// The same size, so only the checksum can tell
+		let file = scratch.0.join("Cabin-regular normal.ttf");
+		let mut data = FONT.to_vec();
+		data[FONT.len() / 2] ^= 0xff;
+		fs::write(&file, data).unwrap();
+
+		let cache = scratch.open(usize::MAX);
+		assert!(matches!(
+			cache.get("Cabin", variant(FontWeight::Regular)),
+			Err(FontError::Corrupt { .. })
+		));
+		assert!(!cache.contains("Cabin", variant(FontWeight::Regular)));
+		assert!(!file.exists());
+	}
+
+	#[test]
+	fn evicts_least_recently_used() {
+		let scratch = Scratch::new("evict");
+		let cache = scratch.open(FONT.len() * 2);
+
+		insert(&cache, FontWeight::Regular);
+		insert(&cache, FontWeight::Bold);
+		cache.get("Cabin", variant(FontWeight::Regular)).unwrap();
+		insert(&cache, FontWeight::Light);
+
+		assert!(cache.contains("Cabin", variant(FontWeight::Regular)));
+		assert!(!cache.contains("Cabin", variant(FontWeight::Bold)));
+		assert_eq!(
+			scratch.files(),
+			["Cabin-light normal.ttf", "Cabin-regular normal.ttf", INDEX]
+		);
+	}
+
+	#[test]
+	fn local_fonts_share_memory() {
+		let scratch = Scratch::new("local");
+		let cache = scratch.open(FONT.len() * 2);
+		let font = Arc::new(fontster::parse_font(FONT).unwrap());
+
+		cache.insert_local("/fonts/Cabin.ttf", font, FONT.len());
+		assert!(cache.get_local("/fonts/Cabin.ttf").is_some());
+		assert!(cache.get_local("/fonts/Other.ttf").is_none());
+		assert_eq!(scratch.files(), Vec::<String>::new());
+
+		insert(&cache, FontWeight::Regular);
+		insert(&cache, FontWeight::Bold);
+		assert!(cache.get_local("/fonts/Cabin.ttf").is_none());
+	}
+
+	#[test]
+	fn adopts_and_prunes_files() {
+		let scratch = Scratch::new("adopt");
+		fs::write(scratch.0.join("Cabin-bold normal.ttf"), FONT).unwrap();
+		fs::write(scratch.0.join("notes.txt"), "not a font").unwrap();
+
+		let cache = scratch.open(usize::MAX);
+		assert!(cache.contains("Cabin", variant(FontWeight::Bold)));
+		assert!(cache
+			.get("Cabin", variant(FontWeight::Bold))
+			.unwrap()
+			.is_some());
+
+		let report = cache.prune().unwrap();
+		assert_eq!((report.checked, report.removed), (3, 1));
+		assert_eq!(scratch.files(), ["Cabin-bold normal.ttf", INDEX]);
+	}
+
+	#[test]
+	fn verify_removes_broken_fonts() {
+		let scratch = Scratch::new("verify");
+		let cache = scratch.open(usize::MAX);
+		insert(&cache, FontWeight::Regular);
+		insert(&cache, FontWeight::Bold);
+
+		fs::write(scratch.0.join("Cabin-bold normal.ttf"), vec![0; FONT.len()]).unwrap();
+
+		let report = cache.verify();
+		assert_eq!((report.checked, report.removed), (2, 1));
+		assert_eq!(report.freed, FONT.len());
+		assert!(cache.contains("Cabin", variant(FontWeight::Regular)));
+		assert!(!cache.contains("Cabin", variant(FontWeight::Bold)));
+	}
+}
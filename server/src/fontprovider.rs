@@ -1,179 +1,619 @@
 use core::fmt;
 use std::{
-	io::{self, Read, Write},
-	path::{Path, PathBuf},
+	collections::HashMap,
+	fs,
+	io::{self, Read},
+	path::Path,
 	str::FromStr,
-	sync::Arc,
-	time::Instant,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant},
 };
 
 use fontster::Font;
-use serde_json::Value;
+use serde::Serialize;
+use serde_json::{Map, Value};
 use std::fs::File;
+use textual::woff;
 use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::{debug, info, warn};
 
-struct FontCache {
-	location: PathBuf,
+use crate::fontcache::FontCache;
+use crate::localfonts::LocalFont;
+
+/// How long connecting to the font server can take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a whole download, of a font or the font list, can take.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times to try downloading a font before giving up.
+const DOWNLOAD_ATTEMPTS: usize = 3;
+
+/// How long to wait before trying a download again. It doubles each time.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// What we download with, so nothing can hang forever.
+fn agent() -> ureq::Agent {
+	ureq::AgentBuilder::new()
+		.timeout_connect(CONNECT_TIMEOUT)
+		.timeout(DOWNLOAD_TIMEOUT)
+		.build()
+}
+
+/// Downloads in progress, so everyone who wants the same font waits on one.
+type InFlight = Mutex<HashMap<(String, FontVariant), Arc<OnceCell<Result<Arc<Font>, String>>>>>;
+
+/// Read and parse a font file on disk, and say how big the file was.
+fn read_font(family: &str, path: &str) -> Result<(Font, usize), FontError> {
+	let mut file = File::open(path)?;
+
+	let mut buffer = vec![];
+	file.read_to_end(&mut buffer)?;
+
+	let font = parse_font(&buffer).map_err(|reason| FontError::Parse {
+		family: family.to_owned(),
+		reason,
+	})?;
+
+	Ok((font, buffer.len()))
+}
+
+/// Parse a font, unpacking it first if it's WOFF or WOFF2.
+pub fn parse_font(data: &[u8]) -> Result<Font, String> {
+	let sfnt = woff::decode(data).map_err(|e| e.to_string())?;
+	fontster::parse_font(&sfnt).map_err(|e| e.to_string())
+}
+
+/// Font cache hits, misses, and downloads for a single render.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FontCounts {
+	pub hits: usize,
+	pub misses: usize,
+	pub downloads: usize,
+}
+
+/// Every font we could download, from the Google Fonts API or a snapshot of
+/// it on disk.
+#[derive(Default)]
+pub struct Catalog {
 	fonts: Vec<FontFamily>,
 }
 
-impl FontCache {
-	fn new<P: Into<PathBuf>>(location: P) -> io::Result<Self> {
-		let mut cache = FontCache {
-			location: location.into(),
-			fonts: vec![],
+impl Catalog {
+	/// Get the font list. This blocks on the network.
+	pub fn fetch(google_fonts_apikey: &str) -> Result<Self, FontError> {
+		Self::parse(&Self::download(google_fonts_apikey)?)
+	}
+
+	/// Get the font list as a snapshot we can save and [Catalog::parse]
+	/// later. It's the API's response with only the fields we use. This blocks
+	/// on the network.
+	pub fn download(google_fonts_apikey: &str) -> Result<String, FontError> {
+		let api_str = format!(
+			"https://www.googleapis.com/webfonts/v1/webfonts?key={}",
+			google_fonts_apikey
+		);
+
+		let before = Instant::now();
+		let body = agent()
+			.get(&api_str)
+			.call()
+			.map_err(|e| FontError::List(e.to_string()))?
+			.into_string()?;
+		let json: Value =
+			serde_json::from_str(&body).map_err(|e| FontError::List(e.to_string()))?;
+
+		let items = match &json["items"] {
+			Value::Array(items) => items,
+			_ => return Err(FontError::List("response had no items".into())),
 		};
 
-		cache.populate().unwrap();
+		let items: Vec<Value> = items
+			.iter()
+			.filter_map(|item| item.as_object())
+			.map(|item| {
+				let kept: Map<String, Value> = item
+					.iter()
+					.filter(|(key, _)| SNAPSHOT_FIELDS.contains(&key.as_str()))
+					.map(|(key, value)| (key.clone(), value.clone()))
+					.collect();
+
+				Value::Object(kept)
+			})
+			.collect();
+
+		info!(
+			families = items.len(),
+			elapsed_ms = before.elapsed().as_millis() as u64,
+			"got the font list"
+		);
+
+		serde_json::to_string_pretty(&serde_json::json!({ "items": items }))
+			.map_err(|e| FontError::List(e.to_string()))
+	}
+
+	/// Read a font list from the API or a snapshot.
+	pub fn parse(snapshot: &str) -> Result<Self, FontError> {
+		let json: Value =
+			serde_json::from_str(snapshot).map_err(|e| FontError::List(e.to_string()))?;
 
-		Ok(cache)
+		Ok(Self {
+			fonts: parse_font_list(&json)?,
+		})
 	}
 
-	fn family<S: AsRef<str>>(&self, name: S) -> Option<&FontFamily> {
-		for font in &self.fonts {
-			if font.face == name.as_ref() {
-				return Some(font);
+	/// Read a snapshot saved by `textual fonts sync`.
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, FontError> {
+		Self::parse(&fs::read_to_string(path)?)
+	}
+
+	/// Write a snapshot from [Catalog::download]. It's written next to the
+	/// old one and moved over it so a reader never sees half a file.
+	pub fn save<P: AsRef<Path>>(snapshot: &str, path: P) -> io::Result<()> {
+		let path = path.as_ref();
+		let temporary = path.with_extension("tmp");
+
+		fs::write(&temporary, snapshot)?;
+		fs::rename(&temporary, path)
+	}
+
+	/// How many families there are.
+	pub fn len(&self) -> usize {
+		self.fonts.len()
+	}
+}
+
+/// What we keep of each family when saving a snapshot of the font list.
+const SNAPSHOT_FIELDS: &[&str] = &[
+	"family",
+	"category",
+	"subsets",
+	"variants",
+	"files",
+	"lastModified",
+];
+
+/// Which families to list. Everything that's set has to match.
+#[derive(Default)]
+pub struct FontFilter {
+	/// Part of the family's name, ignoring case
+	pub search: Option<String>,
+	pub category: Option<String>,
+	/// A script the family has to cover, like latin
+	pub subset: Option<String>,
+	/// The family needs a variant in this weight...
+	pub weight: Option<FontWeight>,
+	/// ...and style
+	pub style: Option<FontStyle>,
+}
+
+impl FontFilter {
+	fn matches(&self, family: &FamilyInfo) -> bool {
+		let search = self.search.as_ref().is_none_or(|search| {
+			family
+				.family
+				.to_lowercase()
+				.contains(&search.to_lowercase())
+		});
+		let category = self.category.as_ref().is_none_or(|category| {
+			family
+				.category
+				.is_some_and(|c| c.eq_ignore_ascii_case(category))
+		});
+		let subset = self.subset.as_ref().is_none_or(|subset| {
+			family
+				.subsets
+				.iter()
+				.any(|s| s.eq_ignore_ascii_case(subset))
+		});
+		let variant = family.variants.iter().any(|variant| {
+			self.weight
+				.is_none_or(|weight| variant.weight == weight.to_string())
+				&& self
+					.style
+					.is_none_or(|style| variant.style == style.to_string())
+		});
+
+		search && category && subset && variant
+	}
+}
+
+/// What we know about a family, for listing it.
+#[derive(Serialize)]
+pub struct FamilyInfo<'a> {
+	pub family: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub category: Option<&'a str>,
+	pub subsets: &'a [String],
+	pub variants: Vec<VariantInfo>,
+}
+
+#[derive(Serialize)]
+pub struct VariantInfo {
+	pub weight: String,
+	pub style: String,
+	/// Whether we have it without downloading
+	pub cached: bool,
+	/// Whether it's one of the configured local fonts
+	pub local: bool,
+}
+
+pub struct FontProvider {
+	default: Arc<Font>,
+	fonts: Vec<FontFamily>,
+	/// Fonts from the configured directories. They're used before the cache
+	/// or the catalog.
+	local: Vec<FontFamily>,
+	/// Whether the catalog has been loaded. Until then only cached fonts work.
+	ready: bool,
+	font_cache: Arc<FontCache>,
+	downloads: Arc<InFlight>,
+	agent: ureq::Agent,
+}
+
+/// What looking up a font found.
+pub enum Lookup {
+	Found(Arc<Font>),
+	/// We have to read it from disk or download it. See [Load::fetch]
+	Load(Load),
+}
+
+/// A font that isn't parsed yet. Like [Download], it doesn't borrow the
+/// provider, so the lock on it can be let go of while we read.
+pub struct Load {
+	family: String,
+	variant: FontVariant,
+	/// A local font to try first
+	local: Option<String>,
+	/// Whether the font cache has a file for it
+	cached: bool,
+	font_cache: Arc<FontCache>,
+	/// Where to get it if neither works, or why we can't
+	download: Result<Download, FontError>,
+}
+
+impl Load {
+	/// Read and parse the font off the executor, or download it if we don't
+	/// have a copy that works.
+	pub async fn fetch(self, counts: &mut FontCounts) -> Result<Arc<Font>, FontError> {
+		if self.local.is_some() || self.cached {
+			let family = self.family.clone();
+			let variant = self.variant;
+			let local = self.local;
+			let font_cache = self.font_cache.clone();
+
+			let found = tokio::task::spawn_blocking(move || {
+				read_disk(&family, variant, local, &font_cache)
+			})
+			.await
+			.map_err(io::Error::other)?;
+
+			if let Some(font) = found {
+				counts.hits += 1;
+				return Ok(font);
 			}
 		}
 
-		None
+		let download = self.download?;
+		debug!(family = %self.family, variant = %self.variant, "font cache miss");
+		counts.misses += 1;
+
+		download.fetch(counts).await
 	}
+}
 
-	fn family_mut<S: AsRef<str>>(&mut self, name: S) -> Option<&mut FontFamily> {
-		for font in self.fonts.iter_mut() {
-			if font.face == name.as_ref() {
+/// A local font, or one from the font cache. Either can turn out to be
+/// unusable, and then we'd rather download a copy than fail.
+fn read_disk(
+	family: &str,
+	variant: FontVariant,
+	local: Option<String>,
+	font_cache: &FontCache,
+) -> Option<Arc<Font>> {
+	if let Some(path) = local {
+		match read_font(family, &path) {
+			Ok((font, size)) => {
+				debug!(family, %variant, path = %path, "local font");
+				let font = Arc::new(font);
+				font_cache.insert_local(&path, font.clone(), size);
 				return Some(font);
 			}
+			Err(e) => {
+				// Maybe the cache or the catalog has one that works
+				warn!(family, %variant, path = %path, error = %e, "local font is unusable");
+			}
 		}
-
-		None
 	}
 
-	fn regular<S: AsRef<str>>(&self, fam: S) -> Option<Font> {
-		self.variant(fam, FontVariant::default())
+	match font_cache.get(family, variant) {
+		Ok(Some(font)) => {
+			debug!(family, %variant, "font cache hit");
+			Some(font)
+		}
+		Ok(None) => None,
+		Err(e) => {
+			// We'll try and download it again, which replaces the bad file
+			warn!(family, %variant, error = %e, "cached font is unusable");
+			None
+		}
 	}
+}
 
-	pub fn variant<F: AsRef<str>>(&self, family: F, variant: FontVariant) -> Option<Font> {
-		if let Some(fam) = self.family(family.as_ref()) {
-			if let Some(path) = fam.variant_path(variant) {
-				let mut file = File::open(path).unwrap();
-
-				let mut buffer = vec![];
-				file.read_to_end(&mut buffer).unwrap();
+/// A font we have to download. It doesn't borrow the provider, so the lock
+/// on it can be let go of while we wait.
+pub struct Download {
+	family: String,
+	variant: FontVariant,
+	url: String,
+	font_cache: Arc<FontCache>,
+	downloads: Arc<InFlight>,
+	agent: ureq::Agent,
+}
 
-				return Some(fontster::parse_font(&mut buffer).unwrap());
+impl Download {
+	/// Download the font, or wait for whoever is already downloading it.
+	pub async fn fetch(self, counts: &mut FontCounts) -> Result<Arc<Font>, FontError> {
+		let key = (self.family.clone(), self.variant);
+		let cell = self
+			.downloads
+			.lock()
+			.unwrap()
+			.entry(key.clone())
+			.or_default()
+			.clone();
+
+		let downloaded = AtomicBool::new(false);
+		let result = cell
+			.get_or_init(|| self.download(&downloaded))
+			.await
+			.clone();
+
+		// Whoever gets here first is done with it. Later requests find the
+		// font in the cache, or try again if it failed
+		{
+			let mut downloads = self.downloads.lock().unwrap();
+			if downloads.get(&key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+				downloads.remove(&key);
 			}
 		}
 
-		None
+		if downloaded.load(Ordering::Relaxed) && result.is_ok() {
+			counts.downloads += 1;
+		}
+
+		result.map_err(|reason| FontError::Fetch {
+			family: self.family,
+			reason,
+		})
 	}
 
-	fn populate(&mut self) -> io::Result<()> {
-		let dir = std::fs::read_dir(&self.location)?;
+	/// `downloaded` is set if we actually went to the network for it.
+	async fn download(&self, downloaded: &AtomicBool) -> Result<Arc<Font>, String> {
+		// Someone else may have finished downloading it since we looked
+		if let Some(font) = self.font_cache.get_parsed(&self.family, self.variant) {
+			return Ok(font);
+		}
+		downloaded.store(true, Ordering::Relaxed);
+
+		let mut delay = RETRY_DELAY;
+		let mut attempt = 1;
+		let data = loop {
+			let agent = self.agent.clone();
+			let url = self.url.clone();
+			let result = tokio::task::spawn_blocking(move || download(&agent, &url))
+				.await
+				.map_err(|e| e.to_string())?;
+
+			match result {
+				Ok(data) => break data,
+				Err(Attempt::Retry(reason)) if attempt < DOWNLOAD_ATTEMPTS => {
+					warn!(family = %self.family, variant = %self.variant, attempt, error = %reason, "font download failed, trying again");
+					tokio::time::sleep(delay).await;
+					delay *= 2;
+					attempt += 1;
+				}
+				Err(Attempt::Retry(reason)) | Err(Attempt::Fail(reason)) => return Err(reason),
+			}
+		};
 
-		for entry in dir {
-			let entry = entry.unwrap();
-			let path = entry.path();
-			let fname = path.file_stem().unwrap().to_str().unwrap();
+		let family = self.family.clone();
+		let variant = self.variant;
+		let font_cache = self.font_cache.clone();
+		tokio::task::spawn_blocking(move || {
+			// Cache what's inside WOFF and WOFF2, so it's only unpacked once
+			let data = woff::decode(&data)
+				.map_err(|e| format!("it isn't a font we can read: {}", e))?
+				.into_owned();
+
+			// Parse before saving so we never cache something we can't use
+			let font = fontster::parse_font(&data)
+				.map_err(|e| format!("it isn't a font we can read: {}", e))?;
+			let font = Arc::new(font);
+
+			if let Err(e) = font_cache.insert(&family, variant, &data, font.clone()) {
+				warn!(family = %family, %variant, error = %e, "failed to save font");
+			}
 
-			let (family, variant) = match fname.rsplit_once('-') {
-				Some((family, variant)) => match variant.split_once(' ') {
-					Some((weight, style)) => {
-						let style = match style.parse() {
-							Ok(style) => style,
-							Err(e) => {
-								eprintln!("Unable to recognise font style for {}", fname);
-								continue;
-							}
-						};
+			Ok(font)
+		})
+		.await
+		.map_err(|e| e.to_string())?
+	}
+}
 
-						let weight = match weight.parse() {
-							Ok(weight) => weight,
-							Err(e) => {
-								eprintln!("Unable to recognise font weight for {}", fname);
-								continue;
-							}
-						};
+/// Why a download attempt failed, and whether trying again might help.
+enum Attempt {
+	Retry(String),
+	Fail(String),
+}
 
-						(family, FontVariant::new(weight, style))
-					}
-					None => {
-						eprintln!("Unable to recognise variant for {}", fname);
-						continue;
-					}
-				},
-				_ => {
-					eprintln!("Unknown file in cache: {}", fname);
-					continue;
+/// Blocks, so it's run off the async threads.
+fn download(agent: &ureq::Agent, url: &str) -> Result<Vec<u8>, Attempt> {
+	let response = match agent.get(url).call() {
+		Ok(response) => response,
+		Err(ureq::Error::Status(code, _)) if code == 429 || code >= 500 => {
+			return Err(Attempt::Retry(format!("the server said {}", code)))
+		}
+		Err(ureq::Error::Status(code, _)) => {
+			return Err(Attempt::Fail(format!("the server said {}", code)))
+		}
+		Err(e) => return Err(Attempt::Retry(e.to_string())),
+	};
+
+	let mut buffer: Vec<u8> = Vec::new();
+	response
+		.into_reader()
+		.read_to_end(&mut buffer)
+		.map_err(|e| Attempt::Retry(e.to_string()))?;
+
+	Ok(buffer)
+}
+
+impl FontProvider {
+	/// Open the font cache, keeping `budget` bytes of fonts on disk and
+	/// `memory_budget` bytes parsed in memory. Fonts that aren't cached can't
+	/// be had until a [Catalog] is given to [FontProvider::set_catalog].
+	pub fn new<P: AsRef<Path>>(
+		fontcache: P,
+		budget: usize,
+		memory_budget: usize,
+	) -> Result<Self, FontError> {
+		let default =
+			fontster::parse_font(include_bytes!("../Cabin-Regular.ttf")).map_err(|e| {
+				FontError::Parse {
+					family: "Cabin".into(),
+					reason: e.to_string(),
 				}
-			};
+			})?;
 
-			let ftype = entry.file_type().unwrap();
+		Ok(Self {
+			default: Arc::new(default),
+			fonts: vec![],
+			local: vec![],
+			ready: false,
+			font_cache: Arc::new(FontCache::open(fontcache.as_ref(), budget, memory_budget)?),
+			downloads: Arc::new(Mutex::new(HashMap::new())),
+			agent: agent(),
+		})
+	}
+
+	pub fn set_catalog(&mut self, catalog: Catalog) {
+		self.fonts = catalog.fonts;
+		self.ready = true;
+	}
 
-			if ftype.is_file() {
-				if let Some(fam) = self.family_mut(family) {
-					fam.push(variant, entry.path().to_str().unwrap());
-				} else {
-					let mut fam = FontFamily::new(family);
-					fam.push(variant, entry.path().to_str().unwrap());
+	/// Use these fonts instead of any the cache or catalog have by the same
+	/// name, weight, and style.
+	pub fn set_local(&mut self, fonts: Vec<LocalFont>) {
+		self.local.clear();
 
-					self.fonts.push(fam);
+		for font in fonts {
+			let path = font.path.to_string_lossy();
+
+			match self.local.iter_mut().find(|fam| fam.face == font.family) {
+				Some(fam) => fam.push(font.variant, path),
+				None => {
+					let mut fam = FontFamily::new(font.family);
+					fam.push(font.variant, path);
+					self.local.push(fam);
 				}
 			}
 		}
+	}
 
-		println!("{} files in cache", self.fonts.len());
-
-		Ok(())
+	/// Whether we have the catalog and can get any font.
+	pub fn ready(&self) -> bool {
+		self.ready
 	}
 
-	fn save_font<F: AsRef<str>>(&mut self, family: F, variant: FontVariant, buf: &[u8]) {
-		let family = family.as_ref();
+	pub fn cached(&self) -> usize {
+		self.font_cache.len()
+	}
 
-		let fname = format!("{}-{} {}.ttf", family, variant.weight, variant.style);
-		let mut path = self.location.clone();
-		path.push(fname);
+	/// Every family that matches the filter, sorted by name. Families we only
+	/// have locally or in the cache are listed too.
+	pub fn search(&self, filter: &FontFilter) -> Vec<FamilyInfo<'_>> {
+		let mut faces: Vec<String> = self
+			.local
+			.iter()
+			.chain(self.fonts.iter())
+			.map(|fam| fam.face.clone())
+			.chain(self.font_cache.families())
+			.collect();
+
+		faces.sort_unstable();
+		faces.dedup();
+
+		faces
+			.into_iter()
+			.filter_map(|face| self.info(&face))
+			.filter(|info| filter.matches(info))
+			.collect()
+	}
 
-		let mut file = File::create(&path).unwrap();
-		file.write_all(buf).unwrap();
+	/// Everything we know about one family, if we know it at all.
+	pub fn describe<S: AsRef<str>>(&self, family: S) -> Option<FamilyInfo<'_>> {
+		self.info(family.as_ref())
+	}
 
-		if let Some(family) = self.family_mut(family) {
-			family.push(variant, path.to_string_lossy())
-		} else {
-			let mut fam = FontFamily::new(family);
-			fam.push(variant, path.to_string_lossy());
+	/// What the local fonts, the catalog, and the cache have between them.
+	/// Only the catalog knows categories and subsets.
+	fn info(&self, face: &str) -> Option<FamilyInfo<'_>> {
+		let local = self.local_family(face);
+		let known = self.family(face);
+		let cached = self.font_cache.variants(face);
 
-			self.fonts.push(fam);
+		if local.is_none() && known.is_none() && cached.is_empty() {
+			return None;
 		}
 
-		println!("saved font {}", path.to_str().unwrap());
+		let mut variants: Vec<FontVariant> = [local, known]
+			.iter()
+			.flatten()
+			.flat_map(|fam| fam.variants.iter().map(|(v, _)| *v))
+			.chain(cached.iter().copied())
+			.collect();
+		variants.sort_unstable_by_key(|v| (v.style as u8, v.weight.into_weight_number()));
+		variants.dedup();
+
+		let is_local = |variant| local.and_then(|fam| fam.variant_path(variant)).is_some();
+
+		Some(FamilyInfo {
+			family: face.to_owned(),
+			category: known.and_then(|fam| fam.category.as_deref()),
+			subsets: known.map(|fam| fam.subsets.as_slice()).unwrap_or_default(),
+			variants: variants
+				.into_iter()
+				.map(|variant| VariantInfo {
+					weight: variant.weight.to_string(),
+					style: variant.style.to_string(),
+					cached: is_local(variant) || cached.contains(&variant),
+					local: is_local(variant),
+				})
+				.collect(),
+		})
 	}
-}
 
-pub struct FontProvider {
-	default: Arc<Font>,
-	fonts: Vec<FontFamily>,
-	font_cache: FontCache,
-}
+	/// Whether getting this font means downloading it. Fonts we don't know
+	/// about aren't downloaded, they're an error.
+	pub fn needs_download<S: AsRef<str>>(&self, family: S, variant: FontVariant) -> bool {
+		let local = self
+			.local_family(family.as_ref())
+			.and_then(|fam| fam.variant_path(variant))
+			.is_some();
 
-impl FontProvider {
-	pub fn new<P: AsRef<Path>>(fontcache: P, google_fonts_apikey: &str) -> Self {
-		let google = get_fonts_from_google(google_fonts_apikey).unwrap();
-
-		Self {
-			default: Arc::new(
-				fontster::parse_font(include_bytes!("../Cabin-Regular.ttf")).unwrap(),
-			),
-			fonts: google,
-			font_cache: FontCache::new(fontcache.as_ref()).unwrap(),
-		}
-	}
+		let cached = self.font_cache.contains(family.as_ref(), variant);
 
-	pub fn cached(&self) -> usize {
-		self.font_cache
-			.fonts
-			.iter()
-			.fold(0, |acc, fam| acc + fam.variants.len())
+		let known = self
+			.family(family.as_ref())
+			.and_then(|fam| fam.variant_path(variant))
+			.is_some();
+
+		known && !cached && !local
 	}
 
 	fn push(&mut self, fam: FontFamily) {
@@ -190,33 +630,73 @@ impl FontProvider {
 		None
 	}
 
-	pub fn variant<F: Into<String>>(&mut self, family: F, variant: FontVariant) -> Arc<Font> {
-		let family_string = family.into();
+	fn local_family<S: AsRef<str>>(&self, face: S) -> Option<&FontFamily> {
+		self.local.iter().find(|fam| fam.face == face.as_ref())
+	}
 
-		if let Some(font) = self.font_cache.variant(&family_string, variant) {
-			println!("hit cache for {} {}", family_string, variant);
+	/// Find a font that's already parsed, or say where to read or download
+	/// it from. Only needs the provider read and never touches the disk, so
+	/// the lock isn't held while we wait on either.
+	pub fn variant<F: Into<String>>(
+		&self,
+		family: F,
+		variant: FontVariant,
+		counts: &mut FontCounts,
+	) -> Result<Lookup, FontError> {
+		let family_string = family.into();
 
-			return Arc::new(font);
-		} else if let Some(family) = self.family(&family_string) {
-			println!("missed cache for {} {}", family_string, variant);
+		let local = self
+			.local_family(&family_string)
+			.and_then(|fam| fam.variant_path(variant))
+			.cloned();
 
-			if let Some(var) = family.variant_path(variant).map(<_>::to_owned) {
-				let response = ureq::get(&var).call().unwrap();
+		// Local fonts win over the cache
+		let parsed = match &local {
+			Some(path) => self.font_cache.get_local(path),
+			None => self.font_cache.get_parsed(&family_string, variant),
+		};
 
-				let mut buffer: Vec<u8> = Vec::new();
-				response.into_reader().read_to_end(&mut buffer).unwrap();
+		if let Some(font) = parsed {
+			debug!(family = %family_string, %variant, "font cache hit");
+			counts.hits += 1;
+			return Ok(Lookup::Found(font));
+		}
 
-				self.font_cache.save_font(family_string, variant, &buffer);
+		let cached = self.font_cache.contains(&family_string, variant);
+		let download = match self.family(&family_string) {
+			Some(family) => match family.variant_path(variant) {
+				Some(url) => Ok(Download {
+					family: family_string.clone(),
+					variant,
+					url: url.to_owned(),
+					font_cache: self.font_cache.clone(),
+					downloads: self.downloads.clone(),
+					agent: self.agent.clone(),
+				}),
+				None => Err(FontError::UnknownVariant {
+					family: family_string.clone(),
+					variant,
+				}),
+			},
+			None if !self.ready => Err(FontError::NotReady(family_string.clone())),
+			None => Err(FontError::UnknownFamily(family_string.clone())),
+		};
 
-				return Arc::new(fontster::parse_font(&buffer).unwrap());
+		// Nothing on disk to try, so we know now if it's going to fail
+		if local.is_none() && !cached {
+			if let Err(e) = download {
+				return Err(e);
 			}
 		}
 
-		self.default.clone()
-	}
-
-	pub fn regular<S: AsRef<str>>(&mut self, fam: S) -> Arc<Font> {
-		self.variant(fam.as_ref(), FontVariant::default())
+		Ok(Lookup::Load(Load {
+			family: family_string,
+			variant,
+			local,
+			cached,
+			font_cache: self.font_cache.clone(),
+			download,
+		}))
 	}
 
 	pub fn default_font(&self) -> Arc<Font> {
@@ -224,28 +704,36 @@ impl FontProvider {
 	}
 }
 
-fn get_fonts_from_google<S: AsRef<str>>(apikey: S) -> Result<Vec<FontFamily>, ureq::Error> {
-	let api_str = format!(
-		"https://www.googleapis.com/webfonts/v1/webfonts?key={}",
-		apikey.as_ref()
-	);
-
-	let before = Instant::now();
-	let response = ureq::get(&api_str).call()?;
-	let json: Value = serde_json::from_str(&response.into_string()?).unwrap();
-
+/// The families in a Google Fonts API response, or a snapshot of one.
+fn parse_font_list(json: &Value) -> Result<Vec<FontFamily>, FontError> {
 	let fonts = match &json["items"] {
 		Value::Array(fonts) => fonts,
-		_ => panic!(),
+		_ => return Err(FontError::List("there are no items".into())),
 	};
 
 	let mut ret = vec![];
 
 	for item in fonts {
-		let name = item["family"].as_str().unwrap();
+		let (name, files) = match (item["family"].as_str(), item["files"].as_object()) {
+			(Some(name), Some(files)) => (name, files),
+			_ => {
+				warn!("skipping malformed font list item");
+				continue;
+			}
+		};
 		let mut family = FontFamily::new(name);
-
-		for (style, filepath) in item["files"].as_object().unwrap() {
+		family.category = item["category"].as_str().map(|s| s.to_owned());
+		family.subsets = item["subsets"]
+			.as_array()
+			.map(|subsets| {
+				subsets
+					.iter()
+					.filter_map(|subset| subset.as_str().map(|s| s.to_owned()))
+					.collect()
+			})
+			.unwrap_or_default();
+
+		for (style, filepath) in files {
 			// Font styles can be one of three things...
 			let variant = if style == "regular" {
 				// ...just the word "regular" which means normal weight and style
@@ -255,26 +743,33 @@ fn get_fonts_from_google<S: AsRef<str>>(apikey: S) -> Result<Vec<FontFamily>, ur
 				FontVariant::new(weight.parse().unwrap_or_default(), FontStyle::Italic)
 			} else {
 				// ...just the weight
-				FontVariant::with_weight(style.parse().unwrap())
+				match style.parse() {
+					Ok(weight) => FontVariant::with_weight(weight),
+					Err(_) => {
+						warn!(family = name, style = %style, "unknown font variant");
+						continue;
+					}
+				}
 			};
 
-			family.push(variant, filepath.as_str().unwrap());
+			if let Some(filepath) = filepath.as_str() {
+				family.push(variant, filepath);
+			}
 		}
 
 		ret.push(family);
 	}
 
-	println!(
-		"getting font list took {}s",
-		Instant::now().duration_since(before).as_secs()
-	);
-
 	Ok(ret)
 }
 
 struct FontFamily {
 	face: String,
 	variants: Vec<(FontVariant, String)>,
+	/// Like serif or handwriting. Only known for fonts from the catalog
+	category: Option<String>,
+	/// Scripts the family covers, like latin or cyrillic
+	subsets: Vec<String>,
 }
 
 impl FontFamily {
@@ -282,6 +777,8 @@ impl FontFamily {
 		FontFamily {
 			face: face.into(),
 			variants: vec![],
+			category: None,
+			subsets: vec![],
 		}
 	}
 
@@ -302,7 +799,7 @@ impl FontFamily {
 	}
 }
 
-#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub struct FontVariant {
 	weight: FontWeight,
 	style: FontStyle,
@@ -326,6 +823,14 @@ impl FontVariant {
 			..Default::default()
 		}
 	}
+
+	pub fn weight(&self) -> FontWeight {
+		self.weight
+	}
+
+	pub fn style(&self) -> FontStyle {
+		self.style
+	}
 }
 
 impl fmt::Display for FontVariant {
@@ -334,7 +839,7 @@ impl fmt::Display for FontVariant {
 	}
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum FontStyle {
 	Normal,
 	Italic,
@@ -375,7 +880,7 @@ impl fmt::Display for FontStyle {
 }
 
 /// Font weight names. List taken from here: https://en.wikipedia.org/wiki/Font#Weight
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum FontWeight {
 	Thin,
 	ExtraLight,
@@ -467,3 +972,268 @@ pub enum FontVariantParseError {
 	#[error("The weight {weight} is not recognised")]
 	UnknownWeightName { weight: String },
 }
+
+#[derive(Debug, Error)]
+pub enum FontError {
+	#[error("The font '{0}' does not exist")]
+	UnknownFamily(String),
+	#[error("The font '{family}' does not come in {variant}")]
+	UnknownVariant {
+		family: String,
+		variant: FontVariant,
+	},
+	#[error("Failed to download the font '{family}': {reason}")]
+	Fetch { family: String, reason: String },
+	#[error("Failed to parse the font '{family}': {reason}")]
+	Parse { family: String, reason: String },
+	#[error("The cached font '{family}' in {variant} is corrupt")]
+	Corrupt {
+		family: String,
+		variant: FontVariant,
+	},
+	#[error("Failed to get the list of fonts: {0}")]
+	List(String),
+	#[error("The font '{0}' isn't cached and we're still getting the list of fonts")]
+	NotReady(String),
+	#[error("{0}")]
+	Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod test {
+	use std::{io::Write, net::TcpListener, path::PathBuf, sync::atomic::AtomicUsize};
+
+	use super::*;
+
+	#[test]
+	fn parse_snapshot() {
+		let snapshot = r#"{
+			"items": [
+				{
+					"family": "Dosis",
+					"category": "sans-serif",
+					"subsets": ["latin", "vietnamese"],
+					"files": {
+						"regular": "https://example.com/dosis-regular.ttf",
+						"700": "https://example.com/dosis-700.ttf",
+						"300italic": "https://example.com/dosis-300italic.ttf"
+					}
+				}
+			]
+		}"#;
+
+		let catalog = Catalog::parse(snapshot).unwrap();
+		assert_eq!(catalog.len(), 1);
+
+		let dosis = &catalog.fonts[0];
+		assert_eq!(dosis.category.as_deref(), Some("sans-serif"));
+		assert_eq!(dosis.subsets, vec!["latin", "vietnamese"]);
+		assert!(dosis
+			.variant_path(FontVariant::with_weight(FontWeight::Bold))
+			.is_some());
+		assert!(dosis
+			.variant_path(FontVariant::new(FontWeight::Light, FontStyle::Italic))
+			.is_some());
+
+		assert!(Catalog::parse(r#"{"kind": "webfonts"}"#).is_err());
+	}
+
+	const FONT: &[u8] = include_bytes!("../Cabin-Regular.ttf");
+
+	/// How the test server answers a request.
+	#[derive(Clone, Copy)]
+	enum Reply {
+		Status(u16),
+		Font,
+		/// The font, but only after a while
+		SlowFont,
+		/// Nothing at all
+		Silence,
+	}
+
+	/// A font server on localhost. Requests are answered with the next reply,
+	/// and the last reply over and over once they run out.
+	struct Server {
+		url: String,
+		requests: Arc<AtomicUsize>,
+	}
+
+	impl Server {
+		fn new(replies: Vec<Reply>) -> Self {
+			let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+			let url = format!("http://{}/font.ttf", listener.local_addr().unwrap());
+			let requests = Arc::new(AtomicUsize::new(0));
+
+			let counter = requests.clone();
+			std::thread::spawn(move || {
+				for stream in listener.incoming() {
+					let mut stream = stream.unwrap();
+					let index = counter.fetch_add(1, Ordering::SeqCst);
+					let reply = replies[index.min(replies.len() - 1)];
+
+					std::thread::spawn(move || {
+						let mut request = [0; 4096];
+						let _ = stream.read(&mut request);
+
+						let (code, body) = match reply {
+							Reply::Status(code) => (code, &b""[..]),
+							Reply::Font => (200, FONT),
+							Reply::SlowFont => {
+								std::thread::sleep(Duration::from_millis(200));
+								(200, FONT)
+							}
+							Reply::Silence => {
+								std::thread::sleep(Duration::from_secs(5));
+								return;
+							}
+						};
+
+						let head = format!(
+							"HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+							code,
+							body.len()
+						);
+						let _ = stream.write_all(head.as_bytes());
+						let _ = stream.write_all(body);
+					});
+				}
+			});
+
+			Self { url, requests }
+		}
+
+		fn requests(&self) -> usize {
+			self.requests.load(Ordering::SeqCst)
+		}
+	}
+
+	/// A provider with an empty font cache of its own, and one font to
+	/// download from `server`.
+	struct Provider {
+		provider: FontProvider,
+		cache: PathBuf,
+	}
+
+	impl Provider {
+		fn new(test: &str, server: &Server) -> Self {
+			let cache = std::env::temp_dir().join(format!(
+				"textual-downloads-{}-{}",
+				test,
+				std::process::id()
+			));
+			let _ = fs::remove_dir_all(&cache);
+			fs::create_dir_all(&cache).unwrap();
+
+			let mut provider = FontProvider::new(&cache, usize::MAX, usize::MAX).unwrap();
+			let snapshot = serde_json::json!({
+				"items": [{ "family": "Test", "files": { "regular": server.url } }]
+			});
+			provider.set_catalog(Catalog::parse(&snapshot.to_string()).unwrap());
+
+			Self { provider, cache }
+		}
+
+		fn load(&self) -> Load {
+			let mut counts = FontCounts::default();
+			match self
+				.provider
+				.variant("Test", FontVariant::default(), &mut counts)
+			{
+				Ok(Lookup::Load(load)) => load,
+				Ok(Lookup::Found(_)) => panic!("the font was already parsed"),
+				Err(e) => panic!("{}", e),
+			}
+		}
+
+		async fn fetch(&self) -> Result<Arc<Font>, FontError> {
+			self.load().fetch(&mut FontCounts::default()).await
+		}
+
+		fn in_flight(&self) -> usize {
+			self.provider.downloads.lock().unwrap().len()
+		}
+	}
+
+	impl Drop for Provider {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.cache);
+		}
+	}
+
+	#[tokio::test]
+	async fn shares_downloads_in_flight() {
+		let server = Server::new(vec![Reply::SlowFont]);
+		let provider = Provider::new("shared", &server);
+
+		let fetches: Vec<_> = (0..3)
+			.map(|_| {
+				let load = provider.load();
+				tokio::spawn(async move {
+					let mut counts = FontCounts::default();
+					let font = load.fetch(&mut counts).await;
+					(font.is_ok(), counts.downloads)
+				})
+			})
+			.collect();
+
+		let mut downloads = 0;
+		for fetch in fetches {
+			let (ok, downloaded) = fetch.await.unwrap();
+			assert!(ok);
+			downloads += downloaded;
+		}
+
+		assert_eq!(server.requests(), 1);
+		assert_eq!(downloads, 1);
+		assert_eq!(provider.in_flight(), 0);
+	}
+
+	#[tokio::test]
+	async fn retries_busy_servers_with_backoff() {
+		let server = Server::new(vec![Reply::Status(503), Reply::Status(429), Reply::Font]);
+		let provider = Provider::new("retry", &server);
+
+		let before = Instant::now();
+		assert!(provider.fetch().await.is_ok());
+		assert_eq!(server.requests(), 3);
+		assert!(before.elapsed() >= RETRY_DELAY * 3);
+	}
+
+	#[tokio::test]
+	async fn gives_up_after_attempts() {
+		let server = Server::new(vec![Reply::Status(500)]);
+		let provider = Provider::new("give-up", &server);
+
+		assert!(provider.fetch().await.is_err());
+		assert_eq!(server.requests(), DOWNLOAD_ATTEMPTS);
+		assert_eq!(provider.in_flight(), 0);
+	}
+
+	#[tokio::test]
+	async fn failures_are_not_kept() {
+		let server = Server::new(vec![Reply::Status(404), Reply::Font]);
+		let provider = Provider::new("not-kept", &server);
+
+		// Not found won't change if we ask again right away
+		assert!(provider.fetch().await.is_err());
+		assert_eq!(server.requests(), 1);
+		assert_eq!(provider.in_flight(), 0);
+
+		assert!(provider.fetch().await.is_ok());
+		assert_eq!(server.requests(), 2);
+	}
+
+	#[tokio::test]
+	async fn downloads_time_out() {
+		let server = Server::new(vec![Reply::Silence]);
+		let mut provider = Provider::new("timeout", &server);
+		provider.provider.agent = ureq::AgentBuilder::new()
+			.timeout(Duration::from_millis(100))
+			.build();
+
+		let before = Instant::now();
+		assert!(provider.fetch().await.is_err());
+		assert_eq!(server.requests(), DOWNLOAD_ATTEMPTS);
+		assert!(before.elapsed() < Duration::from_secs(5));
+	}
+}
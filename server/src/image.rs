@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use crate::color::Color;
+use crate::color::{Color, Gamma};
 
 pub trait ColorProvider: Send + Sync {
 	fn color_at(&self, x: usize, y: usize) -> Color;
@@ -21,6 +21,19 @@ impl ColorProvider for Stripes {
 	}
 }
 
+/// Stretches another provider by a whole number so its pattern looks the same
+/// on an image rendered at a higher pixel density.
+pub struct Scaled {
+	pub provider: Arc<dyn ColorProvider>,
+	pub scale: usize,
+}
+
+impl ColorProvider for Scaled {
+	fn color_at(&self, x: usize, y: usize) -> Color {
+		self.provider.color_at(x / self.scale, y / self.scale)
+	}
+}
+
 pub enum Colors<'a> {
 	RGBA,
 	RGB,
@@ -172,7 +185,9 @@ impl Image {
 		self.data[i + 3] = color.a;
 	}
 
-	pub fn draw_img(&mut self, img: Image, off_x: isize, off_y: isize) {
+	/// Draw an image over this one. Blending happens in linear light as
+	/// described by `gamma`.
+	pub fn draw_img(&mut self, img: Image, off_x: isize, off_y: isize, gamma: &Gamma) {
 		let img_data = img.data();
 		for img_y in 0..(img.height() as isize) {
 			// current pixel y value
@@ -199,24 +214,24 @@ impl Image {
 					let img_index = img.xy_to_index(img_x as usize, img_y as usize);
 					let our_index = self.xy_to_index(x as usize, y as usize);
 
-					let nrml = |c: u8| c as f32 / 255.0;
-
 					let img_alpha_float = img_data[img_index + 3] as f32 / 255.0;
 					let our_alpha_float = self.data[our_index + 3] as f32 / 255.0;
 					let mixed_alpha = img_alpha_float + our_alpha_float * (1.0 - img_alpha_float);
 
 					let mix = |color_under: u8, color_over: u8| {
-						let nrml_over = nrml(color_over);
-						let nrml_under = nrml(color_under);
-
 						if img_alpha_float == 0.0 {
 							color_under
 						} else if our_alpha_float == 0.0 {
 							color_over
 						} else {
-							((((nrml_over * img_alpha_float)
-								+ ((nrml_under * our_alpha_float) * (1.0 - img_alpha_float)))
-								/ mixed_alpha) * 255.0) as u8
+							let linear_over = gamma.decode(color_over);
+							let linear_under = gamma.decode(color_under);
+
+							gamma.encode(
+								((linear_over * img_alpha_float)
+									+ ((linear_under * our_alpha_float) * (1.0 - img_alpha_float)))
+									/ mixed_alpha,
+							)
 						}
 					};
 
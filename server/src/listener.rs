@@ -0,0 +1,335 @@
+use std::{
+	fmt,
+	fs::File,
+	io::{self, BufReader},
+	net::{IpAddr, Ipv4Addr, SocketAddr},
+	os::unix::fs::FileTypeExt,
+	path::{Path, PathBuf},
+	pin::Pin,
+	sync::{Arc, RwLock},
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use hyper::server::accept::Accept;
+use thiserror::Error;
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	net::{TcpListener, TcpStream, UnixListener, UnixStream},
+	sync::mpsc,
+};
+use tokio_rustls::{
+	rustls::{self, Certificate, PrivateKey, ServerConfig},
+	server::TlsStream,
+	TlsAcceptor,
+};
+use tracing::{debug, error};
+
+/// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting again after failing to.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// How many accepted connections can wait for the server to pick them up.
+const BACKLOG: usize = 128;
+
+/// Where we listen.
+pub enum Bind {
+	Tcp(SocketAddr),
+	/// For sitting behind a reverse proxy on the same machine
+	Unix(PathBuf),
+}
+
+impl fmt::Display for Bind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Bind::Tcp(address) => write!(f, "{}", address),
+			Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+		}
+	}
+}
+
+/// A certificate and key we can read again when they change on disk.
+pub struct Tls {
+	certificate: PathBuf,
+	key: PathBuf,
+	config: RwLock<Arc<ServerConfig>>,
+}
+
+impl Tls {
+	pub fn new<P: Into<PathBuf>>(certificate: P, key: P) -> Result<Self, TlsError> {
+		let certificate = certificate.into();
+		let key = key.into();
+		let config = Self::load(&certificate, &key)?;
+
+		Ok(Self {
+			certificate,
+			key,
+			config: RwLock::new(Arc::new(config)),
+		})
+	}
+
+	/// Read the certificate and key again. If they're no good we keep using
+	/// what we had.
+	pub fn reload(&self) -> Result<(), TlsError> {
+		let config = Self::load(&self.certificate, &self.key)?;
+		*self.config.write().unwrap() = Arc::new(config);
+		Ok(())
+	}
+
+	/// The files to watch for changes.
+	pub fn files(&self) -> [&Path; 2] {
+		[&self.certificate, &self.key]
+	}
+
+	fn acceptor(&self) -> TlsAcceptor {
+		TlsAcceptor::from(self.config.read().unwrap().clone())
+	}
+
+	fn load(certificate: &Path, key: &Path) -> Result<ServerConfig, TlsError> {
+		let read = |path: &Path| -> Result<Vec<rustls_pemfile::Item>, TlsError> {
+			let file = File::open(path).map_err(|e| TlsError::Read(path.to_owned(), e))?;
+			rustls_pemfile::read_all(&mut BufReader::new(file))
+				.map_err(|e| TlsError::Read(path.to_owned(), e))
+		};
+
+		let certificates: Vec<Certificate> = read(certificate)?
+			.into_iter()
+			.filter_map(|item| match item {
+				rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+				_ => None,
+			})
+			.collect();
+
+		if certificates.is_empty() {
+			return Err(TlsError::NoCertificate(certificate.to_owned()));
+		}
+
+		let key = read(key)?
+			.into_iter()
+			.find_map(|item| match item {
+				rustls_pemfile::Item::PKCS8Key(der)
+				| rustls_pemfile::Item::RSAKey(der)
+				| rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+				_ => None,
+			})
+			.ok_or_else(|| TlsError::NoKey(key.to_owned()))?;
+
+		let mut config = ServerConfig::builder()
+			.with_safe_defaults()
+			.with_no_client_auth()
+			.with_single_cert(certificates, key)?;
+
+		// Prefer HTTP/2, hyper speaks both
+		config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+		Ok(config)
+	}
+}
+
+/// A connection from a client, however it reached us.
+pub struct Connection {
+	stream: Stream,
+	remote: IpAddr,
+}
+
+enum Stream {
+	Tcp(TcpStream),
+	Unix(UnixStream),
+	Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+	/// Who's on the other end. Unix socket peers are on this machine, so
+	/// they're localhost.
+	pub fn remote(&self) -> IpAddr {
+		self.remote
+	}
+
+	/// Whether the connection is encrypted by us.
+	pub fn secure(&self) -> bool {
+		matches!(self.stream, Stream::Tls(_))
+	}
+}
+
+impl AsyncRead for Connection {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match &mut self.get_mut().stream {
+			Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+			Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+			Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+		}
+	}
+}
+
+impl AsyncWrite for Connection {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match &mut self.get_mut().stream {
+			Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+			Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+			Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match &mut self.get_mut().stream {
+			Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+			Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+			Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match &mut self.get_mut().stream {
+			Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+			Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+			Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+		}
+	}
+}
+
+/// Connections ready to be served. TLS handshakes happen before a connection
+/// gets here, so a slow client can't hold up anyone else.
+pub struct Incoming {
+	connections: mpsc::Receiver<Connection>,
+}
+
+impl Accept for Incoming {
+	type Conn = Connection;
+	type Error = io::Error;
+
+	fn poll_accept(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+	) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+		self.get_mut().connections.poll_recv(cx).map(|c| c.map(Ok))
+	}
+}
+
+/// Start accepting connections. TLS is only for TCP, a reverse proxy in
+/// front of a Unix socket already did it.
+pub async fn listen(bind: &Bind, tls: Option<Arc<Tls>>) -> io::Result<Incoming> {
+	let (sender, connections) = mpsc::channel(BACKLOG);
+
+	match bind {
+		Bind::Tcp(address) => {
+			let listener = TcpListener::bind(address).await?;
+			tokio::spawn(accept_tcp(listener, tls, sender));
+		}
+		Bind::Unix(path) => {
+			// A socket left behind by the last run would stop us binding, but
+			// anything else there is someone's file and not ours to remove
+			match std::fs::symlink_metadata(path) {
+				Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+				Ok(_) => {
+					return Err(io::Error::new(
+						io::ErrorKind::AlreadyExists,
+						format!("{} exists and isn't a socket", path.display()),
+					))
+				}
+				Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+				Err(e) => return Err(e),
+			}
+
+			let listener = UnixListener::bind(path)?;
+			tokio::spawn(accept_unix(listener, sender));
+		}
+	}
+
+	Ok(Incoming { connections })
+}
+
+async fn accept_tcp(
+	listener: TcpListener,
+	tls: Option<Arc<Tls>>,
+	sender: mpsc::Sender<Connection>,
+) {
+	loop {
+		let (stream, remote) = match listener.accept().await {
+			Ok(accepted) => accepted,
+			Err(e) => {
+				// Usually out of file descriptors, give some a chance to close
+				error!(error = %e, "failed to accept a connection");
+				tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+				continue;
+			}
+		};
+
+		let tls = match tls.as_ref() {
+			None => {
+				let connection = Connection {
+					stream: Stream::Tcp(stream),
+					remote: remote.ip(),
+				};
+
+				if sender.send(connection).await.is_err() {
+					return;
+				}
+
+				continue;
+			}
+			Some(tls) => tls.acceptor(),
+		};
+
+		let sender = sender.clone();
+		tokio::spawn(async move {
+			match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+				Ok(Ok(stream)) => {
+					let connection = Connection {
+						stream: Stream::Tls(Box::new(stream)),
+						remote: remote.ip(),
+					};
+
+					// The server is shutting down if nobody's receiving
+					let _ = sender.send(connection).await;
+				}
+				Ok(Err(e)) => debug!(%remote, error = %e, "TLS handshake failed"),
+				Err(_) => debug!(%remote, "TLS handshake timed out"),
+			}
+		});
+	}
+}
+
+async fn accept_unix(listener: UnixListener, sender: mpsc::Sender<Connection>) {
+	loop {
+		let stream = match listener.accept().await {
+			Ok((stream, _)) => stream,
+			Err(e) => {
+				// Usually out of file descriptors, give some a chance to close
+				error!(error = %e, "failed to accept a connection");
+				tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+				continue;
+			}
+		};
+
+		let connection = Connection {
+			stream: Stream::Unix(stream),
+			remote: IpAddr::V4(Ipv4Addr::LOCALHOST),
+		};
+
+		if sender.send(connection).await.is_err() {
+			return;
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+	#[error("Failed to read {}: {1}", .0.display())]
+	Read(PathBuf, io::Error),
+	#[error("There's no certificate in {}", .0.display())]
+	NoCertificate(PathBuf),
+	#[error("There's no private key in {}", .0.display())]
+	NoKey(PathBuf),
+	#[error("{0}")]
+	Rustls(#[from] rustls::Error),
+}
@@ -0,0 +1,293 @@
+use std::{
+	collections::HashSet,
+	fs, io,
+	path::{Path, PathBuf},
+	process::Command,
+	time::Instant,
+};
+
+use textual::woff;
+use tracing::{debug, info, warn};
+use ttf_parser::{name_id, Face, Language};
+
+use crate::fontprovider::{FontStyle, FontVariant, FontWeight};
+
+/// Where fonts usually are when fontconfig isn't around to ask.
+const SYSTEM_DIRECTORIES: &[&str] = &["/usr/share/fonts", "/usr/local/share/fonts"];
+
+/// A font file we found on disk and what it says it is.
+#[derive(Debug, PartialEq)]
+pub struct LocalFont {
+	pub family: String,
+	pub variant: FontVariant,
+	pub path: PathBuf,
+}
+
+/// Find every font in `directories`, and their subdirectories, and the system
+/// fonts if asked for. What a font is comes from its name and OS/2 tables, so
+/// files can be called anything.
+pub fn scan(directories: &[PathBuf], system: bool) -> Vec<LocalFont> {
+	let before = Instant::now();
+	let mut files = vec![];
+	let mut visited = HashSet::new();
+
+	for directory in directories {
+		if let Err(e) = find_files(directory, &mut files, &mut visited) {
+			warn!(path = %directory.display(), error = %e, "unable to read font directory");
+		}
+	}
+
+	if system {
+		files.extend(system_files());
+	}
+
+	let mut fonts: Vec<LocalFont> = vec![];
+	for path in files {
+		let font = match identify(&path) {
+			Some(font) => font,
+			None => continue,
+		};
+
+		// The first directory listed wins
+		let duplicate = fonts
+			.iter()
+			.any(|f| f.family == font.family && f.variant == font.variant);
+		if duplicate {
+			debug!(path = %path.display(), "skipping a font we already have");
+			continue;
+		}
+
+		fonts.push(font);
+	}
+
+	info!(
+		fonts = fonts.len(),
+		elapsed_ms = before.elapsed().as_millis() as u64,
+		"found local fonts"
+	);
+
+	fonts
+}
+
+/// Links are followed, so directories are only read the first time we come
+/// across them. Otherwise a link back up the tree would never end.
+fn find_files(
+	directory: &Path,
+	files: &mut Vec<PathBuf>,
+	visited: &mut HashSet<PathBuf>,
+) -> io::Result<()> {
+	if !visited.insert(directory.canonicalize()?) {
+		debug!(path = %directory.display(), "already read this font directory");
+		return Ok(());
+	}
+
+	let mut entries: Vec<PathBuf> = fs::read_dir(directory)?
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.collect();
+
+	// So which duplicate wins doesn't depend on the filesystem
+	entries.sort();
+
+	for path in entries {
+		if path.is_dir() {
+			if let Err(e) = find_files(&path, files, visited) {
+				warn!(path = %path.display(), error = %e, "unable to read font directory");
+			}
+		} else if is_font(&path) {
+			files.push(path);
+		}
+	}
+
+	Ok(())
+}
+
+/// Ask fontconfig where the fonts are, or look where they usually are.
+fn system_files() -> Vec<PathBuf> {
+	let listed = Command::new("fc-list")
+		.args(["--format", "%{file}\n"])
+		.output();
+
+	match listed {
+		Ok(output) if output.status.success() => {
+			let mut files: Vec<PathBuf> = String::from_utf8_lossy(&output.stdout)
+				.lines()
+				.map(PathBuf::from)
+				.filter(|path| is_font(path))
+				.collect();
+			files.sort();
+			files
+		}
+		_ => {
+			debug!("fc-list didn't work, looking in the usual places");
+
+			let mut files = vec![];
+			let mut visited = HashSet::new();
+			let home = std::env::var("HOME").ok().map(PathBuf::from);
+			let directories = SYSTEM_DIRECTORIES
+				.iter()
+				.map(PathBuf::from)
+				.chain(home.iter().map(|home| home.join(".local/share/fonts")))
+				.chain(home.iter().map(|home| home.join(".fonts")));
+
+			for directory in directories {
+				let _ = find_files(&directory, &mut files, &mut visited);
+			}
+
+			files
+		}
+	}
+}
+
+/// TrueType and OpenType files, and the same packed up as WOFF or WOFF2.
+/// Collections would need us to say which font in them we want, which we
+/// can't yet.
+fn is_font(path: &Path) -> bool {
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some(ext) => ["ttf", "otf", "woff", "woff2"]
+			.iter()
+			.any(|font| ext.eq_ignore_ascii_case(font)),
+		None => false,
+	}
+}
+
+/// Read a font's family, weight, and style from its tables.
+fn identify(path: &Path) -> Option<LocalFont> {
+	let data = match fs::read(path) {
+		Ok(data) => data,
+		Err(e) => {
+			warn!(path = %path.display(), error = %e, "unable to read font");
+			return None;
+		}
+	};
+
+	let sfnt = match woff::decode(&data) {
+		Ok(sfnt) => sfnt,
+		Err(e) => {
+			warn!(path = %path.display(), error = %e, "unable to unpack font");
+			return None;
+		}
+	};
+
+	let face = match Face::parse(&sfnt, 0) {
+		Ok(face) => face,
+		Err(e) => {
+			warn!(path = %path.display(), error = %e, "unable to parse font");
+			return None;
+		}
+	};
+
+	let family = match family_name(&face) {
+		Some(family) => family,
+		None => {
+			warn!(path = %path.display(), "font has no family name");
+			return None;
+		}
+	};
+
+	let style = match face.style() {
+		ttf_parser::Style::Normal => FontStyle::Normal,
+		ttf_parser::Style::Italic => FontStyle::Italic,
+		ttf_parser::Style::Oblique => FontStyle::Oblique,
+	};
+
+	Some(LocalFont {
+		family,
+		variant: FontVariant::new(weight(face.weight().to_number()), style),
+		path: path.to_owned(),
+	})
+}
+
+/// The typographic family groups every weight under one name, where the
+/// older family name only goes up to bold. Prefer it, and English.
+fn family_name(face: &Face) -> Option<String> {
+	for id in [name_id::TYPOGRAPHIC_FAMILY, name_id::FAMILY] {
+		let names: Vec<_> = face
+			.names()
+			.into_iter()
+			.filter(|name| name.name_id == id && name.is_unicode())
+			.collect();
+
+		let english = names
+			.iter()
+			.find(|name| name.language() == Language::English_UnitedStates)
+			.and_then(|name| name.to_string());
+
+		if let Some(family) = english.or_else(|| names.iter().find_map(|name| name.to_string())) {
+			return Some(family);
+		}
+	}
+
+	None
+}
+
+/// The named weight closest to an OS/2 weight class.
+fn weight(class: u16) -> FontWeight {
+	match class {
+		0..=150 => FontWeight::Thin,
+		151..=250 => FontWeight::ExtraLight,
+		251..=350 => FontWeight::Light,
+		351..=450 => FontWeight::Regular,
+		451..=550 => FontWeight::Medium,
+		551..=650 => FontWeight::SemiBold,
+		651..=750 => FontWeight::Bold,
+		751..=850 => FontWeight::ExtraBold,
+		851..=925 => FontWeight::Black,
+		_ => FontWeight::ExtraBlack,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn weight_classes() {
+		assert_eq!(weight(100), FontWeight::Thin);
+		assert_eq!(weight(400), FontWeight::Regular);
+		assert_eq!(weight(430), FontWeight::Regular);
+		assert_eq!(weight(700), FontWeight::Bold);
+		assert_eq!(weight(950), FontWeight::ExtraBlack);
+	}
+
+	#[test]
+	fn font_extensions() {
+		assert!(is_font(Path::new("Brand Sans.OTF")));
+		assert!(is_font(Path::new("fonts/brand.ttf")));
+		assert!(is_font(Path::new("fonts/brand.woff2")));
+		assert!(!is_font(Path::new("fonts/brand.ttc")));
+		assert!(!is_font(Path::new("fonts/README")));
+	}
+
+	#[test]
+	fn identifies_fixture() {
+		let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("../textual/fixtures");
+		let path = fixtures.join("dejavu-serif-subset.ttf");
+		assert_eq!(
+			identify(&path),
+			Some(LocalFont {
+				family: "DejaVu Serif".to_owned(),
+				variant: FontVariant::new(FontWeight::Regular, FontStyle::Normal),
+				path: path.clone(),
+			})
+		);
+
+		let woff2 = fixtures.join("dejavu-serif-subset.woff2");
+		assert_eq!(identify(&woff2).unwrap().family, "DejaVu Serif");
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn survives_link_loops() {
+		let root = std::env::temp_dir().join(format!("textual-localfonts-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&root);
+		fs::create_dir_all(root.join("inner")).unwrap();
+		fs::write(root.join("inner/font.ttf"), b"").unwrap();
+		std::os::unix::fs::symlink(&root, root.join("inner/loop")).unwrap();
+
+		let mut files = vec![];
+		find_files(&root, &mut files, &mut HashSet::new()).unwrap();
+		let _ = fs::remove_dir_all(&root);
+
+		assert_eq!(files, vec![root.join("inner/font.ttf")]);
+	}
+}
@@ -0,0 +1,194 @@
+use std::{
+	borrow::Borrow,
+	collections::{BTreeMap, HashMap},
+	hash::Hash,
+};
+
+/// A least-recently-used cache bounded by the total size of its values rather
+/// than how many there are. Callers say how big a value is when inserting it.
+pub struct Lru<K, V> {
+	budget: usize,
+	used: usize,
+	tick: u64,
+	entries: HashMap<K, Entry<V>>,
+	/// Maps the tick an entry was last touched to its key. The first entry
+	/// is the least recently used.
+	recency: BTreeMap<u64, K>,
+}
+
+struct Entry<V> {
+	value: V,
+	size: usize,
+	tick: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> Lru<K, V> {
+	pub fn new(budget: usize) -> Self {
+		Self {
+			budget,
+			used: 0,
+			tick: 0,
+			entries: HashMap::new(),
+			recency: BTreeMap::new(),
+		}
+	}
+
+	/// Get a value, marking it as the most recently used.
+	pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+	where
+		K: Borrow<Q>,
+		Q: Eq + Hash + ?Sized,
+	{
+		self.tick += 1;
+		let tick = self.tick;
+
+		let entry = self.entries.get_mut(key)?;
+		let owned = self.recency.remove(&entry.tick)?;
+		self.recency.insert(tick, owned);
+		entry.tick = tick;
+
+		Some(&entry.value)
+	}
+
+	/// Insert a value of `size` bytes and evict the least recently used values
+	/// until we're under budget, returning what was evicted. Values bigger than
+	/// the whole budget are not stored at all.
+	pub fn insert(&mut self, key: K, value: V, size: usize) -> Vec<(K, V)> {
+		self.remove(&key);
+
+		if size > self.budget {
+			return vec![(key, value)];
+		}
+
+		self.tick += 1;
+		self.used += size;
+		self.recency.insert(self.tick, key.clone());
+		self.entries.insert(
+			key,
+			Entry {
+				value,
+				size,
+				tick: self.tick,
+			},
+		);
+
+		self.evict()
+	}
+
+	/// Get a value without marking it as used.
+	pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+	where
+		K: Borrow<Q>,
+		Q: Eq + Hash + ?Sized,
+	{
+		self.entries.get(key).map(|entry| &entry.value)
+	}
+
+	/// Every entry, least recently used first.
+	pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+		self.recency
+			.values()
+			.filter_map(move |key| self.entries.get(key).map(|entry| (key, &entry.value)))
+	}
+
+	pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+	where
+		K: Borrow<Q>,
+		Q: Eq + Hash + ?Sized,
+	{
+		let entry = self.entries.remove(key)?;
+		self.recency.remove(&entry.tick);
+		self.used -= entry.size;
+
+		Some(entry.value)
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	/// Change how many bytes we may keep, returning anything evicted to get
+	/// under the new budget.
+	pub fn set_budget(&mut self, budget: usize) -> Vec<(K, V)> {
+		self.budget = budget;
+		self.evict()
+	}
+
+	/// How many bytes the values in the cache are taking up.
+	pub fn used(&self) -> usize {
+		self.used
+	}
+
+	fn evict(&mut self) -> Vec<(K, V)> {
+		let mut evicted = vec![];
+
+		while self.used > self.budget {
+			let oldest = match self.recency.keys().next() {
+				Some(tick) => *tick,
+				None => break,
+			};
+
+			if let Some(key) = self.recency.remove(&oldest) {
+				if let Some(entry) = self.entries.remove(&key) {
+					self.used -= entry.size;
+					evicted.push((key, entry.value));
+				}
+			}
+		}
+
+		evicted
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn evicts_least_recent() {
+		let mut lru = Lru::new(10);
+		lru.insert("a", 1, 4);
+		lru.insert("b", 2, 4);
+
+		// Touch a so b is the oldest
+		assert_eq!(lru.get("a"), Some(&1));
+
+		assert_eq!(lru.insert("c", 3, 4), vec![("b", 2)]);
+		assert_eq!(lru.get("b"), None);
+		assert_eq!(lru.get("a"), Some(&1));
+		assert_eq!(lru.get("c"), Some(&3));
+		assert_eq!(lru.used(), 8);
+	}
+
+	#[test]
+	fn shrinking_budget_evicts() {
+		let mut lru = Lru::new(10);
+		lru.insert("a", 1, 4);
+		lru.insert("b", 2, 4);
+
+		assert_eq!(lru.set_budget(5), vec![("a", 1)]);
+		assert_eq!(lru.used(), 4);
+		assert!(lru.set_budget(20).is_empty());
+	}
+
+	#[test]
+	fn iterates_least_recent_first() {
+		let mut lru = Lru::new(10);
+		lru.insert("a", 1, 1);
+		lru.insert("b", 2, 1);
+		lru.get("a");
+
+		let order: Vec<&str> = lru.iter().map(|(key, _)| *key).collect();
+		assert_eq!(order, vec!["b", "a"]);
+		assert_eq!(lru.peek("b"), Some(&2));
+	}
+
+	#[test]
+	fn oversized_not_stored() {
+		let mut lru = Lru::new(10);
+		lru.insert("a", 1, 11);
+
+		assert_eq!(lru.len(), 0);
+		assert_eq!(lru.used(), 0);
+	}
+}
@@ -1,50 +1,116 @@
 extern crate image as crateimage;
 
+mod batch;
+mod cache;
 mod color;
 mod config;
+mod cors;
+mod fontcache;
 mod fontprovider;
 mod image;
-mod statistics;
+mod listener;
+mod localfonts;
+mod lru;
+mod metrics;
+mod raster;
+mod ratelimit;
+mod route;
+mod signing;
 mod text;
 
 use std::{
 	cell::Cell,
 	collections::HashMap,
-	convert::{Infallible, TryInto},
+	convert::TryInto,
+	fs,
 	future::Future,
-	net::{SocketAddr, TcpListener, TcpStream},
+	net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+	path::{Path, PathBuf},
 	pin::Pin,
 	str::FromStr,
+	sync::atomic::{AtomicU64, Ordering},
 	task::{Context, Poll},
-	time::Instant,
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use bempline::Document;
 use chrono::Utc;
 use crateimage::png::PngEncoder;
-use fontprovider::FontProvider;
-use hyper::{body::HttpBody, service::Service, Body, Request, Response, Server};
+use fontcache::FontCache;
+use fontprovider::{Catalog, FontError, FontFilter, FontProvider, FontVariant};
+use hyper::{
+	body::{Bytes, HttpBody},
+	header::{HeaderValue, ALLOW, CONTENT_LENGTH, CONTENT_TYPE, ORIGIN, RETRY_AFTER},
+	service::Service,
+	Body, Method, Request, Response, Server, StatusCode,
+};
 use mavourings::query::Query;
 use serde::Serialize;
 use std::sync::Arc;
-use text::{Operation, Text};
+use text::{Operation, Problem, RenderError};
 use thiserror::Error;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::RwLock;
+use tracing::{error, field, info, info_span, instrument, warn, Instrument, Span};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::EnvFilter;
 
-use crate::config::Config;
-use crate::statistics::Statistics;
+use crate::cache::{DiskCache, ResponseCache};
+use crate::color::Gamma;
+use crate::config::{Command, Config, LogFormat};
+use crate::cors::{Cors, ALLOWED_METHODS};
+use crate::listener::{Bind, Connection, Tls};
+use crate::metrics::{Gauges, Metrics};
+use crate::raster::Rasterizer;
+use crate::ratelimit::RateLimiter;
+use crate::route::{Legacy, Page, Route, RouteError, PREFIX};
+use crate::signing::{SignatureError, Signer};
 
 struct Textual {
-	config: Config,
-	statistics: RwLock<Statistics>,
+	/// Replaced when the config is reloaded
+	settings: std::sync::RwLock<Arc<Settings>>,
+	metrics: Metrics,
 	font_provider: RwLock<FontProvider>,
+	rasterizer: Rasterizer,
+	responses: ResponseCache,
+	render_limiter: RateLimiter,
+	font_limiter: RateLimiter,
+	/// Present if we terminate TLS ourselves
+	tls: Option<Arc<Tls>>,
+}
+
+impl Textual {
+	/// The settings as they are now. Hold onto these for the whole request so
+	/// a reload doesn't change things halfway through.
+	fn settings(&self) -> Arc<Settings> {
+		self.settings.read().unwrap().clone()
+	}
+}
+
+/// The config and everything we build from it that can change on a reload.
+struct Settings {
+	config: Config,
+	/// Present if renders must be signed
+	signer: Option<Signer>,
+	cors: Cors,
+}
+
+impl Settings {
+	fn new(config: Config) -> Self {
+		Self {
+			signer: config.signing_secret().map(Signer::new),
+			cors: Cors::new(config.cors_origins()),
+			config,
+		}
+	}
 }
 
 struct MakeSvc {
 	textual: Arc<Textual>,
 }
 
-impl<T> Service<T> for MakeSvc {
+impl Service<&Connection> for MakeSvc {
 	type Response = Svc;
 	type Error = &'static str;
 	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
@@ -53,15 +119,29 @@ impl<T> Service<T> for MakeSvc {
 		Poll::Ready(Ok(()))
 	}
 
-	fn call(&mut self, _: T) -> Self::Future {
+	fn call(&mut self, conn: &Connection) -> Self::Future {
 		let textual = self.textual.clone();
-		let fut = async move { Ok(Svc { textual }) };
+		let peer = Peer {
+			remote: conn.remote(),
+			secure: conn.secure(),
+		};
+		let fut = async move { Ok(Svc { textual, peer }) };
 		Box::pin(fut)
 	}
 }
 
 struct Svc {
 	textual: Arc<Textual>,
+	peer: Peer,
+}
+
+/// The connection a request came in on. Kept in the request's extensions.
+#[derive(Copy, Clone, Debug)]
+struct Peer {
+	/// Who's on the other end of the connection. Might be a proxy.
+	remote: IpAddr,
+	/// Whether we decrypted the request ourselves
+	secure: bool,
 }
 
 impl Service<Request<Body>> for Svc {
@@ -73,152 +153,456 @@ impl Service<Request<Body>> for Svc {
 		Poll::Ready(Ok(()))
 	}
 
-	fn call(&mut self, req: Request<Body>) -> Self::Future {
+	fn call(&mut self, mut req: Request<Body>) -> Self::Future {
 		let tex = self.textual.clone();
-		Box::pin(async { Ok(Self::task(req, tex).await) })
+		let remote = self.peer.remote;
+		req.extensions_mut().insert(self.peer);
+		Box::pin(async move { Ok(Self::task(req, tex, remote).await) })
 	}
 }
 
 impl Svc {
-	async fn task(req: Request<Body>, textual: Arc<Textual>) -> Response<Body> {
-		let response = match Self::serve(req, textual.clone()).await {
-			Ok(resp) => resp,
-			Err(e) => Response::builder()
-				.header("content-type", "text/plain")
-				.body(Body::from(e.to_string()))
-				.unwrap(),
+	async fn task(req: Request<Body>, textual: Arc<Textual>, remote: IpAddr) -> Response<Body> {
+		let settings = textual.settings();
+		let forwarded = req
+			.headers()
+			.get("X-Forwarded-For")
+			.and_then(|hv| hv.to_str().ok());
+		let client =
+			ratelimit::client_address(remote, forwarded, settings.config.trusted_proxies());
+		let agent = req
+			.headers()
+			.get("user-agent")
+			.and_then(|hv| hv.to_str().ok())
+			.unwrap_or("unknown");
+
+		let span = info_span!(
+			"request",
+			id = REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+			%client,
+			method = %req.method(),
+			path = req.uri().path(),
+			agent,
+			route = field::Empty,
+		);
+
+		Self::respond(req, textual, settings, client)
+			.instrument(span)
+			.await
+	}
+
+	/// `settings` are used for the whole request, see [Textual::settings].
+	async fn respond(
+		req: Request<Body>,
+		textual: Arc<Textual>,
+		settings: Arc<Settings>,
+		client: IpAddr,
+	) -> Response<Body> {
+		let start = Instant::now();
+		let origin = req.headers().get(ORIGIN).cloned();
+		let head = req.method() == Method::HEAD;
+
+		let (name, result) = match *req.method() {
+			Method::OPTIONS => ("preflight", Ok(settings.cors.preflight(&req))),
+			Method::GET | Method::HEAD | Method::POST => match Self::route(&req) {
+				Ok((route, query)) => (
+					route.name(),
+					Self::serve(req, textual.clone(), &settings, client, route, query).await,
+				),
+				Err(e) => ("unknown", Err(e)),
+			},
+			_ => ("unknown", Err(ServeError::MethodNotAllowed)),
 		};
+		Span::current().record("route", name);
 
-		match response.headers().get("content-type").map(|hv| hv.to_str()) {
-			Some(Ok(mime)) => {
-				let mut stats = textual.statistics.write().await;
-				//TODO: gen- check exact or log upper instead?
-				stats.add(mime, response.body().size_hint().lower() as usize);
-			}
-			_ => {
-				//TODO: Maybe print here that the content-type was unset or could not be parsed as a string
+		let mut response = match result {
+			Ok(resp) => resp,
+			Err(e) => {
+				if e.status().is_server_error() {
+					error!(error = %e, "error serving request");
+				}
+
+				e.response()
 			}
+		};
+
+		settings.cors.apply(origin.as_ref(), &mut response);
+
+		if head {
+			response = head_response(response).await;
 		}
 
+		let mime = response
+			.headers()
+			.get(CONTENT_TYPE)
+			.and_then(|hv| hv.to_str().ok());
+		textual.metrics.request(
+			name,
+			response.status().as_u16(),
+			mime,
+			response.body().size_hint().lower() as usize,
+		);
+
+		info!(
+			status = response.status().as_u16(),
+			elapsed_ms = start.elapsed().as_millis() as u64,
+			"served"
+		);
+
 		response
 	}
 
+	/// Work out what a request is for. The query is returned too because the
+	/// legacy routes need it to decide.
+	fn route(req: &Request<Body>) -> Result<(Route, Option<Query>), ServeError> {
+		let query = match req.uri().query() {
+			None => None,
+			Some("") => None,
+			Some(s) => Some(parse_query(s)?),
+		};
+
+		let legacy = match query.as_ref() {
+			None => Legacy::default(),
+			Some(query) => Legacy {
+				query: true,
+				forceraw: query.has_bool("forceraw"),
+				info: query.has_bool("info"),
+				me: query.has_bool("me"),
+			},
+		};
+
+		// HEAD is a GET without the body, which we take off later
+		let method = match *req.method() {
+			Method::HEAD => Method::GET,
+			ref method => method.clone(),
+		};
+
+		let route = Route::resolve(&method, req.uri().path(), &legacy)?;
+		Ok((route, query))
+	}
+
 	async fn serve(
 		req: Request<Body>,
 		textual: Arc<Textual>,
-	) -> Result<Response<Body>, Infallible> {
-		//TODO: gen- Look at the path properly
-		if req.uri().path().contains("ui") {
-			return Ok(mavourings::file_string_reply("ui.html").await.unwrap());
+		settings: &Settings,
+		client: IpAddr,
+		route: Route,
+		query: Option<Query>,
+	) -> Result<Response<Body>, ServeError> {
+		match route {
+			Route::Guide => Self::serve_tool().await,
+			Route::Ui => Self::serve_file("ui.html").await,
+			Route::Healthz => Ok(plain_response(StatusCode::OK, "ok")),
+			Route::Readyz => Self::serve_ready(&textual).await,
+			Route::Fonts => Self::serve_fonts(&textual, query).await,
+			Route::Family(family) => Self::serve_family(&textual, &family).await,
+			Route::FontPreview(family) => {
+				Self::serve_font_preview(textual, settings, client, family, query).await
+			}
+			Route::Metrics => Self::serve_metrics(&textual).await,
+			Route::Document | Route::Canonical => {
+				Self::serve_document(req, textual, settings, client, route, query).await
+			}
+			Route::Image(page) | Route::Card(page) => {
+				Self::serve_page(req, textual, settings, client, route, page, query).await
+			}
 		}
+	}
+
+	/// Draw an image, or the card that embeds it.
+	async fn serve_page(
+		req: Request<Body>,
+		textual: Arc<Textual>,
+		settings: &Settings,
+		client: IpAddr,
+		route: Route,
+		page: Page,
+		query: Option<Query>,
+	) -> Result<Response<Body>, ServeError> {
+		let agent = req
+			.headers()
+			.get("user-agent")
+			.and_then(|hv| hv.to_str().ok())
+			.unwrap_or("unknown");
+
+		if query.is_none() && page == Page::Render {
+			return Err(ServeError::BadInput(
+				"There's nothing to draw. Add some parameters, or see the guide".into(),
+			));
+		}
+
+		let requested = PageQuery::new(query);
 
-		let mut query_str = match req.uri().query() {
-			None => return Ok(Self::serve_tool().await),
-			Some(s) if s.is_empty() => return Ok(Self::serve_tool().await),
-			Some(s) => s.to_owned(),
+		if let Some(signer) = settings.signer.as_ref() {
+			signer.verify(
+				&signed_query(&requested.op, page, requested.font.as_deref()),
+				requested.expires.as_deref(),
+				requested.signature.as_deref(),
+				unix_now(),
+			)?;
+		}
+
+		if requested.strict && !requested.problems.is_empty() {
+			return problem_response(&textual, settings, &req, client, &requested.problems).await;
+		}
+
+		let PageQuery {
+			op,
+			problems,
+			font,
+			expires,
+			..
+		} = requested;
+
+		let op = match page {
+			Page::Render => op,
+			Page::Info => page_operation(info_text(&textual).await, font, &op),
+			Page::Me => page_operation(me_text(&req, client, agent), font, &op),
 		};
-		let query: Query = query_str.parse().unwrap();
 
-		if query.has_bool("info") && !query.has_bool("forceraw") {
-			let stats = textual.statistics.read().await;
-			let provider = textual.font_provider.read().await;
+		let caching = match page {
+			Page::Render => Caching::Shared,
+			Page::Info | Page::Me => Caching::NoStore,
+		};
 
-			let text = format!(
-				"{}\n\nimage sent: {}\nhtml sent: {}\ntotal requests: {}\n\nfonts in cache: {}",
-				Utc::now().format("%H:%M UTC\n%a %B %-d %Y"),
-				bytes_to_human(stats.image()),
-				bytes_to_human(stats.html()),
-				stats.requests(),
-				provider.cached()
-			);
+		let mut ret = if let Route::Card(_) = route {
+			// Only matters if we're signing, and then it was checked above
+			let expires = expires.and_then(|exp| exp.parse().ok());
+			make_meta(settings, &req, op, page, expires).await
+		} else {
+			let etag = textual.responses.key(&op.canonical_query(), "image/png");
 
-			let font = match query.get_first_value("font") {
-				Some(font) => format!("font={}&", font),
-				None => String::new(),
-			};
+			if etag_matches(&req, &etag) {
+				return not_modified(settings, &etag, caching);
+			}
 
-			query_str = format!(
-				"{}fs=32&c=black&bc=eed&lh=font&text={}",
-				font,
-				Query::url_encode(&text)
-			);
+			make_image(textual, settings, op, etag, client, caching).await
+		};
+
+		// Lenient requests still tell you what was wrong
+		if let (Ok(resp), false) = (ret.as_mut(), problems.is_empty()) {
+			let warnings = problems
+				.iter()
+				.map(|p| header_safe(p.to_string()))
+				.collect::<Vec<String>>()
+				.join("; ");
+
+			if let Ok(value) = HeaderValue::from_str(&warnings) {
+				resp.headers_mut().insert("x-textual-warnings", value);
+			}
 		}
 
-		let agent = req.headers().get("user-agent").unwrap().to_str().unwrap();
+		ret
+	}
 
-		let clientaddr = req
-			.headers()
-			.get("X-Forwarded-For")
-			.map(|h| h.to_str().unwrap_or("unknown"))
-			.unwrap_or("unknown");
+	/// Operations can be POSTed as JSON instead of written in the query. The
+	/// body is an [Operation] as described by its serde implementation.
+	///
+	/// [Route::Document] responds with the image, [Route::Canonical] with the
+	/// query string that renders the same image, for sharing links.
+	async fn serve_document(
+		req: Request<Body>,
+		textual: Arc<Textual>,
+		settings: &Settings,
+		client: IpAddr,
+		route: Route,
+		query: Option<Query>,
+	) -> Result<Response<Body>, ServeError> {
+		let too_large = || {
+			ServeError::TooLarge(format!(
+				"Documents can be at most {}",
+				bytes_to_human(MAX_DOCUMENT_SIZE)
+			))
+		};
 
-		println!(
-			"connection: {}\n\tua: {}\n\tpath: {}",
-			clientaddr,
-			agent,
-			req.uri().path_and_query().unwrap().to_string()
-		);
+		if req.body().size_hint().lower() > MAX_DOCUMENT_SIZE as u64 {
+			return Err(too_large());
+		}
 
-		if query.has_bool("me") && !query.has_bool("forceraw") && !query.has_bool("info") {
-			let referrer = match req.headers().get(hyper::header::REFERER) {
-				None => "unknown",
-				Some(hv) => match hv.to_str() {
-					Ok(hstring) => hstring,
-					Err(e) => "unknown",
-				},
-			};
+		// A chunked body doesn't say how big it is, so stop reading as soon as
+		// it's bigger than we'd take
+		let mut incoming = req.into_body();
+		let mut body = Vec::new();
+		while let Some(chunk) = incoming.data().await {
+			let chunk = chunk.map_err(|e| ServeError::BadInput(e.to_string()))?;
 
-			let text = format!(
-				"IP: {}\n\nUser Agent\n{}\n\nReferrer\n{}",
-				clientaddr, agent, referrer
-			);
+			if body.len() + chunk.len() > MAX_DOCUMENT_SIZE {
+				return Err(too_large());
+			}
+			body.extend_from_slice(&chunk);
+		}
+
+		let op: Operation =
+			serde_json::from_slice(&body).map_err(|e| ServeError::BadInput(e.to_string()))?;
+
+		let canonical = op.canonical_query();
+		if route == Route::Canonical {
+			return Ok(plain_response(StatusCode::OK, canonical));
+		}
 
-			let font = match query.get_first_value("font") {
-				Some(font) => format!("font={}&", font),
-				None => String::new(),
+		if let Some(signer) = settings.signer.as_ref() {
+			let (signature, expires) = match query.as_ref() {
+				Some(query) => (query_value(query, "sig"), query_value(query, "exp")),
+				None => (None, None),
 			};
 
-			query_str = format!(
-				"{}fs=32&c=black&bc=eed&lh=font&text={}",
-				font,
-				Query::url_encode(&text)
-			);
+			signer.verify(
+				&canonical,
+				expires.as_deref(),
+				signature.as_deref(),
+				unix_now(),
+			)?;
 		}
 
-		let text: Operation = query.into();
+		let etag = textual.responses.key(&canonical, "image/png");
+		make_image(textual, settings, op, etag, client, Caching::Shared).await
+	}
 
-		// Find the hostname we should use for the image link in the opengraph tags
-		let host = textual
-			.config
-			.meta_host()
-			.or(req
-				.headers()
-				.get("host")
-				.map(|hv| hv.to_str().ok())
-				.flatten())
-			.unwrap_or("localhost");
+	/// The font families we know of, a page at a time. They can be filtered by
+	/// name, category, subset, and the weights and styles they come in.
+	async fn serve_fonts(
+		textual: &Textual,
+		query: Option<Query>,
+	) -> Result<Response<Body>, ServeError> {
+		let value = |key: &str| query.as_ref().and_then(|query| query_value(query, key));
+		let number = |key: &str, default: usize| match value(key) {
+			None => Ok(default),
+			Some(string) => string
+				.parse()
+				.ok()
+				.filter(|n: &usize| *n > 0)
+				.ok_or_else(|| ServeError::BadInput(format!("Invalid {}: '{}'", key, string))),
+		};
 
-		let scheme = textual
-			.config
-			.scheme()
-			.or(req.uri().scheme_str())
-			.unwrap_or(if host == "localhost" { "http" } else { "https" });
+		let filter = FontFilter {
+			search: value("search"),
+			category: value("category"),
+			subset: value("subset"),
+			weight: value("weight")
+				.map(|weight| weight.parse())
+				.transpose()
+				.map_err(|e| ServeError::BadInput(format!("{}", e)))?,
+			style: value("style")
+				.map(|style| style.parse())
+				.transpose()
+				.map_err(|e| ServeError::BadInput(format!("{}", e)))?,
+		};
+		let page = number("page", 1)?;
+		let per_page = number("per_page", FONTS_PER_PAGE)?.min(MAX_FONTS_PER_PAGE);
 
-		let start = Instant::now();
-		let ret = if text.forceraw {
-			// Image
-			Ok(make_image(textual, text).await?)
+		let json = {
+			let provider = textual.font_provider.read().await;
+			let found = provider.search(&filter);
+			let total = found.len();
+			let fonts: Vec<_> = found
+				.into_iter()
+				.skip((page - 1).saturating_mul(per_page))
+				.take(per_page)
+				.collect();
+
+			serde_json::to_string(&serde_json::json!({
+				"total": total,
+				"page": page,
+				"per_page": per_page,
+				"fonts": fonts,
+			}))
+			.map_err(|e| ServeError::Internal(e.to_string()))?
+		};
+
+		Ok(json_response(json)?)
+	}
+
+	/// Everything we know about a font family.
+	async fn serve_family(textual: &Textual, family: &str) -> Result<Response<Body>, ServeError> {
+		let json = {
+			let provider = textual.font_provider.read().await;
+			let info = provider.describe(family).ok_or_else(|| {
+				ServeError::UnknownFont(format!("The font '{}' does not exist", family))
+			})?;
+
+			serde_json::to_string(&info).map_err(|e| ServeError::Internal(e.to_string()))?
+		};
+
+		Ok(json_response(json)?)
+	}
+
+	/// Draw a sample of a font family. It's always the same text so previews
+	/// don't need a signature even when renders do.
+	async fn serve_font_preview(
+		textual: Arc<Textual>,
+		settings: &Settings,
+		client: IpAddr,
+		family: String,
+		query: Option<Query>,
+	) -> Result<Response<Body>, ServeError> {
+		let value = |key: &str| query.as_ref().and_then(|query| query_value(query, key));
+		let weight = match value("weight") {
+			Some(weight) => weight
+				.parse()
+				.map_err(|e| ServeError::BadInput(format!("{}", e)))?,
+			None => Default::default(),
+		};
+		let style = match value("style") {
+			Some(style) => style
+				.parse()
+				.map_err(|e| ServeError::BadInput(format!("{}", e)))?,
+			None => Default::default(),
+		};
+
+		// Only known families, so a preview can't be used to look for fonts
+		if textual
+			.font_provider
+			.read()
+			.await
+			.describe(&family)
+			.is_none()
+		{
+			return Err(ServeError::UnknownFont(format!(
+				"The font '{}' does not exist",
+				family
+			)));
+		}
+
+		let op = Operation::specimen(family, FontVariant::new(weight, style));
+		let etag = textual.responses.key(&op.canonical_query(), "image/png");
+		make_image(textual, settings, op, etag, client, Caching::Shared).await
+	}
+
+	async fn serve_ready(textual: &Textual) -> Result<Response<Body>, ServeError> {
+		if textual.font_provider.read().await.ready() {
+			Ok(plain_response(StatusCode::OK, "ready"))
 		} else {
-			let link = format!("{}://{}?{}&forceraw", scheme, host, query_str);
-			Ok(make_meta(textual, text, link).await?)
+			Err(ServeError::Unavailable(
+				"Still getting the list of fonts".into(),
+			))
+		}
+	}
+
+	/// Everything in [Metrics], for Prometheus to scrape.
+	async fn serve_metrics(textual: &Textual) -> Result<Response<Body>, ServeError> {
+		let (glyphs_cached, glyph_bytes) = textual.rasterizer.cached();
+		let gauges = Gauges {
+			fonts_cached: textual.font_provider.read().await.cached(),
+			glyphs_cached,
+			glyph_bytes,
 		};
-		let elapsed = start.elapsed();
 
-		ret
+		let body = textual.metrics.render(&gauges);
+
+		Ok(Response::builder()
+			.header("content-type", "text/plain; version=0.0.4")
+			.header("content-length", body.len())
+			.body(Body::from(body))?)
+	}
+
+	async fn serve_tool() -> Result<Response<Body>, ServeError> {
+		Self::serve_file("guide.html").await
 	}
 
-	async fn serve_tool() -> Response<Body> {
-		mavourings::file_string_reply("guide.html").await.unwrap()
+	async fn serve_file(path: &str) -> Result<Response<Body>, ServeError> {
+		mavourings::file_string_reply(path)
+			.await
+			.map_err(|_| ServeError::Internal(format!("Failed to read {}", path)))
 	}
 }
 
@@ -233,21 +617,890 @@ async fn main() {
 		}
 	};
 
-	let provider = FontProvider::new(config.font_cache_path(), include_str!("webfont.key"));
+	if let Some(Command::PrintConfig) = config.command() {
+		print!("{}", config.print());
+		return;
+	}
+
+	if let Some(Command::Sign { query, expires }) = config.command() {
+		let signer = match config.signing_secret() {
+			Some(secret) => Signer::new(secret),
+			None => {
+				println!("SigningSecret is not set in the config file");
+				std::process::exit(1);
+			}
+		};
+
+		let page = match parse_query(query) {
+			Ok(parsed) => Legacy {
+				query: true,
+				forceraw: parsed.has_bool("forceraw"),
+				info: parsed.has_bool("info"),
+				me: parsed.has_bool("me"),
+			}
+			.page(),
+			Err(e) => {
+				println!("{}", e);
+				std::process::exit(1);
+			}
+		};
+
+		let expires = expires.map(|secs| unix_now() + secs);
+		match sign_query(&signer, query, page, expires) {
+			Ok(signed) => println!("?{}", signed),
+			Err(e) => {
+				println!("{}", e);
+				std::process::exit(1);
+			}
+		}
+
+		return;
+	}
+
+	if let Some(Command::Render { inputs, out, jobs }) = config.command() {
+		let jobs = jobs.unwrap_or_else(|| {
+			std::thread::available_parallelism()
+				.map(|n| n.get())
+				.unwrap_or(1)
+		});
+
+		let failed = batch::render(&config, inputs, out, jobs).await;
+		if failed > 0 {
+			eprintln!("{} failed", failed);
+			std::process::exit(1);
+		}
+
+		return;
+	}
+
+	if let Some(Command::FontsSync) = config.command() {
+		if let Err(e) = sync_fonts(&config).await {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+
+		return;
+	}
+
+	if let Some(Command::CacheVerify) | Some(Command::CachePrune) = config.command() {
+		if let Err(e) = tend_cache(&config) {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+
+		return;
+	}
+
+	let log_filter = init_tracing(&config);
+
+	let mut provider = match FontProvider::new(
+		config.font_cache_path(),
+		config.font_cache_size(),
+		config.font_memory_cache(),
+	) {
+		Ok(provider) => provider,
+		Err(e) => {
+			error!(error = %e, "failed to load fonts");
+			std::process::exit(1);
+		}
+	};
+	provider.set_local(localfonts::scan(
+		config.font_directories(),
+		config.system_fonts(),
+	));
+
+	let rasterizer = Rasterizer::new(
+		Gamma::new(config.gamma(), config.contrast()),
+		config.hint_below(),
+		config.glyph_cache(),
+	);
+
+	let disk_cache = match config.response_disk_cache() {
+		None => None,
+		Some(path) => match DiskCache::new(path, config.response_disk_cache_size()) {
+			Ok(disk) => Some(disk),
+			Err(e) => {
+				error!(error = %e, "failed to open the response disk cache");
+				std::process::exit(1);
+			}
+		},
+	};
+
+	// Anything that changes how an operation renders goes in the salt so that
+	// changing it doesn't serve stale images
+	let salt = format!(
+		"{} {} {} {}",
+		env!("CARGO_PKG_VERSION"),
+		config.gamma(),
+		config.contrast(),
+		config.hint_below()
+	);
+	let responses = ResponseCache::new(salt, config.response_cache(), disk_cache);
+
+	let render_limiter = RateLimiter::new(config.render_rate(), config.render_burst());
+	let font_limiter = RateLimiter::new(config.font_rate(), config.font_burst());
+
+	let metrics = match config.metrics_file() {
+		None => Metrics::default(),
+		Some(path) => Metrics::load(path).unwrap_or_else(|e| {
+			warn!(path = %path.display(), error = %e, "failed to load metrics");
+			Metrics::default()
+		}),
+	};
+
+	let bind = match config.unix_socket() {
+		Some(path) => Bind::Unix(path.to_owned()),
+		None => Bind::Tcp(SocketAddr::new(config.listen(), config.port())),
+	};
+
+	let tls = match config.tls() {
+		None => None,
+		Some(files) => match Tls::new(&files.certificate, &files.key) {
+			Ok(tls) => Some(Arc::new(tls)),
+			Err(e) => {
+				error!(error = %e, "failed to load the TLS certificate");
+				std::process::exit(1);
+			}
+		},
+	};
+
+	let metrics_file = config.metrics_file().map(|path| path.to_owned());
 
-	let address = SocketAddr::new(config.listen(), config.port());
 	let textual = Textual {
-		config,
+		settings: std::sync::RwLock::new(Arc::new(Settings::new(config))),
+		rasterizer,
+		responses,
+		render_limiter,
+		font_limiter,
 		font_provider: RwLock::new(provider),
-		statistics: RwLock::new(Statistics::default()),
+		metrics,
+		tls: tls.clone(),
 	};
+	let textual = Arc::new(textual);
 
-	Server::bind(&address)
-		.serve(MakeSvc {
-			textual: Arc::new(textual),
-		})
+	if let Some(path) = metrics_file.clone() {
+		tokio::spawn(save_metrics(textual.clone(), path));
+	}
+
+	tokio::spawn(load_catalog(textual.clone()));
+	tokio::spawn(watch_config(textual.clone(), log_filter));
+
+	let incoming = match listener::listen(&bind, tls).await {
+		Ok(incoming) => incoming,
+		Err(e) => {
+			error!(address = %bind, error = %e, "failed to bind");
+			std::process::exit(1);
+		}
+	};
+
+	// HTTP/2 is negotiated with ALPN over TLS and prior knowledge otherwise
+	let server = Server::builder(incoming).serve(MakeSvc {
+		textual: textual.clone(),
+	});
+
+	info!(address = %bind, tls = textual.tls.is_some(), "listening");
+	if let Err(e) = server.with_graceful_shutdown(shutdown_signal()).await {
+		error!(error = %e, "server error");
+	}
+
+	if let Some(path) = metrics_file {
+		if let Err(e) = textual.metrics.save(&path) {
+			warn!(path = %path.display(), error = %e, "failed to save metrics");
+		}
+	}
+
+	if let Bind::Unix(path) = &bind {
+		let _ = fs::remove_file(path);
+	}
+
+	info!("stopped");
+}
+
+/// The longest we wait between attempts to get the font catalog.
+const CATALOG_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How often to look whether a reload turned catalog refreshes on.
+const CATALOG_REFRESH_CHECK: Duration = Duration::from_secs(60);
+
+/// Save the font catalog for `textual fonts sync`.
+async fn sync_fonts(config: &Config) -> Result<(), String> {
+	let key = config
+		.google_fonts_key()
+		.ok_or("GoogleFontsKey isn't set")?
+		.to_owned();
+	let path = config.font_catalog().ok_or("FontCatalog isn't set")?;
+
+	let snapshot = tokio::task::spawn_blocking(move || Catalog::download(&key))
 		.await
-		.unwrap();
+		.map_err(|e| e.to_string())?
+		.map_err(|e| e.to_string())?;
+	let families = Catalog::parse(&snapshot).map_err(|e| e.to_string())?.len();
+
+	Catalog::save(&snapshot, path)
+		.map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+	println!("Saved {} families to {}", families, path.display());
+	Ok(())
+}
+
+/// Check the fonts in the cache are what we saved, or clear out what isn't
+/// ours.
+fn tend_cache(config: &Config) -> Result<(), String> {
+	let cache = FontCache::open(
+		config.font_cache_path(),
+		config.font_cache_size(),
+		config.font_memory_cache(),
+	)
+	.map_err(|e| format!("Failed to open the font cache: {}", e))?;
+
+	if let Some(Command::CachePrune) = config.command() {
+		let report = cache
+			.prune()
+			.map_err(|e| format!("Failed to prune the font cache: {}", e))?;
+
+		println!(
+			"Removed {} of {} files, freeing {}",
+			report.removed,
+			report.checked,
+			bytes_to_human(report.freed)
+		);
+	} else {
+		let report = cache.verify();
+
+		println!(
+			"Checked {} fonts and removed {} broken ones, freeing {}",
+			report.checked,
+			report.removed,
+			bytes_to_human(report.freed)
+		);
+	}
+
+	Ok(())
+}
+
+/// Get the font catalog. A snapshot on disk is used if there is one, otherwise
+/// we ask the API until it works. Until then we serve cached fonts and report
+/// not ready.
+async fn load_catalog(textual: Arc<Textual>) {
+	let settings = textual.settings();
+	let key = settings.config.google_fonts_key().map(|key| key.to_owned());
+	let snapshot = settings.config.font_catalog().map(|path| path.to_owned());
+
+	let loaded = match snapshot.as_ref() {
+		None => false,
+		Some(path) => match Catalog::load(path) {
+			Ok(catalog) => {
+				info!(path = %path.display(), families = catalog.len(), "loaded the font catalog");
+				textual.font_provider.write().await.set_catalog(catalog);
+				true
+			}
+			Err(e) => {
+				warn!(path = %path.display(), error = %e, "failed to load the font catalog");
+				false
+			}
+		},
+	};
+
+	let key = match key {
+		Some(key) => key,
+		None if loaded => {
+			info!("ready");
+			return;
+		}
+		None => {
+			warn!("GoogleFontsKey isn't set, only cached fonts can be used");
+			textual
+				.font_provider
+				.write()
+				.await
+				.set_catalog(Catalog::default());
+			return;
+		}
+	};
+
+	if !loaded {
+		fetch_catalog(&textual, &key, snapshot.as_deref()).await;
+	}
+	info!("ready");
+
+	// Read every time so a reload can change how often, or turn it on or off
+	loop {
+		match textual.settings().config.font_catalog_refresh() {
+			Some(every) => {
+				tokio::time::sleep(every).await;
+				fetch_catalog(&textual, &key, snapshot.as_deref()).await;
+				info!("refreshed the font catalog");
+			}
+			None => tokio::time::sleep(CATALOG_REFRESH_CHECK).await,
+		}
+	}
+}
+
+/// Ask the API for the catalog until it works and save a snapshot of it if we
+/// have somewhere to.
+async fn fetch_catalog(textual: &Textual, key: &str, snapshot: Option<&Path>) {
+	let mut backoff = Duration::from_secs(1);
+
+	loop {
+		let key = key.to_owned();
+		let downloaded = tokio::task::spawn_blocking(move || Catalog::download(&key))
+			.await
+			.map_err(|e| FontError::List(e.to_string()))
+			.and_then(|downloaded| downloaded)
+			.and_then(|json| Ok((Catalog::parse(&json)?, json)));
+
+		match downloaded {
+			Ok((catalog, json)) => {
+				if let Some(path) = snapshot {
+					if let Err(e) = Catalog::save(&json, path) {
+						warn!(path = %path.display(), error = %e, "failed to save the font catalog");
+					}
+				}
+
+				textual.font_provider.write().await.set_catalog(catalog);
+				return;
+			}
+			Err(e) => {
+				warn!(error = %e, retry_secs = backoff.as_secs(), "failed to get the font catalog");
+			}
+		}
+
+		tokio::time::sleep(backoff).await;
+		backoff = (backoff * 2).min(CATALOG_MAX_BACKOFF);
+	}
+}
+
+/// Resolves on SIGINT or SIGTERM. The server stops accepting connections and
+/// finishes the requests it has.
+async fn shutdown_signal() {
+	let interrupt = async {
+		if let Err(e) = tokio::signal::ctrl_c().await {
+			error!(error = %e, "failed to listen for SIGINT");
+			std::future::pending::<()>().await;
+		}
+	};
+
+	let terminate = async {
+		match signal(SignalKind::terminate()) {
+			Ok(mut terminate) => {
+				terminate.recv().await;
+			}
+			Err(e) => {
+				error!(error = %e, "failed to listen for SIGTERM");
+				std::future::pending::<()>().await;
+			}
+		}
+	};
+
+	tokio::select! {
+		_ = interrupt => (),
+		_ = terminate => (),
+	}
+
+	info!("shutting down, finishing open requests");
+}
+
+/// Changes which logs are written.
+type LogFilter = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// Log to stdout at the configured level. Spans log when they close so we
+/// get how long each part of a request took.
+fn init_tracing(config: &Config) -> LogFilter {
+	let builder = tracing_subscriber::fmt()
+		.with_env_filter(EnvFilter::new(config.log_level()))
+		.with_span_events(FmtSpan::CLOSE);
+
+	// The handle's type depends on the format, so hide it behind a closure
+	fn set_filter<S>(handle: Handle<EnvFilter, S>) -> LogFilter
+	where
+		S: Send + Sync + 'static,
+	{
+		Box::new(move |level| {
+			let filter = EnvFilter::try_new(level).map_err(|e| e.to_string())?;
+			handle.reload(filter).map_err(|e| e.to_string())
+		})
+	}
+
+	match config.log_format() {
+		LogFormat::Text => {
+			let builder = builder.with_filter_reloading();
+			let handle = builder.reload_handle();
+			builder.init();
+			set_filter(handle)
+		}
+		LogFormat::Json => {
+			let builder = builder.json().with_filter_reloading();
+			let handle = builder.reload_handle();
+			builder.init();
+			set_filter(handle)
+		}
+	}
+}
+
+/// How often we look at the config file to see if it changed.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reload the config on SIGHUP or when the file changes. The TLS certificate
+/// and key are reloaded the same way.
+async fn watch_config(textual: Arc<Textual>, log_filter: LogFilter) {
+	let mut hangup = match signal(SignalKind::hangup()) {
+		Ok(hangup) => Some(hangup),
+		Err(e) => {
+			warn!(error = %e, "failed to listen for SIGHUP");
+			None
+		}
+	};
+
+	let location = textual.settings().config.location().to_owned();
+	let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+	let mut last_modified = modified(&location);
+
+	let tls_modified = || {
+		textual
+			.tls
+			.as_ref()
+			.map(|tls| tls.files().map(modified))
+			.unwrap_or_default()
+	};
+	let mut last_tls_modified = tls_modified();
+
+	let mut poll = tokio::time::interval(CONFIG_POLL_INTERVAL);
+	loop {
+		let hungup = async {
+			match hangup.as_mut() {
+				Some(hangup) => hangup.recv().await,
+				None => std::future::pending().await,
+			}
+		};
+
+		tokio::select! {
+			_ = hungup => {
+				info!("reloading the config, got SIGHUP");
+			}
+			_ = poll.tick() => {
+				let now = tls_modified();
+				if now != last_tls_modified {
+					last_tls_modified = now;
+					info!("reloading the TLS certificate, the files changed");
+					reload_tls(&textual);
+				}
+
+				let now = modified(&location);
+				if now == last_modified {
+					continue;
+				}
+
+				info!(path = %location.display(), "reloading the config, the file changed");
+				last_modified = now;
+				reload_config(&textual, &log_filter);
+				continue;
+			}
+		}
+
+		last_modified = modified(&location);
+		last_tls_modified = tls_modified();
+		reload_config(&textual, &log_filter);
+		reload_tls(&textual);
+	}
+}
+
+/// Read the config again and use it if it's valid. Settings that need a
+/// restart are reported but otherwise left alone.
+fn reload_config(textual: &Textual, log_filter: &LogFilter) {
+	let config = match Config::get() {
+		Ok(Some(config)) => config,
+		Ok(None) => return,
+		Err(e) => {
+			error!(error = %e, "the new config is invalid, keeping the old one");
+			return;
+		}
+	};
+
+	let old = textual.settings();
+	let changes = old.config.changes(&config);
+	if changes.is_empty() {
+		info!("the config didn't change");
+		return;
+	}
+
+	for change in &changes {
+		if change.needs_restart() {
+			warn!(
+				key = change.key,
+				old = %change.old,
+				new = %change.new,
+				"changed, but only takes effect after a restart"
+			);
+		} else {
+			info!(key = change.key, old = %change.old, new = %change.new, "changed");
+		}
+	}
+
+	if let Err(e) = log_filter(config.log_level()) {
+		warn!(error = %e, "failed to change the log level");
+	}
+
+	textual.rasterizer.set_cache_budget(config.glyph_cache());
+	textual.responses.set_memory_budget(config.response_cache());
+	textual
+		.render_limiter
+		.set_rate(config.render_rate(), config.render_burst());
+	textual
+		.font_limiter
+		.set_rate(config.font_rate(), config.font_burst());
+
+	*textual.settings.write().unwrap() = Arc::new(Settings::new(config));
+}
+
+/// Read the TLS certificate and key again. New connections use them, the
+/// ones we have keep what they started with.
+fn reload_tls(textual: &Textual) {
+	if let Some(tls) = textual.tls.as_ref() {
+		match tls.reload() {
+			Ok(()) => info!("reloaded the TLS certificate"),
+			Err(e) => {
+				error!(error = %e, "failed to reload the TLS certificate, keeping the old one")
+			}
+		}
+	}
+}
+
+/// How often metrics are written to the metrics file.
+const METRICS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+async fn save_metrics(textual: Arc<Textual>, path: PathBuf) {
+	let mut interval = tokio::time::interval(METRICS_SAVE_INTERVAL);
+	loop {
+		interval.tick().await;
+
+		if let Err(e) = textual.metrics.save(&path) {
+			warn!(path = %path.display(), error = %e, "failed to save metrics");
+		}
+	}
+}
+
+fn parse_query(query_str: &str) -> Result<Query, ServeError> {
+	query_str
+		.parse()
+		.map_err(|_| ServeError::BadInput("Could not parse the query string".into()))
+}
+
+fn query_value(query: &Query, key: &str) -> Option<String> {
+	query.get_first_value(key).map(|v| v.to_string())
+}
+
+/// What a page was asked to draw. The query is consumed when it's parsed into
+/// an operation, so this keeps everything else we need from it.
+struct PageQuery {
+	op: Operation,
+	problems: Vec<Problem>,
+	strict: bool,
+	/// The font the info and me pages should be drawn in
+	font: Option<String>,
+	signature: Option<String>,
+	expires: Option<String>,
+}
+
+impl PageQuery {
+	fn new(query: Option<Query>) -> Self {
+		let query = match query {
+			Some(query) => query,
+			None => {
+				return Self {
+					op: Operation::default(),
+					problems: vec![],
+					strict: false,
+					font: None,
+					signature: None,
+					expires: None,
+				}
+			}
+		};
+
+		let font = query_value(&query, "font");
+		let signature = query_value(&query, "sig");
+		let expires = query_value(&query, "exp");
+		let strict = query.has_bool("strict");
+		let (op, problems) = Operation::parse(query);
+
+		Self {
+			op,
+			problems,
+			strict,
+			font,
+			signature,
+			expires,
+		}
+	}
+}
+
+/// The info and me pages are a message drawn in the requested font, at the
+/// requested aspect and density.
+fn page_operation(text: String, font: Option<String>, requested: &Operation) -> Operation {
+	let mut op = Operation::message(text);
+	for text in op.texts.iter_mut() {
+		text.font = font.clone();
+	}
+
+	op.aspect = requested.aspect;
+	op.dpr = requested.dpr;
+	op
+}
+
+async fn info_text(textual: &Textual) -> String {
+	let metrics = &textual.metrics;
+	let provider = textual.font_provider.read().await;
+
+	let (glyphs_cached, glyph_bytes) = textual.rasterizer.cached();
+
+	format!(
+		"{}\n\nimage sent: {}\nhtml sent: {}\ntotal requests: {}\n\nfonts in cache: {}\nglyphs in cache: {} ({})\nglyph hits: {}\nglyph misses: {}",
+		Utc::now().format("%H:%M UTC\n%a %B %-d %Y"),
+		bytes_to_human(metrics.sent("image") as usize),
+		bytes_to_human(metrics.sent("text/html") as usize),
+		metrics.requests(),
+		provider.cached(),
+		glyphs_cached,
+		bytes_to_human(glyph_bytes),
+		metrics.glyph_hits(),
+		metrics.glyph_misses()
+	)
+}
+
+fn me_text(req: &Request<Body>, client: IpAddr, agent: &str) -> String {
+	let referrer = req
+		.headers()
+		.get(hyper::header::REFERER)
+		.and_then(|hv| hv.to_str().ok())
+		.unwrap_or("unknown");
+
+	format!(
+		"IP: {}\n\nUser Agent\n{}\n\nReferrer\n{}",
+		client, agent, referrer
+	)
+}
+
+/// What a signature covers. This is the operation's canonical query so any
+/// spelling of the same image verifies. The info and me pages aren't
+/// described by the operation, so their name and the font they're drawn in
+/// are added on.
+fn signed_query(op: &Operation, page: Page, font: Option<&str>) -> String {
+	let mut signed = op.canonical_query();
+
+	if let Some(marker) = page.signature_marker() {
+		signed.push('&');
+		signed.push_str(marker);
+
+		if let Some(font) = font {
+			signed.push_str(&format!("&font={}", Query::url_encode(font)));
+		}
+	}
+
+	signed
+}
+
+/// Append a signature for `page` to a query, with an expiry in seconds since
+/// the unix epoch if there is one.
+fn sign_query(
+	signer: &Signer,
+	query_str: &str,
+	page: Page,
+	expires: Option<u64>,
+) -> Result<String, ServeError> {
+	let query = parse_query(query_str)?;
+	let font = query_value(&query, "font");
+	let (op, _) = Operation::parse(query);
+
+	let signature = signer.sign(&signed_query(&op, page, font.as_deref()), expires);
+
+	Ok(match expires {
+		Some(exp) => format!("{}&exp={}&sig={}", query_str, exp, signature),
+		None => format!("{}&sig={}", query_str, signature),
+	})
+}
+
+/// Remove any signature and expiry from a query so it can be signed again.
+fn strip_signature(query_str: &str) -> String {
+	query_str
+		.split('&')
+		.filter(|param| {
+			let key = param.split('=').next().unwrap_or_default();
+			key != "sig" && key != "exp"
+		})
+		.collect::<Vec<&str>>()
+		.join("&")
+}
+
+fn unix_now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
+}
+
+/// Identifies a request in the logs.
+static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The most bytes we'll read from a POSTed document.
+const MAX_DOCUMENT_SIZE: usize = 64 * 1024;
+
+/// How many families /fonts lists at a time, unless asked for more.
+const FONTS_PER_PAGE: usize = 50;
+
+/// The most families /fonts will list at a time.
+const MAX_FONTS_PER_PAGE: usize = 500;
+
+/// Everything that can go wrong while serving a request, and the status code
+/// it's reported with.
+#[derive(Debug, Error)]
+enum ServeError {
+	#[error("{0}")]
+	BadInput(String),
+	#[error("{0}")]
+	Forbidden(String),
+	#[error("{0}")]
+	NotFound(String),
+	#[error("{0}")]
+	UnknownFont(String),
+	#[error("{0}")]
+	FontFetch(String),
+	#[error("{0}")]
+	TooLarge(String),
+	#[error("This method is not allowed")]
+	MethodNotAllowed,
+	#[error("{0}")]
+	Timeout(String),
+	#[error("{0}")]
+	Unavailable(String),
+	#[error("Too many {what}, try again in {} seconds", retry_after(.retry))]
+	RateLimited { what: &'static str, retry: Duration },
+	#[error("{0}")]
+	Internal(String),
+}
+
+impl ServeError {
+	fn status(&self) -> StatusCode {
+		match self {
+			ServeError::BadInput(_) => StatusCode::BAD_REQUEST,
+			ServeError::Forbidden(_) => StatusCode::FORBIDDEN,
+			ServeError::NotFound(_) | ServeError::UnknownFont(_) => StatusCode::NOT_FOUND,
+			ServeError::FontFetch(_) => StatusCode::BAD_GATEWAY,
+			ServeError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+			ServeError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+			ServeError::Timeout(_) | ServeError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+			ServeError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+			ServeError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+}
+
+impl ServeError {
+	fn response(&self) -> Response<Body> {
+		let mut response = plain_response(self.status(), self.to_string());
+
+		match self {
+			ServeError::RateLimited { retry, .. } => {
+				response
+					.headers_mut()
+					.insert(RETRY_AFTER, HeaderValue::from(retry_after(retry)));
+			}
+			ServeError::MethodNotAllowed => {
+				response
+					.headers_mut()
+					.insert(ALLOW, HeaderValue::from_static(ALLOWED_METHODS));
+			}
+			_ => (),
+		}
+
+		response
+	}
+}
+
+/// Take the body off a response to a HEAD request. The content-length stays as
+/// it would have been for a GET.
+async fn head_response(response: Response<Body>) -> Response<Body> {
+	let (mut parts, body) = response.into_parts();
+
+	if !parts.headers.contains_key(CONTENT_LENGTH) {
+		if let Ok(bytes) = hyper::body::to_bytes(body).await {
+			parts
+				.headers
+				.insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+		}
+	}
+
+	Response::from_parts(parts, Body::empty())
+}
+
+/// Retry-After is in whole seconds. Round up so clients don't come back early.
+fn retry_after(retry: &Duration) -> u64 {
+	retry.as_secs() + if retry.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+impl From<FontError> for ServeError {
+	fn from(e: FontError) -> Self {
+		match e {
+			FontError::UnknownFamily(_) | FontError::UnknownVariant { .. } => {
+				ServeError::UnknownFont(e.to_string())
+			}
+			FontError::Fetch { .. } | FontError::Parse { .. } | FontError::List(_) => {
+				ServeError::FontFetch(e.to_string())
+			}
+			FontError::NotReady(_) => ServeError::Unavailable(e.to_string()),
+			FontError::Corrupt { .. } | FontError::Io(_) => ServeError::Internal(e.to_string()),
+		}
+	}
+}
+
+impl From<RenderError> for ServeError {
+	fn from(e: RenderError) -> Self {
+		match e {
+			RenderError::Font(e) => e.into(),
+			RenderError::Timeout(_) => ServeError::Timeout(e.to_string()),
+			_ => ServeError::TooLarge(e.to_string()),
+		}
+	}
+}
+
+impl From<RouteError> for ServeError {
+	fn from(e: RouteError) -> Self {
+		ServeError::NotFound(e.to_string())
+	}
+}
+
+impl From<SignatureError> for ServeError {
+	fn from(e: SignatureError) -> Self {
+		ServeError::Forbidden(e.to_string())
+	}
+}
+
+impl From<hyper::http::Error> for ServeError {
+	fn from(e: hyper::http::Error) -> Self {
+		ServeError::Internal(e.to_string())
+	}
+}
+
+fn json_response(json: String) -> Result<Response<Body>, hyper::http::Error> {
+	Response::builder()
+		.header("content-type", "application/json")
+		.header("content-length", json.len())
+		.body(Body::from(json))
+}
+
+fn plain_response<S: Into<String>>(status: StatusCode, body: S) -> Response<Body> {
+	let body = body.into();
+	let length = body.len();
+
+	let mut response = Response::new(Body::from(body));
+	*response.status_mut() = status;
+	response
+		.headers_mut()
+		.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+	response
+		.headers_mut()
+		.insert(CONTENT_LENGTH, HeaderValue::from(length));
+
+	response
 }
 
 fn bytes_to_human(bytes: usize) -> String {
@@ -272,9 +1525,93 @@ fn bytes_to_human(bytes: usize) -> String {
 	format!("{} {}", (bytes * 10.0).ceil() / 10.0, suffix)
 }
 
-async fn make_image(textual: Arc<Textual>, op: Operation) -> Result<Response<Body>, Infallible> {
-	let image = op.make_image(&textual.font_provider).await;
+/// Whether an image can be kept, by us or by anyone between us and the client.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Caching {
+	/// It's the same for everyone, for as long as MaxAge says
+	Shared,
+	/// It's about the client, or changes on its own, so nobody keeps it
+	NoStore,
+}
+
+impl Caching {
+	fn header(self, settings: &Settings) -> String {
+		match self {
+			Caching::Shared => format!("public, max-age={}", settings.config.max_age()),
+			Caching::NoStore => "private, no-store".into(),
+		}
+	}
+}
+
+async fn make_image(
+	textual: Arc<Textual>,
+	settings: &Settings,
+	op: Operation,
+	etag: String,
+	client: IpAddr,
+	caching: Caching,
+) -> Result<Response<Body>, ServeError> {
+	if caching == Caching::Shared {
+		if let Some(encoded) = textual.responses.get(&etag).await {
+			return image_response(settings, &etag, encoded, caching);
+		}
+	}
+
+	// Only work we'd actually have to do counts against a client
+	let downloads = {
+		let provider = textual.font_provider.read().await;
+		op.fonts()
+			.into_iter()
+			.filter(|(family, variant)| provider.needs_download(family, *variant))
+			.count()
+	};
+
+	if downloads > 0 {
+		textual
+			.font_limiter
+			.take(client, downloads as f64)
+			.map_err(|retry| ServeError::RateLimited {
+				what: "font downloads",
+				retry,
+			})?;
+	}
+
+	textual
+		.render_limiter
+		.take(client, 1.0)
+		.map_err(|retry| ServeError::RateLimited {
+			what: "renders",
+			retry,
+		})?;
+
+	let image = {
+		let _rendering = textual.metrics.rendering();
+		let start = Instant::now();
+
+		let (image, counts) = op
+			.make_image(
+				&textual.font_provider,
+				&textual.rasterizer,
+				&settings.config.limits(),
+			)
+			.await?;
+
+		textual
+			.metrics
+			.rendered(start.elapsed(), counts.glyphs, counts.fonts);
+		image
+	};
+
+	let encoded = encode_png(&image)?;
+	if caching == Caching::Shared {
+		textual.responses.insert(etag.clone(), encoded.clone());
+	}
+
+	image_response(settings, &etag, encoded, caching)
+}
 
+#[instrument(level = "debug", skip_all)]
+fn encode_png(image: &image::Image) -> Result<Bytes, ServeError> {
 	let mut encoded_buffer = vec![];
 
 	let encoder = PngEncoder::new(&mut encoded_buffer);
@@ -285,13 +1622,175 @@ async fn make_image(textual: Arc<Textual>, op: Operation) -> Result<Response<Bod
 			image.height() as u32,
 			crateimage::ColorType::Rgba8,
 		)
-		.unwrap();
+		.map_err(|e| ServeError::Internal(format!("Failed to encode png: {}", e)))?;
 
-	Response::builder()
+	Ok(Bytes::from(encoded_buffer))
+}
+
+/// Tell a strict request what was wrong with it. Clients that accept JSON
+/// get the problems as JSON, everyone else gets them drawn in an image so
+/// they show up where the image would have been.
+async fn problem_response(
+	textual: &Textual,
+	settings: &Settings,
+	req: &Request<Body>,
+	client: IpAddr,
+	problems: &[Problem],
+) -> Result<Response<Body>, ServeError> {
+	let wants_json = req
+		.headers()
+		.get(hyper::header::ACCEPT)
+		.and_then(|hv| hv.to_str().ok())
+		.map(|accept| accept.contains("application/json"))
+		.unwrap_or(false);
+
+	if wants_json {
+		#[derive(Serialize)]
+		struct Problems<'a> {
+			problems: &'a [Problem],
+		}
+
+		let json = serde_json::to_string(&Problems { problems })
+			.map_err(|e| ServeError::Internal(e.to_string()))?;
+
+		return Ok(Response::builder()
+			.status(StatusCode::BAD_REQUEST)
+			.header("content-type", "application/json")
+			.header("content-length", json.len())
+			.body(Body::from(json))?);
+	}
+
+	let message = problems
+		.iter()
+		.map(|p| p.to_string())
+		.collect::<Vec<String>>()
+		.join("\n");
+
+	// Drawing the problems is still a render
+	textual
+		.render_limiter
+		.take(client, 1.0)
+		.map_err(|retry| ServeError::RateLimited {
+			what: "renders",
+			retry,
+		})?;
+
+	let (image, _) = Operation::message(message)
+		.make_image(
+			&textual.font_provider,
+			&textual.rasterizer,
+			&settings.config.limits(),
+		)
+		.await?;
+	let encoded = encode_png(&image)?;
+
+	Ok(Response::builder()
+		.status(StatusCode::BAD_REQUEST)
 		.header("content-type", "image/png")
-		.header("content-length", encoded_buffer.len())
-		.body(Body::from(encoded_buffer))
-		.map_err(|e| panic!())
+		.header("content-length", encoded.len())
+		.body(Body::from(encoded))?)
+}
+
+/// Headers can only hold visible ASCII, so replace anything else.
+fn header_safe<S: AsRef<str>>(string: S) -> String {
+	string
+		.as_ref()
+		.chars()
+		.map(|c| {
+			if c.is_ascii_graphic() || c == ' ' {
+				c
+			} else {
+				'?'
+			}
+		})
+		.collect()
+}
+
+fn image_response(
+	settings: &Settings,
+	etag: &str,
+	encoded: Bytes,
+	caching: Caching,
+) -> Result<Response<Body>, ServeError> {
+	Ok(Response::builder()
+		.header("content-type", "image/png")
+		.header("content-length", encoded.len())
+		.header("etag", format!("\"{}\"", etag))
+		.header("cache-control", caching.header(settings))
+		.body(Body::from(encoded))?)
+}
+
+fn not_modified(
+	settings: &Settings,
+	etag: &str,
+	caching: Caching,
+) -> Result<Response<Body>, ServeError> {
+	Ok(Response::builder()
+		.status(StatusCode::NOT_MODIFIED)
+		.header("etag", format!("\"{}\"", etag))
+		.header("cache-control", caching.header(settings))
+		.body(Body::empty())?)
+}
+
+/// Whether any of the tags in the request's If-None-Match header are ours.
+fn etag_matches(req: &Request<Body>, etag: &str) -> bool {
+	let header = match req
+		.headers()
+		.get(hyper::header::IF_NONE_MATCH)
+		.map(|hv| hv.to_str())
+	{
+		Some(Ok(header)) => header,
+		_ => return false,
+	};
+
+	header
+		.split(',')
+		.map(|tag| tag.trim())
+		.any(|tag| tag == "*" || tag.trim_start_matches("W/").trim_matches('"') == etag)
+}
+
+/// Every device pixel ratio we render, formatted for an `<img>` srcset.
+fn srcset<F>(link: F) -> Result<String, ServeError>
+where
+	F: Fn(&str) -> Result<String, ServeError>,
+{
+	Ok((1..=3)
+		.map(|dpr| link(&format!("&dpr={}", dpr)).map(|link| format!("{} {}x", link, dpr)))
+		.collect::<Result<Vec<String>, ServeError>>()?
+		.join(", "))
+}
+
+/// Where we are, for links to ourselves.
+fn base_url(settings: &Settings, req: &Request<Body>) -> String {
+	let peer = req.extensions().get::<Peer>();
+
+	// Find the hostname we should use for the image link in the opengraph tags.
+	// HTTP/2 puts it in the URI instead of a Host header.
+	let host = settings
+		.config
+		.meta_host()
+		.or(req.headers().get("host").and_then(|hv| hv.to_str().ok()))
+		.or(req.uri().authority().map(|authority| authority.as_str()))
+		.unwrap_or("localhost");
+
+	// A proxy in front of us knows how the client reached it
+	let forwarded = peer
+		.filter(|peer| settings.config.trusted_proxies().contains(&peer.remote))
+		.and_then(|_| req.headers().get("X-Forwarded-Proto"))
+		.and_then(|hv| hv.to_str().ok())
+		.filter(|proto| *proto == "http" || *proto == "https");
+
+	let secure = peer.filter(|peer| peer.secure).map(|_| "https");
+
+	let scheme = settings
+		.config
+		.scheme()
+		.or(forwarded)
+		.or(secure)
+		.or(req.uri().scheme_str())
+		.unwrap_or(if host == "localhost" { "http" } else { "https" });
+
+	format!("{}://{}", scheme, host)
 }
 
 static TEMPLATE: &'static str = include_str!("template.htm");
@@ -306,26 +1805,108 @@ struct Meta {
 	hex_color: String,
 }
 
+/// The card for a page. Its image links are to the page with the same query,
+/// plus an aspect ratio or density. Those change what's drawn, so if we're
+/// signing they're signed again here. They'll expire with the request.
 async fn make_meta(
-	textual: Arc<Textual>,
+	settings: &Settings,
+	req: &Request<Body>,
 	op: Operation,
-	link: String,
-) -> Result<Response<Body>, Infallible> {
-	let mut t = Document::from_str(TEMPLATE).unwrap();
+	page: Page,
+	expires: Option<u64>,
+) -> Result<Response<Body>, ServeError> {
+	let base = format!("{}{}{}", base_url(settings, req), PREFIX, page.path());
+	let query = strip_signature(req.uri().query().unwrap_or_default());
+
+	let link = |extra: &str| -> Result<String, ServeError> {
+		let query = format!("{}{}", query, extra);
+		let query = match settings.signer.as_ref() {
+			Some(signer) => sign_query(signer, &query, page, expires)?,
+			None => query,
+		};
+
+		Ok(format!("{}?{}", base, query))
+	};
+
+	let mut t = Document::from_str(TEMPLATE)
+		.map_err(|_| ServeError::Internal("Failed to parse the meta template".into()))?;
 
 	t.set("text", op.full_text());
 	t.set("alt", op.get_alt());
-	t.set("twitter_image", format!("{}&aspect=1.8", link));
-	t.set("og_image", format!("{}&aspect=1.8", link));
-	t.set("image", link);
+	t.set("twitter_image", link("&aspect=1.8")?);
+	t.set("og_image", link("&aspect=1.8")?);
+	t.set("srcset", srcset(link)?);
+	t.set("image", link("")?);
 	t.set("font", String::new());
 	t.set("hex_color", String::new());
 
 	let render = t.compile();
 
-	Response::builder()
+	Ok(Response::builder()
 		.header("content-type", "text/html")
 		.header("content-length", render.len())
-		.body(Body::from(render))
-		.map_err(|e| panic!())
+		.body(Body::from(render))?)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[tokio::test]
+	async fn meta_srcset_is_signed() {
+		let args = vec![
+			"textual".into(),
+			"--font-cache".into(),
+			std::env::temp_dir().display().to_string(),
+			"--meta-host".into(),
+			"example.com".into(),
+		];
+		let mut settings = Settings::new(Config::from_args(args).unwrap().unwrap());
+		settings.signer = Some(Signer::new("secret"));
+
+		// The card's own signature isn't carried over
+		let req = Request::builder()
+			.uri("/v1/render.png?text=Hi&sig=00")
+			.body(Body::empty())
+			.unwrap();
+		let (op, _) = Operation::parse(parse_query("text=Hi").unwrap());
+		let expires = unix_now() + 60;
+
+		let response = make_meta(&settings, &req, op, Page::Render, Some(expires))
+			.await
+			.unwrap();
+		let html = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		let html = String::from_utf8(html.to_vec()).unwrap();
+		let srcset = html
+			.split("srcset=\"")
+			.nth(1)
+			.and_then(|rest| rest.split('"').next())
+			.unwrap()
+			.replace("&amp;", "&");
+
+		let links: Vec<&str> = srcset.split(", ").collect();
+		assert_eq!(links.len(), 3);
+
+		let signer = settings.signer.as_ref().unwrap();
+		for (dpr, link) in (1..=3).zip(links) {
+			let (url, density) = link.split_once(' ').unwrap();
+			assert_eq!(density, format!("{}x", dpr));
+
+			let query = url
+				.strip_prefix("https://example.com/v1/render.png?")
+				.unwrap();
+			let requested = PageQuery::new(Some(parse_query(query).unwrap()));
+			assert_eq!(requested.op.dpr, dpr);
+			assert_eq!(requested.expires, Some(expires.to_string()));
+
+			signer
+				.verify(
+					&signed_query(&requested.op, Page::Render, None),
+					requested.expires.as_deref(),
+					requested.signature.as_deref(),
+					unix_now(),
+				)
+				.unwrap();
+		}
+	}
 }
@@ -0,0 +1,424 @@
+use std::{
+	collections::BTreeMap,
+	fmt::Write,
+	fs, io,
+	path::Path,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Mutex,
+	},
+	time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{fontprovider::FontCounts, raster::GlyphCounts};
+
+/// Upper bounds, in seconds, of the render latency histogram buckets.
+const RENDER_BUCKETS: [f64; 11] = [
+	0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Everything we count, exposed in the Prometheus text format. Counters can be
+/// saved to a file and loaded again so they survive restarts.
+#[derive(Default)]
+pub struct Metrics {
+	counters: Mutex<Counters>,
+	in_flight: AtomicUsize,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Counters {
+	/// Route name to status code to count
+	requests: BTreeMap<String, BTreeMap<u16, u64>>,
+	/// Content type to count and bytes
+	responses: BTreeMap<String, u64>,
+	response_bytes: BTreeMap<String, u64>,
+	render_seconds: Histogram,
+	font_hits: u64,
+	font_misses: u64,
+	font_downloads: u64,
+	glyph_hits: u64,
+	glyph_misses: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Histogram {
+	/// How many observations fell in each of [RENDER_BUCKETS]. These aren't
+	/// cumulative like Prometheus wants, we add them up when rendering.
+	buckets: Vec<u64>,
+	sum: f64,
+	count: u64,
+}
+
+impl Default for Histogram {
+	fn default() -> Self {
+		Self {
+			buckets: vec![0; RENDER_BUCKETS.len()],
+			sum: 0.0,
+			count: 0,
+		}
+	}
+}
+
+impl Histogram {
+	fn observe(&mut self, value: f64) {
+		if let Some(idx) = RENDER_BUCKETS.iter().position(|bound| value <= *bound) {
+			self.buckets[idx] += 1;
+		}
+
+		self.sum += value;
+		self.count += 1;
+	}
+}
+
+/// Current values that aren't counters, read when the metrics are rendered.
+pub struct Gauges {
+	pub fonts_cached: usize,
+	pub glyphs_cached: usize,
+	pub glyph_bytes: usize,
+}
+
+/// Marks a render as in flight until it's dropped.
+pub struct Rendering<'a> {
+	metrics: &'a Metrics,
+}
+
+impl Drop for Rendering<'_> {
+	fn drop(&mut self) {
+		self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+impl Metrics {
+	/// Load counters saved with [Metrics::save]. A file that doesn't exist yet
+	/// gives empty metrics.
+	pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		let counters: Counters = match fs::read(path) {
+			Ok(data) => serde_json::from_slice(&data)
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Counters::default(),
+			Err(e) => return Err(e),
+		};
+
+		// The buckets changed since these were saved, so they mean nothing now
+		let mut counters = counters;
+		if counters.render_seconds.buckets.len() != RENDER_BUCKETS.len() {
+			counters.render_seconds = Histogram::default();
+		}
+
+		Ok(Self {
+			counters: Mutex::new(counters),
+			in_flight: AtomicUsize::new(0),
+		})
+	}
+
+	/// Write the counters out so [Metrics::load] can pick them up again.
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+		let path = path.as_ref();
+		let json = serde_json::to_vec(&*self.counters.lock().unwrap())
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+		let temporary = path.with_extension("tmp");
+		fs::write(&temporary, json)?;
+		fs::rename(&temporary, path)
+	}
+
+	/// Count a response. `route` is the name of what served it.
+	pub fn request(&self, route: &str, status: u16, mime: Option<&str>, bytes: usize) {
+		let mut counters = self.counters.lock().unwrap();
+
+		*counters
+			.requests
+			.entry(route.to_owned())
+			.or_default()
+			.entry(status)
+			.or_default() += 1;
+
+		if let Some(mime) = mime {
+			*counters.responses.entry(mime.to_owned()).or_default() += 1;
+			*counters.response_bytes.entry(mime.to_owned()).or_default() += bytes as u64;
+		}
+	}
+
+	/// Start a render. It stops being in flight when the guard is dropped.
+	pub fn rendering(&self) -> Rendering<'_> {
+		self.in_flight.fetch_add(1, Ordering::Relaxed);
+		Rendering { metrics: self }
+	}
+
+	/// Count a finished render.
+	pub fn rendered(&self, took: Duration, glyphs: GlyphCounts, fonts: FontCounts) {
+		let mut counters = self.counters.lock().unwrap();
+
+		counters.render_seconds.observe(took.as_secs_f64());
+		counters.glyph_hits += glyphs.hits as u64;
+		counters.glyph_misses += glyphs.misses as u64;
+		counters.font_hits += fonts.hits as u64;
+		counters.font_misses += fonts.misses as u64;
+		counters.font_downloads += fonts.downloads as u64;
+	}
+
+	pub fn requests(&self) -> u64 {
+		self.counters
+			.lock()
+			.unwrap()
+			.requests
+			.values()
+			.flat_map(|statuses| statuses.values())
+			.sum()
+	}
+
+	/// Bytes sent with a content type starting with `prefix`.
+	pub fn sent(&self, prefix: &str) -> u64 {
+		self.counters
+			.lock()
+			.unwrap()
+			.response_bytes
+			.iter()
+			.filter(|(mime, _)| mime.starts_with(prefix))
+			.map(|(_, bytes)| bytes)
+			.sum()
+	}
+
+	pub fn glyph_hits(&self) -> u64 {
+		self.counters.lock().unwrap().glyph_hits
+	}
+
+	pub fn glyph_misses(&self) -> u64 {
+		self.counters.lock().unwrap().glyph_misses
+	}
+
+	/// Everything in the Prometheus text exposition format.
+	pub fn render(&self, gauges: &Gauges) -> String {
+		let counters = self.counters.lock().unwrap();
+		let mut out = String::new();
+
+		header(
+			&mut out,
+			"textual_requests_total",
+			"counter",
+			"Responses sent, by route and status",
+		);
+		for (route, statuses) in &counters.requests {
+			for (status, count) in statuses {
+				let _ = writeln!(
+					out,
+					"textual_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+					escape(route),
+					status,
+					count
+				);
+			}
+		}
+
+		header(
+			&mut out,
+			"textual_responses_total",
+			"counter",
+			"Responses sent, by content type",
+		);
+		for (mime, count) in &counters.responses {
+			let _ = writeln!(
+				out,
+				"textual_responses_total{{type=\"{}\"}} {}",
+				escape(mime),
+				count
+			);
+		}
+
+		header(
+			&mut out,
+			"textual_response_bytes_total",
+			"counter",
+			"Bytes sent, by content type",
+		);
+		for (mime, bytes) in &counters.response_bytes {
+			let _ = writeln!(
+				out,
+				"textual_response_bytes_total{{type=\"{}\"}} {}",
+				escape(mime),
+				bytes
+			);
+		}
+
+		header(
+			&mut out,
+			"textual_render_seconds",
+			"histogram",
+			"Time spent rendering images that weren't cached",
+		);
+		let histogram = &counters.render_seconds;
+		let mut cumulative = 0;
+		for (bound, count) in RENDER_BUCKETS.iter().zip(histogram.buckets.iter()) {
+			cumulative += count;
+			let _ = writeln!(
+				out,
+				"textual_render_seconds_bucket{{le=\"{}\"}} {}",
+				bound, cumulative
+			);
+		}
+		let _ = writeln!(
+			out,
+			"textual_render_seconds_bucket{{le=\"+Inf\"}} {}",
+			histogram.count
+		);
+		let _ = writeln!(out, "textual_render_seconds_sum {}", histogram.sum);
+		let _ = writeln!(out, "textual_render_seconds_count {}", histogram.count);
+
+		let values = [
+			(
+				"textual_renders_in_flight",
+				"gauge",
+				"Renders happening right now",
+				self.in_flight.load(Ordering::Relaxed) as u64,
+			),
+			(
+				"textual_font_cache_hits_total",
+				"counter",
+				"Fonts found in the font cache",
+				counters.font_hits,
+			),
+			(
+				"textual_font_cache_misses_total",
+				"counter",
+				"Fonts that weren't in the font cache",
+				counters.font_misses,
+			),
+			(
+				"textual_font_downloads_total",
+				"counter",
+				"Fonts downloaded successfully",
+				counters.font_downloads,
+			),
+			(
+				"textual_fonts_cached",
+				"gauge",
+				"Font variants in the font cache",
+				gauges.fonts_cached as u64,
+			),
+			(
+				"textual_glyph_cache_hits_total",
+				"counter",
+				"Glyphs found in the glyph cache",
+				counters.glyph_hits,
+			),
+			(
+				"textual_glyph_cache_misses_total",
+				"counter",
+				"Glyphs that had to be rasterized",
+				counters.glyph_misses,
+			),
+			(
+				"textual_glyphs_cached",
+				"gauge",
+				"Glyphs in the glyph cache",
+				gauges.glyphs_cached as u64,
+			),
+			(
+				"textual_glyph_cache_bytes",
+				"gauge",
+				"Bytes of glyphs in the glyph cache",
+				gauges.glyph_bytes as u64,
+			),
+		];
+
+		for (name, kind, help, value) in values.iter() {
+			header(&mut out, name, kind, help);
+			let _ = writeln!(out, "{} {}", name, value);
+		}
+
+		out
+	}
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {} {}", name, help);
+	let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Label values escape backslashes, quotes, and newlines.
+fn escape(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn gauges() -> Gauges {
+		Gauges {
+			fonts_cached: 0,
+			glyphs_cached: 0,
+			glyph_bytes: 0,
+		}
+	}
+
+	#[test]
+	fn counts_requests() {
+		let metrics = Metrics::default();
+		metrics.request("card", 200, Some("text/html"), 100);
+		metrics.request("image", 200, Some("image/png"), 1000);
+		metrics.request("image", 404, Some("text/plain"), 10);
+
+		assert_eq!(metrics.requests(), 3);
+		assert_eq!(metrics.sent("image"), 1000);
+
+		let rendered = metrics.render(&gauges());
+		assert!(rendered.contains("textual_requests_total{route=\"image\",status=\"404\"} 1"));
+		assert!(rendered.contains("textual_response_bytes_total{type=\"text/html\"} 100"));
+	}
+
+	#[test]
+	fn histogram_is_cumulative() {
+		let metrics = Metrics::default();
+		let counts = (GlyphCounts::default(), FontCounts::default());
+
+		metrics.rendered(Duration::from_millis(3), counts.0, counts.1);
+		metrics.rendered(Duration::from_millis(30), counts.0, counts.1);
+		metrics.rendered(Duration::from_secs(60), counts.0, counts.1);
+
+		let rendered = metrics.render(&gauges());
+		assert!(rendered.contains("textual_render_seconds_bucket{le=\"0.005\"} 1"));
+		assert!(rendered.contains("textual_render_seconds_bucket{le=\"0.05\"} 2"));
+		assert!(rendered.contains("textual_render_seconds_bucket{le=\"10\"} 2"));
+		assert!(rendered.contains("textual_render_seconds_bucket{le=\"+Inf\"} 3"));
+		assert!(rendered.contains("textual_render_seconds_count 3"));
+	}
+
+	#[test]
+	fn in_flight_guard() {
+		let metrics = Metrics::default();
+
+		{
+			let _rendering = metrics.rendering();
+			assert!(metrics
+				.render(&gauges())
+				.contains("textual_renders_in_flight 1"));
+		}
+
+		assert!(metrics
+			.render(&gauges())
+			.contains("textual_renders_in_flight 0"));
+	}
+
+	#[test]
+	fn save_and_load() {
+		let path =
+			std::env::temp_dir().join(format!("textual-metrics-{}.json", std::process::id()));
+
+		let metrics = Metrics::default();
+		metrics.request("card", 200, Some("text/html"), 100);
+		metrics.save(&path).unwrap();
+
+		let loaded = Metrics::load(&path).unwrap();
+		assert_eq!(loaded.requests(), 1);
+		assert_eq!(loaded.sent("text/html"), 100);
+
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,214 @@
+use std::sync::{Arc, Mutex};
+
+use fontster::Font;
+
+use crate::{color::Gamma, lru::Lru};
+
+/// How many positions within a pixel a glyph can be rasterized at. Each
+/// glyph is rounded to the nearest 1/SUBPIXEL_STEPS of a pixel.
+pub const SUBPIXEL_STEPS: u8 = 4;
+
+/// Roughly how many bytes a cache entry takes up beyond its coverage.
+const GLYPH_OVERHEAD: usize = 64;
+
+/// Turns glyphs into coverage bitmaps, keeping recently used ones around so
+/// they're shared between requests.
+pub struct Rasterizer {
+	gamma: Gamma,
+	hint_below: f32,
+	cache: Mutex<Lru<GlyphKey, Arc<RasterGlyph>>>,
+}
+
+impl Rasterizer {
+	/// Glyphs with a font size under `hint_below` are hinted. Set it to 0.0 to
+	/// never hint. `cache_budget` is how many bytes of glyphs to keep.
+	pub fn new(gamma: Gamma, hint_below: f32, cache_budget: usize) -> Self {
+		Self {
+			gamma,
+			hint_below,
+			cache: Mutex::new(Lru::new(cache_budget)),
+		}
+	}
+
+	pub fn gamma(&self) -> &Gamma {
+		&self.gamma
+	}
+
+	/// fontster can't hint outlines, so the best we can do for small text is
+	/// snap it to the pixel grid and skip subpixel positioning. This keeps
+	/// stems on whole pixels rather than smearing them over two.
+	pub fn hinted(&self, font_size: f32) -> bool {
+		font_size < self.hint_below
+	}
+
+	/// Split a position into the whole pixel a glyph should be drawn at and
+	/// the subpixel bucket it should be rasterized with.
+	pub fn position(&self, x: f32, font_size: f32) -> (isize, u8) {
+		if self.hinted(font_size) {
+			return (x.round() as isize, 0);
+		}
+
+		let whole = x.floor();
+		let bucket = ((x - whole) * SUBPIXEL_STEPS as f32).round() as u8;
+
+		if bucket == SUBPIXEL_STEPS {
+			(whole as isize + 1, 0)
+		} else {
+			(whole as isize, bucket)
+		}
+	}
+
+	/// Get a glyph from the cache, rasterizing it if we don't have it yet.
+	pub fn glyph(
+		&self,
+		font: &Font,
+		c: char,
+		font_size: f32,
+		subpixel: u8,
+		counts: &mut GlyphCounts,
+	) -> Arc<RasterGlyph> {
+		let key = GlyphKey {
+			font: font.file_hash(),
+			c,
+			font_size: font_size.to_bits(),
+			subpixel,
+		};
+
+		if let Some(glyph) = self.cache.lock().unwrap().get(&key) {
+			counts.hits += 1;
+			return glyph.clone();
+		}
+
+		// We don't hold the lock while rasterizing. If two requests race on the
+		// same glyph they'll both do the work, which is fine.
+		counts.misses += 1;
+		let glyph = Arc::new(self.rasterize(font, c, font_size, subpixel));
+		let size = glyph.coverage.len() + GLYPH_OVERHEAD;
+
+		self.cache.lock().unwrap().insert(key, glyph.clone(), size);
+
+		glyph
+	}
+
+	/// Change how many bytes of glyphs to keep.
+	pub fn set_cache_budget(&self, budget: usize) {
+		self.cache.lock().unwrap().set_budget(budget);
+	}
+
+	/// How many glyphs are cached and how many bytes they take up.
+	pub fn cached(&self) -> (usize, usize) {
+		let cache = self.cache.lock().unwrap();
+		(cache.len(), cache.used())
+	}
+
+	/// Rasterize a character offset to the right by `subpixel` buckets, with
+	/// coverage adjusted by our contrast.
+	fn rasterize(&self, font: &Font, c: char, font_size: f32, subpixel: u8) -> RasterGlyph {
+		let (metrics, raster) = font.rasterize(c, font_size);
+		let offset = subpixel as f32 / SUBPIXEL_STEPS as f32;
+
+		let (width, coverage) = shift(metrics.width, metrics.height, &raster, offset);
+
+		RasterGlyph {
+			width,
+			height: metrics.height,
+			coverage: coverage
+				.into_iter()
+				.map(|cov| self.gamma.coverage(cov))
+				.collect(),
+		}
+	}
+}
+
+impl Default for Rasterizer {
+	fn default() -> Self {
+		Self::new(Gamma::default(), 0.0, 16 * 1024 * 1024)
+	}
+}
+
+/// Identifies a rasterized glyph across every font we've loaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct GlyphKey {
+	/// Hash of the font file, so the same font loaded twice shares glyphs
+	font: usize,
+	c: char,
+	font_size: u32,
+	subpixel: u8,
+}
+
+/// Cache hits and misses for a single render.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GlyphCounts {
+	pub hits: usize,
+	pub misses: usize,
+}
+
+/// A greyscale coverage bitmap of a glyph.
+#[derive(Debug, Clone)]
+pub struct RasterGlyph {
+	pub width: usize,
+	pub height: usize,
+	pub coverage: Vec<u8>,
+}
+
+/// Shift a coverage bitmap to the right by `offset` of a pixel, where offset is
+/// in 0.0..1.0. Each pixel gives up `offset` of its coverage to the pixel on its
+/// right, which is what rasterizing with a fractional origin would have done.
+/// The returned bitmap is a pixel wider if the offset is non-zero.
+fn shift(width: usize, height: usize, raster: &[u8], offset: f32) -> (usize, Vec<u8>) {
+	if offset == 0.0 || width == 0 {
+		return (width, raster.to_vec());
+	}
+
+	let new_width = width + 1;
+	let mut shifted = vec![0; new_width * height];
+
+	for y in 0..height {
+		let row = &raster[y * width..(y + 1) * width];
+
+		for x in 0..new_width {
+			let here = row.get(x).map(|c| *c as f32).unwrap_or(0.0);
+			let left = if x > 0 { row[x - 1] as f32 } else { 0.0 };
+
+			shifted[y * new_width + x] = (here * (1.0 - offset) + left * offset).round() as u8;
+		}
+	}
+
+	(new_width, shifted)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn position_buckets() {
+		let raster = Rasterizer::default();
+
+		assert_eq!(raster.position(10.0, 16.0), (10, 0));
+		assert_eq!(raster.position(10.25, 16.0), (10, 1));
+		assert_eq!(raster.position(10.6, 16.0), (10, 2));
+		assert_eq!(raster.position(10.9, 16.0), (11, 0));
+	}
+
+	#[test]
+	fn position_hinted() {
+		let raster = Rasterizer::new(Gamma::default(), 14.0, 0);
+
+		assert_eq!(raster.position(10.25, 12.0), (10, 0));
+		assert_eq!(raster.position(10.6, 12.0), (11, 0));
+		assert_eq!(raster.position(10.25, 16.0), (10, 1));
+	}
+
+	#[test]
+	fn shift_spreads_coverage() {
+		let (width, shifted) = shift(2, 1, &[255, 0], 0.5);
+
+		assert_eq!(width, 3);
+		assert_eq!(shifted, vec![128, 128, 0]);
+
+		let (width, unshifted) = shift(2, 1, &[255, 0], 0.0);
+		assert_eq!(width, 2);
+		assert_eq!(unshifted, vec![255, 0]);
+	}
+}
@@ -0,0 +1,309 @@
+use std::{
+	collections::HashMap,
+	net::{IpAddr, Ipv6Addr},
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+/// Once we're tracking this many clients, forget the ones whose buckets have
+/// refilled. They'd start full anyway.
+const PRUNE_AT: usize = 4096;
+
+/// Looking for refilled buckets means looking at all of them, so it's done
+/// at most this often.
+const PRUNE_EVERY: Duration = Duration::from_secs(10);
+
+/// The most clients we'll track. Past this the ones we heard from longest ago
+/// are forgotten, a tenth at a time so it doesn't happen on every request.
+const MAX_CLIENTS: usize = 65536;
+
+/// Token bucket rate limiting keyed by client address. Every client starts
+/// with `burst` tokens which refill at a steady rate.
+///
+/// IPv6 clients are limited by their /64, since that's usually what one
+/// customer gets and they can pick any address in it.
+pub struct RateLimiter {
+	rate: Mutex<Rate>,
+	buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+	clients: HashMap<IpAddr, Bucket>,
+	pruned: Instant,
+}
+
+impl Buckets {
+	/// Forget clients whose buckets have refilled, if we haven't looked lately.
+	fn prune(&mut self, rate: &Rate, now: Instant) {
+		if self.clients.len() < PRUNE_AT || now.saturating_duration_since(self.pruned) < PRUNE_EVERY
+		{
+			return;
+		}
+
+		self.clients
+			.retain(|_, bucket| rate.refilled(bucket, now) < rate.burst);
+		self.pruned = now;
+	}
+
+	/// Make room for a new client by forgetting the ones we heard from
+	/// longest ago.
+	fn make_room(&mut self) {
+		if self.clients.len() < MAX_CLIENTS {
+			return;
+		}
+
+		let mut updated: Vec<Instant> = self.clients.values().map(|b| b.updated).collect();
+		let (_, cutoff, _) = updated.select_nth_unstable(MAX_CLIENTS / 10);
+		let cutoff = *cutoff;
+
+		self.clients.retain(|_, bucket| bucket.updated > cutoff);
+	}
+}
+
+#[derive(Copy, Clone)]
+struct Rate {
+	per_second: f64,
+	burst: f64,
+}
+
+impl Rate {
+	fn new(per_minute: f64, burst: f64) -> Self {
+		Self {
+			per_second: per_minute / 60.0,
+			burst: burst.max(1.0),
+		}
+	}
+
+	/// How many tokens the bucket would have at `now`.
+	fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+		let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+		(bucket.tokens + elapsed * self.per_second).min(self.burst)
+	}
+}
+
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+impl RateLimiter {
+	/// A rate of 0 disables limiting.
+	pub fn new(per_minute: f64, burst: f64) -> Self {
+		Self {
+			rate: Mutex::new(Rate::new(per_minute, burst)),
+			buckets: Mutex::new(Buckets {
+				clients: HashMap::new(),
+				pruned: Instant::now(),
+			}),
+		}
+	}
+
+	/// Change the rate. Clients keep the tokens they have, up to the new burst.
+	pub fn set_rate(&self, per_minute: f64, burst: f64) {
+		*self.rate.lock().unwrap() = Rate::new(per_minute, burst);
+	}
+
+	/// Take `cost` tokens from the client's bucket. If there aren't enough,
+	/// nothing is taken and we return how long until there will be.
+	pub fn take(&self, client: IpAddr, cost: f64) -> Result<(), Duration> {
+		self.take_at(client, cost, Instant::now())
+	}
+
+	fn take_at(&self, client: IpAddr, cost: f64, now: Instant) -> Result<(), Duration> {
+		let rate = *self.rate.lock().unwrap();
+		if rate.per_second <= 0.0 {
+			return Ok(());
+		}
+
+		// A cost larger than the bucket could never be paid
+		let cost = cost.min(rate.burst);
+
+		let client = limited_as(client);
+		let mut buckets = self.buckets.lock().unwrap();
+		buckets.prune(&rate, now);
+		if !buckets.clients.contains_key(&client) {
+			buckets.make_room();
+		}
+
+		let bucket = buckets.clients.entry(client).or_insert(Bucket {
+			tokens: rate.burst,
+			updated: now,
+		});
+
+		bucket.tokens = rate.refilled(bucket, now);
+		bucket.updated = now;
+
+		if bucket.tokens >= cost {
+			bucket.tokens -= cost;
+			Ok(())
+		} else {
+			Err(Duration::from_secs_f64(
+				(cost - bucket.tokens) / rate.per_second,
+			))
+		}
+	}
+}
+
+/// The address a client is limited as. IPv6 addresses are cut to their /64.
+fn limited_as(client: IpAddr) -> IpAddr {
+	match client.to_canonical() {
+		IpAddr::V6(v6) => {
+			let network = u128::from(v6) & !(u64::MAX as u128);
+			IpAddr::V6(Ipv6Addr::from(network))
+		}
+		v4 => v4,
+	}
+}
+
+/// Work out who we're talking to. If the connection came from a trusted proxy
+/// we believe its X-Forwarded-For, taking the rightmost address that isn't
+/// also a trusted proxy. Anything to the left of that could be made up.
+pub fn client_address(remote: IpAddr, forwarded: Option<&str>, trusted: &[IpAddr]) -> IpAddr {
+	if !trusted.contains(&remote) {
+		return remote;
+	}
+
+	let forwarded = match forwarded {
+		Some(forwarded) => forwarded,
+		None => return remote,
+	};
+
+	let mut client = remote;
+	for address in forwarded.rsplit(',') {
+		match address.trim().parse() {
+			Ok(address) => {
+				client = address;
+
+				if !trusted.contains(&address) {
+					break;
+				}
+			}
+			// A proxy we trust wrote garbage. Stop at the last good address.
+			Err(_) => break,
+		}
+	}
+
+	client
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn ip(s: &str) -> IpAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn bucket_empties_and_refills() {
+		let limiter = RateLimiter::new(60.0, 2.0);
+		let client = ip("192.0.2.1");
+		let now = Instant::now();
+
+		assert!(limiter.take_at(client, 1.0, now).is_ok());
+		assert!(limiter.take_at(client, 1.0, now).is_ok());
+		assert_eq!(
+			limiter.take_at(client, 1.0, now),
+			Err(Duration::from_secs(1))
+		);
+
+		// Someone else has their own bucket
+		assert!(limiter.take_at(ip("192.0.2.2"), 1.0, now).is_ok());
+
+		let later = now + Duration::from_secs(1);
+		assert!(limiter.take_at(client, 1.0, later).is_ok());
+	}
+
+	#[test]
+	fn ipv6_limited_by_network() {
+		let limiter = RateLimiter::new(60.0, 1.0);
+		let now = Instant::now();
+
+		assert!(limiter.take_at(ip("2001:db8::1"), 1.0, now).is_ok());
+		assert!(limiter.take_at(ip("2001:db8::2"), 1.0, now).is_err());
+		assert!(limiter.take_at(ip("2001:db8:0:1::1"), 1.0, now).is_ok());
+
+		// Mapped IPv4 addresses are the IPv4 client
+		assert!(limiter.take_at(ip("192.0.2.1"), 1.0, now).is_ok());
+		assert!(limiter.take_at(ip("::ffff:192.0.2.1"), 1.0, now).is_err());
+	}
+
+	#[test]
+	fn clients_are_capped() {
+		let limiter = RateLimiter::new(60.0, 1.0);
+		let start = Instant::now();
+		let client = |n: u32| IpAddr::from(std::net::Ipv4Addr::from(n));
+
+		// Nobody has refilled, so only the cap forgets anyone
+		for n in 0..MAX_CLIENTS as u32 + 1 {
+			let now = start + Duration::from_micros(n as u64);
+			assert!(limiter.take_at(client(n), 1.0, now).is_ok());
+		}
+
+		let buckets = limiter.buckets.lock().unwrap();
+		assert!(buckets.clients.len() <= MAX_CLIENTS);
+		assert!(!buckets.clients.contains_key(&client(0)));
+		assert!(buckets.clients.contains_key(&client(MAX_CLIENTS as u32)));
+	}
+
+	#[test]
+	fn pruned_now_and_then() {
+		let limiter = RateLimiter::new(60.0, 1.0);
+		let start = Instant::now();
+		let client = |n: u32| IpAddr::from(std::net::Ipv4Addr::from(n));
+
+		for n in 0..PRUNE_AT as u32 {
+			assert!(limiter.take_at(client(n), 1.0, start).is_ok());
+		}
+
+		// Everyone has refilled, but we looked too recently to look again
+		let refilled = start + Duration::from_secs(1);
+		assert!(limiter
+			.take_at(client(PRUNE_AT as u32), 1.0, refilled)
+			.is_ok());
+		assert_eq!(limiter.buckets.lock().unwrap().clients.len(), PRUNE_AT + 1);
+
+		let later = refilled + PRUNE_EVERY;
+		assert!(limiter
+			.take_at(client(PRUNE_AT as u32 + 1), 1.0, later)
+			.is_ok());
+		assert_eq!(limiter.buckets.lock().unwrap().clients.len(), 1);
+	}
+
+	#[test]
+	fn zero_rate_disables() {
+		let limiter = RateLimiter::new(0.0, 1.0);
+		let client = ip("192.0.2.1");
+
+		for _ in 0..100 {
+			assert!(limiter.take(client, 1.0).is_ok());
+		}
+	}
+
+	#[test]
+	fn client_from_trusted_proxy() {
+		let trusted = [ip("127.0.0.1"), ip("10.0.0.2")];
+		let forwarded = Some("203.0.113.9, 198.51.100.7, 10.0.0.2");
+
+		// Untrusted peers can't claim to be someone else
+		assert_eq!(
+			client_address(ip("192.0.2.1"), forwarded, &trusted),
+			ip("192.0.2.1")
+		);
+
+		assert_eq!(
+			client_address(ip("127.0.0.1"), forwarded, &trusted),
+			ip("198.51.100.7")
+		);
+
+		assert_eq!(
+			client_address(ip("127.0.0.1"), None, &trusted),
+			ip("127.0.0.1")
+		);
+
+		assert_eq!(
+			client_address(ip("127.0.0.1"), Some("nonsense, 10.0.0.2"), &trusted),
+			ip("10.0.0.2")
+		);
+	}
+}
@@ -0,0 +1,296 @@
+use hyper::Method;
+use percent_encoding::percent_decode_str;
+use thiserror::Error;
+
+/// Every route but the legacy ones lives under here.
+pub const PREFIX: &str = "/v1/";
+
+/// What a request is asking for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+	/// An image drawn from the query
+	Image(Page),
+	/// An HTML page with meta tags that embeds an image, for link previews
+	Card(Page),
+	/// An image drawn from a JSON operation in the body
+	Document,
+	/// The canonical query of a JSON operation in the body
+	Canonical,
+	/// The font families we know, filtered and a page at a time
+	Fonts,
+	/// One font family
+	Family(String),
+	/// An image showing off a font family
+	FontPreview(String),
+	Healthz,
+	Readyz,
+	Metrics,
+	Guide,
+	Ui,
+}
+
+/// Which image a route draws.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Page {
+	/// What the query describes
+	Render,
+	/// Information on the server
+	Info,
+	/// How the client appears to the server
+	Me,
+}
+
+impl Page {
+	/// Where the image is, relative to [PREFIX].
+	pub fn path(&self) -> &'static str {
+		match self {
+			Page::Render => "render.png",
+			Page::Info => "info",
+			Page::Me => "me",
+		}
+	}
+
+	/// The info and me pages aren't described by the operation, so signatures
+	/// for them are marked with their name.
+	pub fn signature_marker(&self) -> Option<&'static str> {
+		match self {
+			Page::Render => None,
+			Page::Info => Some("info"),
+			Page::Me => Some("me"),
+		}
+	}
+}
+
+/// The boolean parameters that used to decide what the root URL served.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Legacy {
+	/// Whether there was a query at all
+	pub query: bool,
+	pub forceraw: bool,
+	pub info: bool,
+	pub me: bool,
+}
+
+impl Legacy {
+	/// Which page the parameters picked. forceraw always drew the query, and
+	/// info wins over me.
+	pub fn page(&self) -> Page {
+		if self.forceraw {
+			Page::Render
+		} else if self.info {
+			Page::Info
+		} else if self.me {
+			Page::Me
+		} else {
+			Page::Render
+		}
+	}
+}
+
+impl Route {
+	/// What to call this route in metrics.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Route::Image(Page::Render) => "image",
+			Route::Image(Page::Info) => "info",
+			Route::Image(Page::Me) => "me",
+			Route::Card(Page::Render) => "card",
+			Route::Card(Page::Info) => "info_card",
+			Route::Card(Page::Me) => "me_card",
+			Route::Document => "document",
+			Route::Canonical => "canonical",
+			Route::Fonts => "fonts",
+			Route::Family(_) => "family",
+			Route::FontPreview(_) => "font_preview",
+			Route::Healthz => "healthz",
+			Route::Readyz => "readyz",
+			Route::Metrics => "metrics",
+			Route::Guide => "guide",
+			Route::Ui => "ui",
+		}
+	}
+
+	pub fn resolve(method: &Method, path: &str, legacy: &Legacy) -> Result<Self, RouteError> {
+		match path.strip_prefix(PREFIX) {
+			Some(rest) => Self::versioned(method, rest),
+			None => Self::legacy(method, path, legacy),
+		}
+	}
+
+	fn versioned(method: &Method, path: &str) -> Result<Self, RouteError> {
+		let route = match (method, path) {
+			(&Method::GET, "card") => Route::Card(Page::Render),
+			(&Method::GET, "info") => Route::Image(Page::Info),
+			(&Method::GET, "me") => Route::Image(Page::Me),
+			(&Method::GET, "fonts") => Route::Fonts,
+			(&Method::GET, "healthz") => Route::Healthz,
+			(&Method::GET, "readyz") => Route::Readyz,
+			(&Method::GET, "metrics") => Route::Metrics,
+			(&Method::GET, "guide") | (&Method::GET, "") => Route::Guide,
+			(&Method::GET, "ui") => Route::Ui,
+			(&Method::POST, "render") | (&Method::POST, "render.png") => Route::Document,
+			(&Method::POST, "canonical") => Route::Canonical,
+			(&Method::GET, fonts) if fonts.starts_with("fonts/") => {
+				let rest = fonts.trim_start_matches("fonts/");
+				let (family, preview) = match rest.strip_suffix("/preview") {
+					Some(family) => (family, true),
+					None => (rest, false),
+				};
+
+				let family = percent_decode_str(family)
+					.decode_utf8()
+					.map_err(|_| RouteError::NotFound(format!("{}{}", PREFIX, path)))?
+					.into_owned();
+				if family.is_empty() || family.contains('/') {
+					return Err(RouteError::NotFound(format!("{}{}", PREFIX, path)));
+				}
+
+				if preview {
+					Route::FontPreview(family)
+				} else {
+					Route::Family(family)
+				}
+			}
+			(&Method::GET, render) if render.starts_with("render.") => {
+				match render.trim_start_matches("render.") {
+					"png" => Route::Image(Page::Render),
+					format => return Err(RouteError::UnsupportedFormat(format.into())),
+				}
+			}
+			_ => return Err(RouteError::NotFound(format!("{}{}", PREFIX, path))),
+		};
+
+		Ok(route)
+	}
+
+	/// Everything outside of [PREFIX] is how the server worked before it had
+	/// routes. Anything with "ui" in it is the UI, POSTs are documents, and
+	/// everything else is decided by the query. The exceptions are /metrics
+	/// and the health checks, where scrapers and orchestrators expect them.
+	fn legacy(method: &Method, path: &str, legacy: &Legacy) -> Result<Self, RouteError> {
+		if method == Method::POST {
+			return match path {
+				"/render" => Ok(Route::Document),
+				"/canonical" => Ok(Route::Canonical),
+				_ => Err(RouteError::NotFound(path.into())),
+			};
+		}
+
+		let route = if path == "/metrics" {
+			Route::Metrics
+		} else if path == "/healthz" {
+			Route::Healthz
+		} else if path == "/readyz" {
+			Route::Readyz
+		} else if path.contains("ui") {
+			Route::Ui
+		} else if !legacy.query {
+			Route::Guide
+		} else if legacy.forceraw {
+			Route::Image(Page::Render)
+		} else {
+			Route::Card(legacy.page())
+		};
+
+		Ok(route)
+	}
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RouteError {
+	#[error("Nothing is at {0}")]
+	NotFound(String),
+	#[error("Images can be rendered as png, not {0}")]
+	UnsupportedFormat(String),
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn get(path: &str) -> Result<Route, RouteError> {
+		Route::resolve(&Method::GET, path, &Legacy::default())
+	}
+
+	#[test]
+	fn versioned_routes() {
+		assert_eq!(get("/v1/render.png"), Ok(Route::Image(Page::Render)));
+		assert_eq!(get("/v1/card"), Ok(Route::Card(Page::Render)));
+		assert_eq!(get("/v1/info"), Ok(Route::Image(Page::Info)));
+		assert_eq!(get("/v1/guide"), Ok(Route::Guide));
+		assert_eq!(
+			Route::resolve(&Method::POST, "/v1/render", &Legacy::default()),
+			Ok(Route::Document)
+		);
+
+		assert_eq!(
+			get("/v1/render.svg"),
+			Err(RouteError::UnsupportedFormat("svg".into()))
+		);
+	}
+
+	#[test]
+	fn font_routes() {
+		assert_eq!(get("/v1/fonts"), Ok(Route::Fonts));
+		assert_eq!(
+			get("/v1/fonts/Open%20Sans"),
+			Ok(Route::Family("Open Sans".into()))
+		);
+		assert_eq!(
+			get("/v1/fonts/Dosis/preview"),
+			Ok(Route::FontPreview("Dosis".into()))
+		);
+		assert!(get("/v1/fonts/").is_err());
+		assert!(get("/v1/fonts/Dosis/other").is_err());
+		assert_eq!(
+			get("/v1/nothing"),
+			Err(RouteError::NotFound("/v1/nothing".into()))
+		);
+		assert_eq!(
+			Route::resolve(&Method::POST, "/v1/card", &Legacy::default()),
+			Err(RouteError::NotFound("/v1/card".into()))
+		);
+	}
+
+	#[test]
+	fn legacy_routes() {
+		let query = Legacy {
+			query: true,
+			..Legacy::default()
+		};
+
+		assert_eq!(get("/"), Ok(Route::Guide));
+		assert_eq!(get("/ui"), Ok(Route::Ui));
+		assert_eq!(get("/metrics"), Ok(Route::Metrics));
+		assert_eq!(get("/readyz"), Ok(Route::Readyz));
+		assert_eq!(
+			Route::resolve(&Method::GET, "/", &query),
+			Ok(Route::Card(Page::Render))
+		);
+
+		let forceraw = Legacy {
+			forceraw: true,
+			info: true,
+			..query
+		};
+		assert_eq!(
+			Route::resolve(&Method::GET, "/", &forceraw),
+			Ok(Route::Image(Page::Render))
+		);
+
+		let both = Legacy {
+			info: true,
+			me: true,
+			..query
+		};
+		assert_eq!(
+			Route::resolve(&Method::GET, "/", &both),
+			Ok(Route::Card(Page::Info))
+		);
+
+		assert_eq!(
+			Route::resolve(&Method::POST, "/canonical", &query),
+			Ok(Route::Canonical)
+		);
+	}
+}
@@ -0,0 +1,148 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs and verifies render links with a shared secret, so only someone who
+/// knows it can mint links to our instance.
+pub struct Signer {
+	mac: HmacSha256,
+}
+
+impl Signer {
+	pub fn new<S: AsRef<[u8]>>(secret: S) -> Self {
+		Self {
+			mac: HmacSha256::new_from_slice(secret.as_ref()).expect("HMAC accepts any key length"),
+		}
+	}
+
+	/// Sign what a request will do, and when it expires if it ever does.
+	/// Returns the hex encoded signature.
+	pub fn sign(&self, signed: &str, expires: Option<u64>) -> String {
+		self.mac(signed, expires)
+			.finalize()
+			.into_bytes()
+			.iter()
+			.map(|byte| format!("{:02x}", byte))
+			.collect()
+	}
+
+	/// Check a request's `sig` and `exp` parameters. `now` is in seconds since
+	/// the unix epoch.
+	pub fn verify(
+		&self,
+		signed: &str,
+		expires: Option<&str>,
+		signature: Option<&str>,
+		now: u64,
+	) -> Result<(), SignatureError> {
+		let signature = signature.ok_or(SignatureError::Missing)?;
+		let signature = hex_decode(signature).ok_or(SignatureError::Malformed)?;
+
+		let expires = match expires {
+			None => None,
+			Some(exp) => Some(exp.parse::<u64>().map_err(|_| SignatureError::Malformed)?),
+		};
+
+		// Check the signature first so we don't say a forged link has expired
+		self.mac(signed, expires)
+			.verify_slice(&signature)
+			.map_err(|_| SignatureError::Invalid)?;
+
+		match expires {
+			Some(exp) if exp < now => Err(SignatureError::Expired),
+			_ => Ok(()),
+		}
+	}
+
+	fn mac(&self, signed: &str, expires: Option<u64>) -> HmacSha256 {
+		let mut mac = self.mac.clone();
+		mac.update(signed.as_bytes());
+
+		if let Some(exp) = expires {
+			mac.update(format!("\nexp={}", exp).as_bytes());
+		}
+
+		mac
+	}
+}
+
+fn hex_decode(string: &str) -> Option<Vec<u8>> {
+	string
+		.as_bytes()
+		.chunks(2)
+		.map(|pair| match pair {
+			[_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+			_ => None,
+		})
+		.collect()
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum SignatureError {
+	#[error("This link must be signed")]
+	Missing,
+	#[error("The signature or expiry is malformed")]
+	Malformed,
+	#[error("The signature does not match")]
+	Invalid,
+	#[error("This link has expired")]
+	Expired,
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn sign_and_verify() {
+		let signer = Signer::new("secret");
+		let sig = signer.sign("text=Hi", None);
+
+		assert_eq!(signer.verify("text=Hi", None, Some(&sig), 0), Ok(()));
+		assert_eq!(
+			signer.verify("text=Bye", None, Some(&sig), 0),
+			Err(SignatureError::Invalid)
+		);
+		assert_eq!(
+			signer.verify("text=Hi", None, None, 0),
+			Err(SignatureError::Missing)
+		);
+		assert_eq!(
+			signer.verify("text=Hi", None, Some("xyz"), 0),
+			Err(SignatureError::Malformed)
+		);
+
+		let other = Signer::new("not the secret");
+		assert_eq!(
+			other.verify("text=Hi", None, Some(&sig), 0),
+			Err(SignatureError::Invalid)
+		);
+	}
+
+	#[test]
+	fn expiry() {
+		let signer = Signer::new("secret");
+		let sig = signer.sign("text=Hi", Some(100));
+
+		assert_eq!(
+			signer.verify("text=Hi", Some("100"), Some(&sig), 50),
+			Ok(())
+		);
+		assert_eq!(
+			signer.verify("text=Hi", Some("100"), Some(&sig), 150),
+			Err(SignatureError::Expired)
+		);
+
+		// Pushing the expiry back breaks the signature
+		assert_eq!(
+			signer.verify("text=Hi", Some("200"), Some(&sig), 150),
+			Err(SignatureError::Invalid)
+		);
+		assert_eq!(
+			signer.verify("text=Hi", None, Some(&sig), 150),
+			Err(SignatureError::Invalid)
+		);
+	}
+}
@@ -1,25 +1,62 @@
 use std::{
-	borrow::BorrowMut, convert::TryFrom, fmt::Display, ops::DerefMut, str::FromStr, sync::Arc,
+	borrow::BorrowMut,
+	convert::TryFrom,
+	fmt::Display,
+	ops::DerefMut,
+	str::FromStr,
+	sync::Arc,
+	time::{Duration, Instant},
 };
 
-use fontster::{
-	Font, GlyphPosition, HorizontalAlign, Layout, LayoutSettings, LineHeight, StyledText,
-};
+use fontster::{Font, HorizontalAlign, Layout, LayoutSettings, LineHeight, StyledText};
 use mavourings::query::{Parameter, Query};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::RwLock;
+use tracing::{debug_span, Instrument};
 
 use crate::{
 	color::Color,
-	fontprovider::{FontStyle, FontVariant, FontWeight},
-	image::{ColorProvider, Colors, Image, Mask, Stripes},
+	fontprovider::{
+		FontCounts, FontError, FontStyle, FontVariant, FontVariantParseError, FontWeight, Lookup,
+	},
+	image::{ColorProvider, Colors, Image, Mask, Scaled, Stripes},
+	raster::{GlyphCounts, RasterGlyph, Rasterizer},
 	FontProvider,
 };
 
 #[derive(Clone)]
 pub enum Visual {
 	Color(Color),
-	Pattern(Arc<dyn ColorProvider>),
+	/// A pattern along with the name and font size it was made from, so we
+	/// can describe it again.
+	Pattern {
+		name: String,
+		fontsize: f32,
+		provider: Arc<dyn ColorProvider>,
+	},
+}
+
+impl Visual {
+	/// Scale a pattern for rendering at `dpr` device pixels per logical pixel.
+	/// Solid colours are the same at any density.
+	fn scaled(&self, dpr: u8) -> Visual {
+		match self {
+			Visual::Pattern {
+				name,
+				fontsize,
+				provider,
+			} if dpr > 1 => Visual::Pattern {
+				name: name.clone(),
+				fontsize: *fontsize,
+				provider: Arc::new(Scaled {
+					provider: provider.clone(),
+					scale: dpr as usize,
+				}),
+			},
+			visual => visual.clone(),
+		}
+	}
 }
 
 impl From<Color> for Visual {
@@ -40,8 +77,15 @@ impl FontFace {
 	}
 }
 
+/// What a font preview says after the family's name.
+const SPECIMEN: &str = "The quick brown fox jumps over the lazy dog\n\
+	ABCDEFGHIJKLMNOPQRSTUVWXYZ\n\
+	abcdefghijklmnopqrstuvwxyz\n\
+	0123456789 !?&@.,;:'\"()";
+
 /// A `text` parameter.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "TextDocument", try_from = "TextDocument")]
 pub struct Text {
 	pub text: String,
 	pub font: Option<String>,
@@ -67,17 +111,28 @@ impl Default for Text {
 }
 
 impl Text {
-	async fn get_font(&self, fp: &RwLock<FontProvider>) -> Arc<Font> {
+	async fn get_font(
+		&self,
+		fp: &RwLock<FontProvider>,
+		counts: &mut FontCounts,
+	) -> Result<Arc<Font>, FontError> {
 		if let Some(font) = self.font.as_deref() {
 			let varient = self.font_variant();
 
-			return {
-				let mut provider = fp.write().await;
-				provider.variant(font, varient)
-			};
+			let span = debug_span!("font", family = font, variant = %varient);
+			return async {
+				// Let go of the provider before downloading so nobody waits on us
+				let lookup = fp.read().await.variant(font, varient, counts)?;
+				match lookup {
+					Lookup::Found(font) => Ok(font),
+					Lookup::Load(load) => load.fetch(counts).await,
+				}
+			}
+			.instrument(span)
+			.await;
 		}
 
-		fp.read().await.default_font()
+		Ok(fp.read().await.default_font())
 	}
 
 	pub fn font_variant(&self) -> FontVariant {
@@ -88,7 +143,8 @@ impl Text {
 	}
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "OperationDocument", try_from = "OperationDocument")]
 pub struct Operation {
 	pub bvisual: Visual,
 	pub texts: Vec<Text>,
@@ -96,6 +152,9 @@ pub struct Operation {
 	pub padding: usize,
 	pub align: HorizontalAlign,
 	pub forceraw: bool,
+	/// Device pixels per logical pixel. Sizes are multiplied by this when
+	/// rendering so the image is sharp on high density screens.
+	pub dpr: u8,
 	pub aspect: Option<f32>,
 	pub outline: bool,
 	pub glyph_outline: bool,
@@ -112,6 +171,7 @@ impl Default for Operation {
 			padding: 32,
 			align: HorizontalAlign::Left,
 			forceraw: false,
+			dpr: 1,
 			aspect: None,
 			outline: false,
 			glyph_outline: false,
@@ -121,17 +181,73 @@ impl Default for Operation {
 	}
 }
 
+/// Bounds on how much work a single render may do. Everything but the time
+/// is checked before we allocate the image.
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+	/// Most pixels the image may have, after dpr is applied
+	pub max_pixels: usize,
+	/// Most pixels the image may be on either side, after dpr is applied
+	pub max_dimension: usize,
+	/// Most characters across every text
+	pub max_glyphs: usize,
+	pub max_texts: usize,
+	/// Largest font size in logical pixels
+	pub max_font_size: f32,
+	/// How long we may spend rendering. Time spent getting fonts doesn't count.
+	pub render_time: Duration,
+}
+
+impl Default for Limits {
+	fn default() -> Self {
+		Self {
+			max_pixels: 4096 * 4096,
+			max_dimension: 8192,
+			max_glyphs: 4096,
+			max_texts: 64,
+			max_font_size: 1024.0,
+			render_time: Duration::from_secs(5),
+		}
+	}
+}
+
+/// How the glyph and font caches did during a render.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RenderCounts {
+	pub glyphs: GlyphCounts,
+	pub fonts: FontCounts,
+}
+
 impl Operation {
-	pub async fn make_image(self, fp: &RwLock<FontProvider>) -> Image {
+	/// Render the image. Also returns how many glyphs and fonts we found in
+	/// their caches.
+	pub async fn make_image(
+		self,
+		fp: &RwLock<FontProvider>,
+		rasterizer: &Rasterizer,
+		limits: &Limits,
+	) -> Result<(Image, RenderCounts), RenderError> {
+		self.check_limits(limits)?;
+		let mut counts = RenderCounts::default();
+		let mut deadline = Instant::now() + limits.render_time;
+
 		let mut fonts: Vec<(FontFace, Arc<Font>)> = vec![];
+		let scale = self.dpr as f32;
+		let padding = self.padding.saturating_mul(self.dpr as usize);
 
 		let settings = LayoutSettings {
 			horizontal_align: self.align,
 			line_height: self.line_height,
 		};
 
+		// Only entered while laying out so font fetches don't count against it
+		let layout_span = debug_span!("layout", texts = self.texts.len());
 		let mut layout = Layout::new(settings);
 		for text in &self.texts {
+			if text.text.is_empty() {
+				continue;
+			}
+
 			let fontface =
 				FontFace::new(text.font.clone().unwrap_or_default(), text.font_variant());
 
@@ -152,83 +268,179 @@ impl Operation {
 			{
 				Some(i) => i,
 				None => {
-					fonts.push((fontface, text.get_font(fp).await));
+					let fetch = Instant::now();
+					fonts.push((fontface, text.get_font(fp, &mut counts.fonts).await?));
+					deadline += fetch.elapsed();
 
 					fonts.len() - 1
 				}
 			};
 
-			if text.text.len() == 0 {
-				continue;
-			}
-
-			layout.append(
-				&fonts
-					.iter()
-					.map(|(_face, font)| font.clone())
-					.collect::<Vec<Arc<Font>>>(),
-				StyledText {
-					text: text.text.as_str(),
-					font_size: text.fontsize,
-					font_index: index,
-					user: text.visual.clone(),
-				},
-			);
+			layout_span.in_scope(|| {
+				layout.append(
+					&fonts
+						.iter()
+						.map(|(_face, font)| font.clone())
+						.collect::<Vec<Arc<Font>>>(),
+					StyledText {
+						text: text.text.as_str(),
+						font_size: text.fontsize * scale,
+						font_index: index,
+						user: text.visual.scaled(self.dpr),
+					},
+				)
+			});
 		}
+		drop(layout_span);
 
 		let (horizontal_pad, vertical_pad) = if let Some(ratio) = self.aspect {
 			let current_ratio = layout.width() / layout.height();
 
 			if ratio > current_ratio {
 				// we're too tall! pad the width.
-				let needed_padding = (((layout.height() + self.padding as f32) * ratio)
-					- layout.width())
-				.ceil() as usize;
+				let needed_padding =
+					(((layout.height() + padding as f32) * ratio) - layout.width()).ceil() as usize;
 
-				if needed_padding < self.padding {
+				if needed_padding < padding {
 					// the added padding is less than the desired. We can't set
 					// the needed to the desired our we'd overshoot
-					(needed_padding, needed_padding - self.padding)
+					(needed_padding, needed_padding.saturating_sub(padding))
 				} else {
-					(needed_padding, self.padding)
+					(needed_padding, padding)
 				}
 			} else if ratio < current_ratio {
 				// we're too wide! pad the height
-				let needed_padding = (((layout.width() + self.padding as f32) / ratio)
-					- layout.height())
-				.ceil() as usize;
+				let needed_padding =
+					(((layout.width() + padding as f32) / ratio) - layout.height()).ceil() as usize;
 
-				if needed_padding < self.padding {
-					(needed_padding - self.padding, needed_padding)
+				if needed_padding < padding {
+					(needed_padding.saturating_sub(padding), needed_padding)
 				} else {
-					(self.padding, needed_padding)
+					(padding, needed_padding)
 				}
 			} else {
-				(self.padding, self.padding)
+				(padding, padding)
 			}
 		} else {
-			(self.padding, self.padding)
+			(padding, padding)
 		};
 
 		let fonts: Vec<Arc<Font>> = fonts.iter().map(|t| t.1.clone()).collect();
-		let width = layout.width().ceil() as usize + horizontal_pad;
-		let height = layout.height().ceil() as usize + vertical_pad;
-		let mut image = match &self.bvisual {
+		let width = (layout.width().ceil() as usize).saturating_add(horizontal_pad);
+		let height = (layout.height().ceil() as usize).saturating_add(vertical_pad);
+		Self::check_size(width, height, limits)?;
+
+		let _rasterize = debug_span!("rasterize", width, height).entered();
+		let mut image = match &self.bvisual.scaled(self.dpr) {
 			Visual::Color(c) => Image::with_color(width, height, *c),
-			Visual::Pattern(p) => Image::from_provider(width, height, 0, 0, p.as_ref()),
+			Visual::Pattern { provider, .. } => {
+				Image::from_provider(width, height, 0, 0, provider.as_ref())
+			}
 		};
 
 		let off_x = horizontal_pad as isize / 2;
 		let off_y = vertical_pad as isize / 2;
 		for glyph in layout.glyphs() {
-			let x = glyph.x as isize + off_x;
-			let y = glyph.y as isize + off_y;
+			if Instant::now() > deadline {
+				return Err(RenderError::Timeout(limits.render_time));
+			}
+
+			let (x, subpixel) = rasterizer.position(glyph.x, glyph.font_size);
+			let x = x + off_x;
+			let y = if rasterizer.hinted(glyph.font_size) {
+				glyph.y.round() as isize + off_y
+			} else {
+				glyph.y as isize + off_y
+			};
+
+			let raster = rasterizer.glyph(
+				&fonts[glyph.font_index],
+				glyph.c,
+				glyph.font_size,
+				subpixel,
+				&mut counts.glyphs,
+			);
+
+			let glyph = self.glyph(&raster, glyph.user, x, y);
+			image.draw_img(glyph, x, y, rasterizer.gamma());
+		}
+
+		Ok((image, counts))
+	}
+
+	/// Check what we can before doing any layout.
+	fn check_limits(&self, limits: &Limits) -> Result<(), RenderError> {
+		// Empty texts are skipped when rendering, so they don't count
+		let texts = self.texts.iter().filter(|t| !t.text.is_empty()).count();
+		if texts > limits.max_texts {
+			return Err(RenderError::TooManyTexts {
+				count: texts,
+				max: limits.max_texts,
+			});
+		}
+
+		let glyphs: usize = self.texts.iter().map(|t| t.text.chars().count()).sum();
+		if glyphs > limits.max_glyphs {
+			return Err(RenderError::TooManyGlyphs {
+				count: glyphs,
+				max: limits.max_glyphs,
+			});
+		}
+
+		for text in &self.texts {
+			if text.fontsize.is_nan() || text.fontsize > limits.max_font_size {
+				return Err(RenderError::FontTooLarge {
+					size: text.fontsize,
+					max: limits.max_font_size,
+				});
+			}
+		}
+
+		// Padding alone past the largest image we draw can only fail later
+		let padding = self.padding.saturating_mul(self.dpr as usize);
+		if padding > limits.max_dimension {
+			return Err(RenderError::PaddingTooLarge {
+				padding,
+				max: limits.max_dimension,
+			});
+		}
+
+		Ok(())
+	}
+
+	fn check_size(width: usize, height: usize, limits: &Limits) -> Result<(), RenderError> {
+		let too_many_pixels = width
+			.checked_mul(height)
+			.map(|pixels| pixels > limits.max_pixels)
+			.unwrap_or(true);
+
+		if width > limits.max_dimension || height > limits.max_dimension || too_many_pixels {
+			return Err(RenderError::TooLarge {
+				width,
+				height,
+				max_dimension: limits.max_dimension,
+				max_pixels: limits.max_pixels,
+			});
+		}
+
+		Ok(())
+	}
+
+	/// Every font and variant this operation draws text in.
+	pub fn fonts(&self) -> Vec<(&str, FontVariant)> {
+		let mut fonts: Vec<(&str, FontVariant)> = vec![];
 
-			let glyph = self.glyph(&fonts, glyph, off_x, off_y);
-			image.draw_img(glyph, x, y);
+		for text in self.texts.iter().filter(|t| !t.text.is_empty()) {
+			if let Some(font) = text.font.as_deref() {
+				let face = (font, text.font_variant());
+
+				if !fonts.contains(&face) {
+					fonts.push(face);
+				}
+			}
 		}
 
-		image
+		fonts
 	}
 
 	/// Get all the text that will be rendered for this query.
@@ -242,34 +454,92 @@ impl Operation {
 		ret
 	}
 
-	//todo: pass glyph an offset so we can align the pattern (gen 2020-03: what does this mean)
-	/// Renders a single glyph
-	fn glyph(
-		&self,
-		fonts: &[Arc<Font>],
-		glyph: GlyphPosition<Visual>,
-		off_x: isize,
-		off_y: isize,
-	) -> Image {
-		let font = &fonts[glyph.font_index];
-		let (metrics, raster) = font.rasterize(glyph.c, glyph.font_size);
-
-		match glyph.user {
+	/// Describe this operation as a query string. Operations that render the
+	/// same image describe themselves the same way no matter how they were
+	/// written, so this is safe to use as a cache key.
+	pub fn canonical_query(&self) -> String {
+		let mut params: Vec<(&str, String)> = vec![];
+
+		let align = match self.align {
+			HorizontalAlign::Center => "center",
+			HorizontalAlign::Right => "right",
+			_ => "left",
+		};
+		params.push(("align", align.into()));
+
+		if let Some(aspect) = self.aspect {
+			params.push(("aspect", aspect.to_string()));
+		}
+
+		params.push(("pad", self.padding.to_string()));
+		params.push(("lh", Self::line_height_string(self.line_height)));
+
+		if self.dpr != Self::default().dpr {
+			params.push(("dpr", self.dpr.to_string()));
+		}
+
+		match &self.bvisual {
+			Visual::Color(c) => params.push(("bc", c.as_hex())),
+			Visual::Pattern { name, fontsize, .. } => {
+				params.push(("fs", fontsize.to_string()));
+				params.push(("bpattern", name.clone()));
+			}
+		}
+
+		// Only texts that draw something matter. The last text is always the
+		// state left over after the final text parameter.
+		for text in self.texts.iter().filter(|t| !t.text.is_empty()) {
+			if let Some(font) = &text.font {
+				params.push(("font", font.clone()));
+			}
+
+			params.push(("weight", text.font_weight.unwrap_or_default().to_string()));
+			params.push(("style", text.font_style.unwrap_or_default().to_string()));
+
+			match &text.visual {
+				Visual::Color(c) => params.push(("c", c.as_hex())),
+				Visual::Pattern { name, fontsize, .. } => {
+					// Patterns are sized by the font size when they were set.
+					params.push(("fs", fontsize.to_string()));
+					params.push(("pattern", name.clone()));
+				}
+			}
+
+			params.push(("fs", text.fontsize.to_string()));
+			params.push(("text", text.text.clone()));
+		}
+
+		params
+			.into_iter()
+			.map(|(key, value)| format!("{}={}", key, Query::url_encode(&value)))
+			.collect::<Vec<String>>()
+			.join("&")
+	}
+
+	fn line_height_string(line_height: LineHeight) -> String {
+		match line_height {
+			LineHeight::Font => "font".into(),
+			LineHeight::Ratio(ratio) => format!("ratio {}", ratio),
+			LineHeight::Smallest(ratio) => format!("min {}", ratio),
+		}
+	}
+
+	/// Colours a single rasterized glyph. `x` and `y` are where it will be
+	/// drawn so that patterns line up across glyphs.
+	fn glyph(&self, raster: &RasterGlyph, visual: Visual, x: isize, y: isize) -> Image {
+		match visual {
 			Visual::Color(c) => Image::from_buffer(
-				metrics.width,
-				metrics.height,
-				raster,
+				raster.width,
+				raster.height,
+				raster.coverage.clone(),
 				Colors::GreyAsAlpha(c),
 			),
-			Visual::Pattern(arcpat) => {
-				let mut mask = Mask::new(metrics.width, metrics.height);
-				let x = glyph.x.ceil() as isize + off_x;
-				let y = glyph.y.ceil() as isize + off_y;
-
-				mask.set_from_buf(metrics.width, metrics.height, &raster, 0, 0);
+			Visual::Pattern { provider, .. } => {
+				let mut mask = Mask::new(raster.width, raster.height);
+				mask.set_from_buf(raster.width, raster.height, &raster.coverage, 0, 0);
 
 				let mut pattern =
-					Image::from_provider(metrics.width, metrics.height, x, y, arcpat.as_ref());
+					Image::from_provider(raster.width, raster.height, x, y, provider.as_ref());
 				pattern.mask(mask, 0, 0);
 
 				pattern
@@ -346,22 +616,14 @@ impl Operation {
 		None
 	}
 
-	fn color_or<S: AsRef<str>>(string: Option<S>, color: Color) -> Color {
-		if let Some(string) = string {
-			Self::color(string).unwrap_or(color)
-		} else {
-			color
-		}
-	}
-
-	fn pattern<P: AsRef<str>>(fontsize: f32, string: P) -> Option<Visual> {
-		match string.as_ref() {
-			"trans" => Some(Visual::Pattern(Arc::new(Stripes {
+	fn pattern<P: AsRef<str>>(fontsize: f32, string: P) -> Result<Visual, String> {
+		let stripes = match string.as_ref() {
+			"trans" => Stripes {
 				colors: vec![(85, 205, 252).into(), Color::WHITE, (247, 168, 184).into()],
 				stripe_width: (fontsize / 8.0) as usize,
 				slope: 2.0,
-			}))),
-			"enby" => Some(Visual::Pattern(Arc::new(Stripes {
+			},
+			"enby" => Stripes {
 				colors: vec![
 					(255, 244, 48).into(),
 					Color::WHITE,
@@ -370,8 +632,8 @@ impl Operation {
 				],
 				stripe_width: (fontsize / 8.0) as usize,
 				slope: 2.0,
-			}))),
-			"sappho" => Some(Visual::Pattern(Arc::new(Stripes {
+			},
+			"sappho" => Stripes {
 				colors: vec![
 					(213, 45, 0).into(),
 					(239, 118, 39).into(),
@@ -383,33 +645,46 @@ impl Operation {
 				],
 				stripe_width: (fontsize / 8.0) as usize,
 				slope: 2.0,
-			}))),
-			"ace" => Some(Visual::Pattern(Arc::new(Stripes {
+			},
+			"ace" => Stripes {
 				colors: vec![
 					Color::BLACK,
-					Self::color("7f7f7f").unwrap(),
+					(127, 127, 127).into(),
 					Color::WHITE,
-					Self::color("64349A").unwrap(),
+					(100, 52, 154).into(),
 				],
 				stripe_width: (fontsize / 8.0) as usize,
 				slope: 2.0,
-			}))),
+			},
 			str if str.starts_with("stripe:") => {
-				//FIXME: This is weird and silently discards invalid colors
-				let joined_colors = str.strip_prefix("stripe:").unwrap();
-				let colors: Vec<Color> = joined_colors
-					.split(":")
-					.filter_map(|s| Self::color(s))
-					.collect();
-
-				Some(Visual::Pattern(Arc::new(Stripes {
+				let joined_colors = &str["stripe:".len()..];
+				let mut colors: Vec<Color> = vec![];
+
+				for color in joined_colors.split(':') {
+					match Self::color(color) {
+						Some(color) => colors.push(color),
+						None => return Err(format!("stripe colour '{}' is invalid", color)),
+					}
+				}
+
+				Stripes {
 					colors,
 					stripe_width: (fontsize / 8.0) as usize,
 					slope: 2.0,
-				})))
+				}
 			}
-			_ => None,
-		}
+			_ => {
+				return Err(
+					"must be trans, enby, sappho, ace, or stripe: followed by colours".into(),
+				)
+			}
+		};
+
+		Ok(Visual::Pattern {
+			name: string.as_ref().to_owned(),
+			fontsize,
+			provider: Arc::new(stripes),
+		})
 	}
 
 	fn line_height<H: AsRef<str>>(height: H) -> Option<LineHeight> {
@@ -434,24 +709,63 @@ impl Operation {
 		}
 	}
 
-	fn push_parameter(&mut self, parameter: Parameter) {
+	/// Build an operation from a query, collecting a [Problem] for every
+	/// parameter we couldn't understand. Bad parameters are ignored, or fall
+	/// back to a default, so the operation is always usable.
+	pub fn parse(query: Query) -> (Self, Vec<Problem>) {
+		let mut ret = Self::default();
+		let mut problems = vec![];
+
+		for param in query.into_iter() {
+			if let Err(problem) = ret.push_parameter(param) {
+				problems.push(problem);
+			}
+		}
+
+		(ret, problems)
+	}
+
+	fn push_parameter(&mut self, parameter: Parameter) -> Result<(), Problem> {
 		match parameter {
-			Parameter::Bool(name) => self.parse_bool(name),
-			Parameter::Value(key, value) => self.parse_value(key, value),
+			Parameter::Bool(name) => self.parse_bool(&name).map_err(|reason| Problem {
+				parameter: name,
+				value: String::new(),
+				reason,
+			}),
+			Parameter::Value(key, value) => match self.parse_value(&key, value.clone()) {
+				Ok(()) => Ok(()),
+				Err(reason) => Err(Problem {
+					parameter: key,
+					value,
+					reason,
+				}),
+			},
 		}
 	}
 
-	fn parse_bool<S: AsRef<str>>(&mut self, name: S) {
-		match name.as_ref() {
+	fn parse_bool(&mut self, name: &str) -> Result<(), String> {
+		match name {
 			"forceraw" => self.forceraw = true,
-			_ => (),
+			// These are handled by the server before we get here
+			"info" | "me" | "strict" => (),
+			_ => return Err("unknown parameter".into()),
 		}
+
+		Ok(())
 	}
 
-	fn parse_value(&mut self, key: String, value: String) {
-		let current = self.texts.last_mut().unwrap();
+	fn parse_value(&mut self, key: &str, value: String) -> Result<(), String> {
+		if self.texts.is_empty() {
+			self.texts.push(Text::default());
+		}
+		let current = match self.texts.last_mut() {
+			Some(current) => current,
+			None => return Err("there is no text to apply this to".into()),
+		};
 
-		match key.as_str() {
+		match key {
+			// The signature is checked by the server before we get here
+			"sig" | "exp" => (),
 			"text" => {
 				let next = current.clone();
 				current.text = value;
@@ -459,47 +773,124 @@ impl Operation {
 				self.texts.push(next);
 			}
 			"font" => current.font = Some(value),
-			"weight" | "fontweight" => {
-				current.font_weight = value.parse().map(|v| Some(v)).unwrap_or(None)
-			}
-			"style" | "fontstyle" => {
-				current.font_style = value.parse().map(|v| Some(v)).unwrap_or(None)
-			}
-			"fs" | "fontsize" => {
-				let fs: FontSize = value.parse().unwrap();
-				current.fontsize = fs.pixels(16) as f32;
-			}
-			"c" | "color" | "colour" => {
-				current.visual = Visual::Color(Self::color_or(Some(value), Color::WHITE))
-			}
-			"pattern" => {
-				if let Some(pat) = Self::pattern(current.fontsize, value) {
-					current.visual = pat;
+			"weight" | "fontweight" => match value.parse() {
+				Ok(weight) => current.font_weight = Some(weight),
+				Err(e) => {
+					current.font_weight = None;
+					return Err(format!("{}", e));
 				}
-			}
+			},
+			"style" | "fontstyle" => match value.parse() {
+				Ok(style) => current.font_style = Some(style),
+				Err(e) => {
+					current.font_style = None;
+					return Err(format!("{}", e));
+				}
+			},
+			"fs" | "fontsize" => match value.parse::<FontSize>().map(|fs| fs.pixels(16)) {
+				Ok(pixels) if pixels > 0 => current.fontsize = pixels as f32,
+				_ => return Err("not a size in pixels or points (ex. 32 or 12pt)".into()),
+			},
+			"c" | "color" | "colour" => match Self::color(&value) {
+				Some(c) => current.visual = Visual::Color(c),
+				None => {
+					current.visual = Visual::Color(Color::WHITE);
+					return Err(Self::not_a_color());
+				}
+			},
+			"pattern" => current.visual = Self::pattern(current.fontsize, value)?,
 
 			"align" => match value.as_str() {
 				"center" => self.align = HorizontalAlign::Center,
 				"right" => self.align = HorizontalAlign::Right,
-				_ => self.align = HorizontalAlign::Left,
+				"left" => self.align = HorizontalAlign::Left,
+				_ => {
+					self.align = HorizontalAlign::Left;
+					return Err("must be left, center, or right".into());
+				}
 			},
-			"aspect" => self.aspect = value.parse().ok(),
-			"bc" | "bcolor" | "bcolour" => {
-				self.bvisual = Visual::Color(Self::color_or(Some(value), Color::WHITE))
-			}
-			"bpattern" => {
-				if let Some(pat) = Self::pattern(current.fontsize, value) {
-					self.bvisual = pat;
+			"aspect" => match value.parse::<f32>() {
+				Ok(aspect) if aspect.is_normal() && aspect > 0.0 => self.aspect = Some(aspect),
+				_ => {
+					self.aspect = None;
+					return Err("must be a positive number".into());
 				}
-			}
-			"pad" => self.padding = value.parse().unwrap_or(Self::default().padding),
-			"lh" | "lineheight" => {
-				self.line_height = Self::line_height(value).unwrap_or(Self::default().line_height)
-			}
-			_ => (),
+			},
+			"dpr" | "scale" => match value.parse() {
+				Ok(dpr) if (1..=3).contains(&dpr) => self.dpr = dpr,
+				_ => {
+					self.dpr = Self::default().dpr;
+					return Err("must be 1, 2, or 3".into());
+				}
+			},
+			"bc" | "bcolor" | "bcolour" => match Self::color(&value) {
+				Some(c) => self.bvisual = Visual::Color(c),
+				None => {
+					self.bvisual = Visual::Color(Color::WHITE);
+					return Err(Self::not_a_color());
+				}
+			},
+			"bpattern" => self.bvisual = Self::pattern(current.fontsize, value)?,
+			"pad" => match value.parse() {
+				Ok(pad) => self.padding = pad,
+				Err(_) => {
+					self.padding = Self::default().padding;
+					return Err("must be a whole number of pixels".into());
+				}
+			},
+			"lh" | "lineheight" => match Self::line_height(&value) {
+				Some(lh) => self.line_height = lh,
+				None => {
+					self.line_height = Self::default().line_height;
+					return Err("must be font, ratio #, or min #".into());
+				}
+			},
+			_ => return Err("unknown parameter".into()),
+		}
+
+		Ok(())
+	}
+
+	fn not_a_color() -> String {
+		"not a colour name or a hex colour without the #".into()
+	}
+
+	/// An operation that draws a plain message, used to tell people things
+	/// in an image.
+	pub fn message<S: Into<String>>(text: S) -> Self {
+		Self {
+			bvisual: Visual::Color((238, 238, 221).into()),
+			texts: vec![Text {
+				text: text.into(),
+				fontsize: 32.0,
+				visual: Visual::Color(Color::BLACK),
+				..Default::default()
+			}],
+			line_height: LineHeight::Font,
+			..Default::default()
 		}
 	}
 
+	/// A sample of a font: its name, a pangram, and the alphabet.
+	pub fn specimen<S: Into<String>>(family: S, variant: FontVariant) -> Self {
+		let family = family.into();
+		let text = |text: String, fontsize: f32| Text {
+			text,
+			font: Some(family.clone()),
+			font_weight: Some(variant.weight()),
+			font_style: Some(variant.style()),
+			fontsize,
+			visual: Visual::Color(Color::BLACK),
+		};
+
+		let mut op = Self::message("");
+		op.texts = vec![
+			text(format!("{}\n", family), 64.0),
+			text(SPECIMEN.into(), 32.0),
+		];
+		op
+	}
+
 	pub fn get_alt(&self) -> String {
 		match self.get_fonts_if_same() {
 			None | Some(None) => self.full_text(),
@@ -533,16 +924,274 @@ impl Operation {
 
 impl From<Query> for Operation {
 	fn from(query: Query) -> Self {
-		let mut ret = Self::default();
+		Self::parse(query).0
+	}
+}
 
-		for param in query.into_iter() {
-			ret.push_parameter(param);
+#[derive(Debug, Error)]
+pub enum RenderError {
+	#[error(transparent)]
+	Font(#[from] FontError),
+	#[error("There are {count} texts but we only draw {max}")]
+	TooManyTexts { count: usize, max: usize },
+	#[error("There are {count} characters but we only draw {max}")]
+	TooManyGlyphs { count: usize, max: usize },
+	#[error("A font size of {size} is larger than the maximum of {max}")]
+	FontTooLarge { size: f32, max: f32 },
+	#[error("A padding of {padding} pixels is larger than the maximum of {max}")]
+	PaddingTooLarge { padding: usize, max: usize },
+	#[error("The image would be {width}x{height} but we only draw {max_dimension} pixels on a side and {max_pixels} in total")]
+	TooLarge {
+		width: usize,
+		height: usize,
+		max_dimension: usize,
+		max_pixels: usize,
+	},
+	#[error("Rendering took longer than {0:?}")]
+	Timeout(Duration),
+}
+
+/// Something wrong with a query parameter.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+	pub parameter: String,
+	pub value: String,
+	pub reason: String,
+}
+
+impl Display for Problem {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if self.value.is_empty() {
+			write!(f, "{}: {}", self.parameter, self.reason)
+		} else {
+			write!(f, "{}={}: {}", self.parameter, self.value, self.reason)
 		}
+	}
+}
 
-		ret
+/// The JSON form of an [Operation]. Every field is optional.
+///
+/// ```json
+/// {
+///     "background": "eed",
+///     "align": "center",
+///     "aspect": 1.8,
+///     "padding": 32,
+///     "line_height": "min 1.05",
+///     "dpr": 2,
+///     "texts": [
+///         { "text": "Hello ", "font": "Dosis", "weight": "bold", "fontsize": 64, "color": "black" },
+///         { "text": "World", "fontsize": 64, "color": { "pattern": "trans" } }
+///     ]
+/// }
+/// ```
+///
+/// Values are written the same way they are in a query string. `line_height`
+/// is one of "font", "ratio #", or "min #".
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct OperationDocument {
+	background: VisualDocument,
+	texts: Vec<Text>,
+	align: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	aspect: Option<f32>,
+	padding: usize,
+	line_height: String,
+	dpr: u8,
+}
+
+impl Default for OperationDocument {
+	fn default() -> Self {
+		let mut doc: OperationDocument = Operation::default().into();
+		doc.texts = vec![];
+		doc
+	}
+}
+
+impl From<Operation> for OperationDocument {
+	fn from(op: Operation) -> Self {
+		let align = match op.align {
+			HorizontalAlign::Center => "center",
+			HorizontalAlign::Right => "right",
+			_ => "left",
+		};
+
+		Self {
+			background: op.bvisual.into(),
+			texts: op
+				.texts
+				.into_iter()
+				.filter(|t| !t.text.is_empty())
+				.collect(),
+			align: align.into(),
+			aspect: op.aspect,
+			padding: op.padding,
+			line_height: Operation::line_height_string(op.line_height),
+			dpr: op.dpr,
+		}
+	}
+}
+
+impl TryFrom<OperationDocument> for Operation {
+	type Error = DocumentError;
+
+	fn try_from(doc: OperationDocument) -> Result<Self, Self::Error> {
+		let align = match doc.align.as_str() {
+			"left" => HorizontalAlign::Left,
+			"center" => HorizontalAlign::Center,
+			"right" => HorizontalAlign::Right,
+			_ => return Err(DocumentError::Align(doc.align)),
+		};
+
+		let line_height = Operation::line_height(&doc.line_height)
+			.ok_or(DocumentError::LineHeight(doc.line_height))?;
+
+		if !(1..=3).contains(&doc.dpr) {
+			return Err(DocumentError::Dpr(doc.dpr));
+		}
+
+		// The same as the query, where aspect has to be above 0
+		if let Some(aspect) = doc.aspect {
+			if !(aspect.is_normal() && aspect > 0.0) {
+				return Err(DocumentError::Aspect(aspect));
+			}
+		}
+
+		Ok(Self {
+			bvisual: doc.background.into_visual(Text::default().fontsize)?,
+			texts: doc.texts,
+			line_height,
+			padding: doc.padding,
+			align,
+			aspect: doc.aspect,
+			dpr: doc.dpr,
+			..Default::default()
+		})
 	}
 }
 
+/// The JSON form of a [Text]. Every field but `text` is optional.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextDocument {
+	text: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	font: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	weight: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	style: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	fontsize: Option<f32>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	color: Option<VisualDocument>,
+}
+
+impl From<Text> for TextDocument {
+	fn from(text: Text) -> Self {
+		Self {
+			text: text.text,
+			font: text.font,
+			weight: text.font_weight.map(|w| w.to_string()),
+			style: text.font_style.map(|s| s.to_string()),
+			fontsize: Some(text.fontsize),
+			color: Some(text.visual.into()),
+		}
+	}
+}
+
+impl TryFrom<TextDocument> for Text {
+	type Error = DocumentError;
+
+	fn try_from(doc: TextDocument) -> Result<Self, Self::Error> {
+		let default = Text::default();
+		let fontsize = font_size(doc.fontsize.unwrap_or(default.fontsize))?;
+
+		Ok(Self {
+			text: doc.text,
+			font: doc.font,
+			font_weight: doc.weight.map(|w| w.parse()).transpose()?,
+			font_style: doc.style.map(|s| s.parse()).transpose()?,
+			fontsize,
+			visual: match doc.color {
+				Some(visual) => visual.into_visual(fontsize)?,
+				None => default.visual,
+			},
+		})
+	}
+}
+
+/// Either a colour string or a pattern. Patterns are sized by the font size
+/// of the text they're on unless they say otherwise.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum VisualDocument {
+	Color(String),
+	Pattern {
+		pattern: String,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		fontsize: Option<f32>,
+	},
+}
+
+impl VisualDocument {
+	fn into_visual(self, fontsize: f32) -> Result<Visual, DocumentError> {
+		match self {
+			VisualDocument::Color(color) => match Operation::color(&color) {
+				Some(c) => Ok(Visual::Color(c)),
+				None => Err(DocumentError::Colour(color)),
+			},
+			VisualDocument::Pattern {
+				pattern,
+				fontsize: pattern_size,
+			} => Operation::pattern(font_size(pattern_size.unwrap_or(fontsize))?, &pattern)
+				.map_err(|reason| DocumentError::Pattern { pattern, reason }),
+		}
+	}
+}
+
+/// A font size has to be above 0, like the whole pixels of `fs` in a query.
+fn font_size(size: f32) -> Result<f32, DocumentError> {
+	if size.is_normal() && size > 0.0 {
+		Ok(size)
+	} else {
+		Err(DocumentError::FontSize(size))
+	}
+}
+
+impl From<Visual> for VisualDocument {
+	fn from(visual: Visual) -> Self {
+		match visual {
+			Visual::Color(c) => VisualDocument::Color(c.as_hex()),
+			Visual::Pattern { name, fontsize, .. } => VisualDocument::Pattern {
+				pattern: name,
+				fontsize: Some(fontsize),
+			},
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum DocumentError {
+	#[error("'{0}' is not a colour")]
+	Colour(String),
+	#[error("Pattern '{pattern}' is invalid: {reason}")]
+	Pattern { pattern: String, reason: String },
+	#[error("Alignment must be left, center, or right. '{0}' is invalid")]
+	Align(String),
+	#[error("'{0}' is not a line height")]
+	LineHeight(String),
+	#[error("dpr must be 1, 2, or 3. '{0}' is invalid")]
+	Dpr(u8),
+	#[error("aspect must be a positive number. '{0}' is invalid")]
+	Aspect(f32),
+	#[error("fontsize must be a positive number. '{0}' is invalid")]
+	FontSize(f32),
+	#[error("{0}")]
+	Variant(#[from] FontVariantParseError),
+}
+
 pub enum FontSize {
 	Pixels(u32),
 	Point(f32),
@@ -587,3 +1236,207 @@ impl FontSizeParseError {
 		)
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn operation(params: &[(&str, &str)]) -> Operation {
+		let mut op = Operation::default();
+		for (key, value) in params {
+			op.push_parameter(Parameter::Value(key.to_string(), value.to_string()))
+				.unwrap();
+		}
+
+		op
+	}
+
+	#[test]
+	fn canonical_query_ignores_spelling() {
+		let short = operation(&[("c", "red"), ("fs", "32"), ("text", "Hi")]);
+		let long = operation(&[
+			("fontsize", "32"),
+			("colour", "ff0000"),
+			("weight", "400"),
+			("text", "Hi"),
+		]);
+
+		assert_eq!(short.canonical_query(), long.canonical_query());
+	}
+
+	#[test]
+	fn problems_collected() {
+		let mut op = Operation::default();
+		let params = [
+			("weight", "boldd"),
+			("fs", "abc"),
+			("pattern", "stripe:red:nope"),
+			("c", "red"),
+			("text", "Hi"),
+		];
+
+		let problems: Vec<Problem> = params
+			.iter()
+			.filter_map(|(key, value)| {
+				op.push_parameter(Parameter::Value(key.to_string(), value.to_string()))
+					.err()
+			})
+			.collect();
+
+		assert_eq!(problems.len(), 3);
+		assert_eq!(problems[0].parameter, "weight");
+		assert_eq!(problems[1].value, "abc");
+		assert_eq!(problems[2].parameter, "pattern");
+		assert_eq!(op.texts[0].fontsize, Text::default().fontsize);
+	}
+
+	#[test]
+	fn json_roundtrip() {
+		let op = operation(&[
+			("bc", "eed"),
+			("align", "center"),
+			("font", "Dosis"),
+			("weight", "bold"),
+			("pattern", "trans"),
+			("fs", "32"),
+			("text", "Hi"),
+		]);
+
+		let json = serde_json::to_string(&op).unwrap();
+		let parsed: Operation = serde_json::from_str(&json).unwrap();
+
+		assert_eq!(op.canonical_query(), parsed.canonical_query());
+	}
+
+	#[test]
+	fn json_matches_query() {
+		let json = r#"{"texts": [{"text": "Hi", "fontsize": 32, "color": "red"}]}"#;
+		let parsed: Operation = serde_json::from_str(json).unwrap();
+		let queried = operation(&[("fs", "32"), ("c", "red"), ("text", "Hi")]);
+
+		assert_eq!(parsed.canonical_query(), queried.canonical_query());
+	}
+
+	#[test]
+	fn json_rejects_bad_colour() {
+		let json = r#"{"background": "notacolour"}"#;
+
+		assert!(serde_json::from_str::<Operation>(json).is_err());
+	}
+
+	#[test]
+	fn json_checked_like_query() {
+		for json in [
+			r#"{"aspect": 0}"#,
+			r#"{"aspect": -1.5}"#,
+			r#"{"texts": [{"text": "Hi", "fontsize": 0}]}"#,
+			r#"{"texts": [{"text": "Hi", "fontsize": -32}]}"#,
+			r#"{"texts": [{"text": "Hi", "color": {"pattern": "trans", "fontsize": -8}}]}"#,
+		] {
+			assert!(serde_json::from_str::<Operation>(json).is_err(), "{}", json);
+		}
+
+		let mut op = Operation::default();
+		for (key, value) in [
+			("aspect", "0"),
+			("aspect", "-1.5"),
+			("fs", "0"),
+			("fs", "-32"),
+		] {
+			let parameter = Parameter::Value(key.to_string(), value.to_string());
+			assert!(op.push_parameter(parameter).is_err(), "{}={}", key, value);
+		}
+	}
+
+	#[test]
+	fn canonical_query_differs() {
+		let red = operation(&[("c", "red"), ("text", "Hi")]);
+		let blue = operation(&[("c", "blue"), ("text", "Hi")]);
+
+		assert_ne!(red.canonical_query(), blue.canonical_query());
+	}
+
+	#[test]
+	fn limits_checked_before_layout() {
+		let limits = Limits {
+			max_texts: 2,
+			max_glyphs: 4,
+			max_font_size: 200.0,
+			..Limits::default()
+		};
+
+		let fine = operation(&[("text", "Hi"), ("text", "Hi")]);
+		assert!(fine.check_limits(&limits).is_ok());
+
+		let texts = operation(&[("text", "a"), ("text", "b"), ("text", "c")]);
+		assert!(matches!(
+			texts.check_limits(&limits),
+			Err(RenderError::TooManyTexts { count: 3, max: 2 })
+		));
+
+		let glyphs = operation(&[("text", "Hello")]);
+		assert!(matches!(
+			glyphs.check_limits(&limits),
+			Err(RenderError::TooManyGlyphs { count: 5, max: 4 })
+		));
+
+		let big = operation(&[("fs", "100000"), ("text", "Hi")]);
+		assert!(matches!(
+			big.check_limits(&limits),
+			Err(RenderError::FontTooLarge { .. })
+		));
+
+		let padded = operation(&[("pad", &usize::MAX.to_string()), ("text", "Hi")]);
+		assert!(matches!(
+			padded.check_limits(&limits),
+			Err(RenderError::PaddingTooLarge { .. })
+		));
+	}
+
+	#[test]
+	fn limits_image_size() {
+		let limits = Limits {
+			max_pixels: 100,
+			max_dimension: 50,
+			..Limits::default()
+		};
+
+		assert!(Operation::check_size(10, 10, &limits).is_ok());
+		assert!(Operation::check_size(51, 1, &limits).is_err());
+		assert!(Operation::check_size(11, 10, &limits).is_err());
+		assert!(Operation::check_size(usize::MAX, usize::MAX, &limits).is_err());
+	}
+
+	#[tokio::test]
+	async fn dpr_scales_everything() {
+		let cache = std::env::temp_dir().join(format!("textual-dpr-{}", std::process::id()));
+		std::fs::create_dir_all(&cache).unwrap();
+		let provider = RwLock::new(FontProvider::new(&cache, usize::MAX, usize::MAX).unwrap());
+		let rasterizer = Rasterizer::default();
+		let limits = Limits::default();
+
+		let render =
+			|params: &[(&str, &str)]| operation(params).make_image(&provider, &rasterizer, &limits);
+
+		// Only padding, so the image is exactly twice as big
+		let (one, _) = render(&[("pad", "10")]).await.unwrap();
+		let (two, _) = render(&[("pad", "10"), ("dpr", "2")]).await.unwrap();
+		assert_eq!(
+			(two.width(), two.height()),
+			(2 * one.width(), 2 * one.height())
+		);
+
+		// Text can round a pixel either way
+		let (one, _) = render(&[("text", "Hi"), ("fs", "20"), ("pad", "10")])
+			.await
+			.unwrap();
+		let (two, _) = render(&[("text", "Hi"), ("fs", "20"), ("pad", "10"), ("dpr", "2")])
+			.await
+			.unwrap();
+		let _ = std::fs::remove_dir_all(&cache);
+
+		assert!(one.width() > 20 && one.height() > 20);
+		assert!((two.width() as isize - 2 * one.width() as isize).abs() <= 1);
+		assert!((two.height() as isize - 2 * one.height() as isize).abs() <= 1);
+	}
+}
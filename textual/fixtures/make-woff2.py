@@ -0,0 +1,472 @@
+#!/usr/bin/env python3
+"""Makes the WOFF2 fixture the woff tests decode, and the TTF it should decode to.
+
+    make-woff2.py /usr/share/fonts/truetype/dejavu/DejaVuSerif.ttf
+
+A few glyphs of DejaVu Serif are kept, simple and composite, with their
+hinting, and enough of its name and OS/2 tables to say what font it is. The TTF is written the way the decoder writes fonts, so it can be
+compared byte for byte. The WOFF2 uses the glyf and hmtx transforms, and
+stores its tables with Brotli's uncompressed blocks so nothing but the
+standard library is needed to make it.
+"""
+
+import os
+import struct
+import sys
+
+KEEP = "AgÁiy 2"
+
+KNOWN_TAGS = [
+	"cmap", "head", "hhea", "hmtx", "maxp", "name", "OS/2", "post", "cvt ", "fpgm",
+	"glyf", "loca", "prep", "CFF ", "VORG", "EBDT", "EBLC", "gasp", "hdmx", "kern",
+	"LTSH", "PCLT", "VDMX", "vhea", "vmtx", "BASE", "GDEF", "GPOS", "GSUB", "EBSC",
+	"JSTF", "MATH", "CBDT", "CBLC", "COLR", "CPAL", "SVG ", "sbix", "acnt", "avar",
+	"bdat", "bloc", "bsln", "cvar", "fdsc", "feat", "fmtx", "fvar", "gvar", "hsty",
+	"just", "lcar", "mort", "morx", "opbd", "prop", "trak", "Zapf", "Silf", "Glat",
+	"Gloc", "Feat", "Sill",
+]
+
+ARGS_ARE_WORDS = 0x0001
+HAS_SCALE = 0x0008
+MORE_COMPONENTS = 0x0020
+HAS_XY_SCALE = 0x0040
+HAS_TWO_BY_TWO = 0x0080
+HAS_INSTRUCTIONS = 0x0100
+
+
+def read_tables(font):
+	count = struct.unpack(">H", font[4:6])[0]
+	tables = {}
+	for index in range(count):
+		tag, _, offset, length = struct.unpack(">4sIII", font[12 + 16 * index:28 + 16 * index])
+		tables[tag.decode("latin1")] = font[offset:offset + length]
+	return tables
+
+
+def cmap_lookup(cmap, char):
+	"""Finds a character's glyph in the format 4 Unicode subtable."""
+	count = struct.unpack(">H", cmap[2:4])[0]
+	for index in range(count):
+		platform, encoding, offset = struct.unpack(">HHI", cmap[4 + 8 * index:12 + 8 * index])
+		if (platform, encoding) in [(3, 1), (0, 3)] and struct.unpack(">H", cmap[offset:offset + 2])[0] == 4:
+			break
+	else:
+		raise SystemExit("no format 4 cmap")
+
+	sub = cmap[offset:]
+	segments = struct.unpack(">H", sub[6:8])[0] // 2
+	array = lambda start, i: struct.unpack(">H", sub[start + 2 * i:start + 2 * i + 2])[0]
+	ends, starts, deltas, ranges = 14, 16 + 2 * segments, 16 + 4 * segments, 16 + 6 * segments
+	code = ord(char)
+	for i in range(segments):
+		if array(starts, i) <= code <= array(ends, i):
+			if array(ranges, i) == 0:
+				return (code + array(deltas, i)) & 0xFFFF
+			glyph = array(ranges + 2 * i + 2 * (code - array(starts, i)) + array(ranges, i), 0)
+			return (glyph + array(deltas, i)) & 0xFFFF if glyph else 0
+	return 0
+
+
+def family_names(name):
+	"""The name table with only the family and subfamily names left in it."""
+	count, storage = struct.unpack(">HH", name[2:6])
+	records = []
+	for index in range(count):
+		record = struct.unpack(">6H", name[6 + 12 * index:18 + 12 * index])
+		if record[3] in [1, 2]:
+			start = storage + record[5]
+			records.append((record[:4], name[start:start + record[4]]))
+
+	table = struct.pack(">HHH", 0, len(records), 6 + 12 * len(records))
+	strings = b""
+	for record, string in records:
+		table += struct.pack(">6H", *record, len(string), len(strings))
+		strings += string
+	return table + strings
+
+
+def glyph_data(tables, long_loca, glyph):
+	loca = tables["loca"]
+	if long_loca:
+		start, end = struct.unpack(">II", loca[4 * glyph:4 * glyph + 8])
+	else:
+		start, end = (2 * x for x in struct.unpack(">HH", loca[2 * glyph:2 * glyph + 4]))
+	return tables["glyf"][start:end]
+
+
+def components(data):
+	"""The components of a composite, as (flags, glyph, rest), and where they end."""
+	position = 10
+	found = []
+	while True:
+		flags, glyph = struct.unpack(">HH", data[position:position + 4])
+		size = 4 if flags & ARGS_ARE_WORDS else 2
+		if flags & HAS_SCALE:
+			size += 2
+		elif flags & HAS_XY_SCALE:
+			size += 4
+		elif flags & HAS_TWO_BY_TWO:
+			size += 8
+		found.append((flags, glyph, data[position + 4:position + 4 + size]))
+		position += 4 + size
+		if not flags & MORE_COMPONENTS:
+			return found, position
+
+
+def simple(data):
+	"""A simple glyph's contours, instructions and points as (x, y, on curve)."""
+	contours = struct.unpack(">h", data[0:2])[0]
+	ends = struct.unpack(">%dH" % contours, data[10:10 + 2 * contours])
+	position = 10 + 2 * contours
+	length = struct.unpack(">H", data[position:position + 2])[0]
+	instructions = data[position + 2:position + 2 + length]
+	position += 2 + length
+
+	count = ends[-1] + 1
+	flags = []
+	while len(flags) < count:
+		flag = data[position]
+		position += 1
+		repeat = 1
+		if flag & 0x08:
+			repeat += data[position]
+			position += 1
+		flags.extend([flag] * repeat)
+
+	def coordinates(short, same):
+		nonlocal position
+		values, value = [], 0
+		for flag in flags:
+			if flag & short:
+				delta = data[position]
+				position += 1
+				value += delta if flag & same else -delta
+			elif not flag & same:
+				value += struct.unpack(">h", data[position:position + 2])[0]
+				position += 2
+			values.append(value)
+		return values
+
+	xs = coordinates(0x02, 0x10)
+	ys = coordinates(0x04, 0x20)
+	points = [(x, y, bool(flag & 1)) for x, y, flag in zip(xs, ys, flags)]
+	return ends, instructions, points
+
+
+def bounds(points):
+	xs = [p[0] for p in points]
+	ys = [p[1] for p in points]
+	return (min(xs), min(ys), max(xs), max(ys))
+
+
+def write_points(points):
+	"""Points as the decoder writes them, see write_points in woff.rs."""
+	flags, xs, ys = [], b"", b""
+	last_x = last_y = 0
+	for x, y, on_curve in points:
+		flag = 0x01 if on_curve else 0
+		for delta, short, same, out in [(x - last_x, 0x02, 0x10, "x"), (y - last_y, 0x04, 0x20, "y")]:
+			if delta == 0:
+				flag |= same
+				continue
+			if abs(delta) < 256:
+				flag |= short
+				if delta > 0:
+					flag |= same
+				value = bytes([abs(delta)])
+			else:
+				value = struct.pack(">h", delta)
+			if out == "x":
+				xs += value
+			else:
+				ys += value
+		last_x, last_y = x, y
+		flags.append(flag)
+
+	out = b""
+	index = 0
+	while index < len(flags):
+		flag = flags[index]
+		repeats = 0
+		while index + 1 + repeats < len(flags) and repeats < 255 and flags[index + 1 + repeats] == flag:
+			repeats += 1
+		out += bytes([flag | 0x08, repeats]) if repeats else bytes([flag])
+		index += 1 + repeats
+	return out + xs + ys
+
+
+def uint255(value):
+	if value < 253:
+		return bytes([value])
+	if value < 506:
+		return bytes([255, value - 253])
+	if value < 762:
+		return bytes([254, value - 506])
+	return bytes([253]) + struct.pack(">H", value)
+
+
+def base128(value):
+	out = [value & 0x7F]
+	value >>= 7
+	while value:
+		out.insert(0, 0x80 | (value & 0x7F))
+		value >>= 7
+	return bytes(out)
+
+
+def triplet(x, y, on_curve):
+	"""A point's flag and bytes, as the reference encoder picks them."""
+	ax, ay = abs(x), abs(y)
+	off = 0 if on_curve else 128
+	x_sign = 0 if x < 0 else 1
+	y_sign = 0 if y < 0 else 1
+	signs = x_sign + 2 * y_sign
+	if x == 0 and ay < 1280:
+		return off + ((ay & 0xF00) >> 7) + y_sign, bytes([ay & 0xFF])
+	if y == 0 and ax < 1280:
+		return off + 10 + ((ax & 0xF00) >> 7) + x_sign, bytes([ax & 0xFF])
+	if ax < 65 and ay < 65:
+		flag = off + 20 + ((ax - 1) & 0x30) + (((ay - 1) & 0x30) >> 2) + signs
+		return flag, bytes([(((ax - 1) & 0xF) << 4) | ((ay - 1) & 0xF)])
+	if ax < 769 and ay < 769:
+		flag = off + 84 + 12 * (((ax - 1) & 0x300) >> 8) + (((ay - 1) & 0x300) >> 6) + signs
+		return flag, bytes([(ax - 1) & 0xFF, (ay - 1) & 0xFF])
+	if ax < 4096 and ay < 4096:
+		return off + 120 + signs, bytes([ax >> 4, ((ax & 0xF) << 4) | (ay >> 8), ay & 0xFF])
+	return off + 124 + signs, struct.pack(">HH", ax, ay)
+
+
+def checksum(data):
+	data = data + b"\0" * (-len(data) % 4)
+	return sum(struct.unpack(">%dI" % (len(data) // 4), data)) & 0xFFFFFFFF
+
+
+def sfnt(tables):
+	"""A font laid out like sfnt in woff.rs does it."""
+	tags = sorted(tables, key=lambda tag: tag.encode("latin1"))
+	count = len(tags)
+	selector = count.bit_length() - 1
+	search = 16 * (1 << selector)
+	font = struct.pack(">IHHHH", 0x00010000, count, search, selector, count * 16 - search)
+
+	offset = 12 + 16 * count
+	head = None
+	for tag in tags:
+		table = tables[tag]
+		if tag == "head":
+			table = tables[tag] = table[:8] + b"\0" * 4 + table[12:]
+			head = offset
+		font += struct.pack(">4sIII", tag.encode("latin1"), checksum(table), offset, len(table))
+		offset += (len(table) + 3) & ~3
+
+	for tag in tags:
+		font += tables[tag] + b"\0" * (-len(tables[tag]) % 4)
+
+	adjustment = (0xB1B0AFBA - checksum(font)) & 0xFFFFFFFF
+	return font[:head + 8] + struct.pack(">I", adjustment) + font[head + 12:]
+
+
+def brotli(data):
+	"""A Brotli stream of uncompressed blocks, which every decoder has to read."""
+	out = bytearray()
+	bits = value = 0
+
+	def write(number, count):
+		nonlocal bits, value
+		value |= number << bits
+		bits += count
+		while bits >= 8:
+			out.append(value & 0xFF)
+			value >>= 8
+			bits -= 8
+
+	def flush():
+		nonlocal bits, value
+		if bits:
+			out.append(value)
+		bits = value = 0
+
+	write(0, 1)  # a 64 KiB window
+	for start in range(0, len(data), 65536):
+		chunk = data[start:start + 65536]
+		write(0, 1)  # not the last block
+		write(0, 2)  # four nibbles of length
+		write(len(chunk) - 1, 16)
+		write(1, 1)  # uncompressed
+		flush()
+		out.extend(chunk)
+	write(1, 1)  # the last block
+	write(1, 1)  # which is empty
+	flush()
+	return bytes(out)
+
+
+def main():
+	source = open(sys.argv[1], "rb").read()
+	tables = read_tables(source)
+	long_loca = struct.unpack(">h", tables["head"][50:52])[0] == 1
+
+	# The glyphs to keep, and the ones their composites are made of
+	keep = [0] + [cmap_lookup(tables["cmap"], char) for char in KEEP]
+	for glyph in keep:
+		data = glyph_data(tables, long_loca, glyph)
+		if data and struct.unpack(">h", data[0:2])[0] < 0:
+			keep.extend(g for _, g, _ in components(data)[0] if g not in keep)
+	new_index = {old: new for new, old in enumerate(keep)}
+
+	glyphs = []
+	for glyph in keep:
+		data = glyph_data(tables, long_loca, glyph)
+		if not data:
+			glyphs.append(("empty",))
+			continue
+		contours = struct.unpack(">h", data[0:2])[0]
+		bbox = struct.unpack(">4h", data[2:10])
+		if contours < 0:
+			parts, end = components(data)
+			records = b"".join(
+				struct.pack(">HH", flags, new_index[g]) + rest for flags, g, rest in parts
+			)
+			instructions = None
+			if any(flags & HAS_INSTRUCTIONS for flags, _, _ in parts):
+				length = struct.unpack(">H", data[end:end + 2])[0]
+				instructions = data[end + 2:end + 2 + length]
+			glyphs.append(("composite", bbox, records, instructions))
+		else:
+			ends, instructions, points = simple(data)
+			glyphs.append(("simple", bbox, ends, instructions, points))
+
+	# The TTF, as the decoder would write it
+	glyf, offsets = b"", []
+	for glyph in glyphs:
+		offsets.append(len(glyf))
+		if glyph[0] == "composite":
+			_, bbox, records, instructions = glyph
+			glyf += struct.pack(">h4h", -1, *bbox) + records
+			if instructions is not None:
+				glyf += struct.pack(">H", len(instructions)) + instructions
+		elif glyph[0] == "simple":
+			_, bbox, ends, instructions, points = glyph
+			glyf += struct.pack(">h4h", len(ends), *bbox)
+			glyf += struct.pack(">%dH" % len(ends), *ends)
+			glyf += struct.pack(">H", len(instructions)) + instructions
+			glyf += write_points(points)
+		glyf += b"\0" * (-len(glyf) % 4)
+	offsets.append(len(glyf))
+	loca = b"".join(struct.pack(">I", o) if long_loca else struct.pack(">H", o // 2) for o in offsets)
+
+	old_metrics = struct.unpack(">H", tables["hhea"][34:36])[0]
+	metrics = []
+	for glyph in keep:
+		if glyph < old_metrics:
+			metrics.append(struct.unpack(">Hh", tables["hmtx"][4 * glyph:4 * glyph + 4]))
+		else:
+			advance = struct.unpack(">H", tables["hmtx"][4 * old_metrics - 4:4 * old_metrics - 2])[0]
+			offset = 4 * old_metrics + 2 * (glyph - old_metrics)
+			metrics.append((advance, struct.unpack(">h", tables["hmtx"][offset:offset + 2])[0]))
+	# The last glyphs share an advance, to have some without one
+	num_metrics = len(metrics)
+	while num_metrics > 1 and metrics[num_metrics - 2][0] == metrics[-1][0]:
+		num_metrics -= 1
+	hmtx = b"".join(struct.pack(">Hh", *m) for m in metrics[:num_metrics])
+	hmtx += b"".join(struct.pack(">h", m[1]) for m in metrics[num_metrics:])
+
+	x_mins = [0 if g[0] == "empty" else g[1][0] for g in glyphs]
+	if [m[1] for m in metrics] != x_mins:
+		raise SystemExit("the bearings aren't all xMin, so hmtx can't be transformed")
+
+	hhea = tables["hhea"][:34] + struct.pack(">H", num_metrics)
+	maxp = tables["maxp"][:4] + struct.pack(">H", len(keep)) + tables["maxp"][6:]
+	post = struct.pack(">I", 0x00030000) + tables["post"][4:32]
+	ttf = {
+		"head": tables["head"],
+		"hhea": hhea,
+		"maxp": maxp,
+		"hmtx": hmtx,
+		"glyf": glyf,
+		"loca": loca,
+		"post": post,
+		"name": family_names(tables["name"]),
+		"OS/2": tables["OS/2"],
+		"cvt ": tables["cvt "],
+		"fpgm": tables["fpgm"],
+		"prep": tables["prep"],
+	}
+	ttf_font = sfnt(ttf)
+	ttf = read_tables(ttf_font)
+
+	# The glyf transform's seven streams
+	contours = points_stream = flags = coordinates = composites = boxes = instructions = b""
+	has_box = bytearray(4 * ((len(glyphs) + 31) // 32))
+	for index, glyph in enumerate(glyphs):
+		if glyph[0] == "empty":
+			contours += struct.pack(">h", 0)
+			continue
+		if glyph[0] == "composite":
+			_, bbox, records, code = glyph
+			contours += struct.pack(">h", -1)
+			composites += records
+			if code is not None:
+				coordinates += uint255(len(code))
+				instructions += code
+		else:
+			_, bbox, ends, code, points = glyph
+			contours += struct.pack(">h", len(ends))
+			last = -1
+			for end in ends:
+				points_stream += uint255(end - last)
+				last = end
+			last_x = last_y = 0
+			for x, y, on_curve in points:
+				flag, data = triplet(x - last_x, y - last_y, on_curve)
+				flags += bytes([flag])
+				coordinates += data
+				last_x, last_y = x, y
+			coordinates += uint255(len(code))
+			instructions += code
+			if bounds(points) == bbox:
+				continue
+		has_box[index // 8] |= 0x80 >> (index % 8)
+		boxes += struct.pack(">4h", *bbox)
+
+	streams = [contours, points_stream, flags, coordinates, composites, bytes(has_box) + boxes, instructions]
+	glyf_transformed = struct.pack(">HHHH", 0, 0, len(glyphs), 1 if long_loca else 0)
+	glyf_transformed += struct.pack(">7I", *(len(s) for s in streams)) + b"".join(streams)
+
+	# Both kinds of bearing are left out
+	hmtx_transformed = bytes([0x03]) + b"".join(struct.pack(">H", m[0]) for m in metrics[:num_metrics])
+
+	# glyf has to come right before loca
+	order = ["head", "hhea", "maxp", "name", "OS/2", "post", "cvt ", "fpgm", "prep", "glyf", "loca", "hmtx"]
+	directory, stream = b"", b""
+	for tag in order:
+		index = KNOWN_TAGS.index(tag)
+		if tag == "glyf":
+			directory += bytes([index]) + base128(len(ttf[tag])) + base128(len(glyf_transformed))
+			stream += glyf_transformed
+		elif tag == "loca":
+			directory += bytes([index]) + base128(len(ttf[tag])) + base128(0)
+		elif tag == "hmtx":
+			directory += bytes([index | 0x40]) + base128(len(ttf[tag])) + base128(len(hmtx_transformed))
+			stream += hmtx_transformed
+		else:
+			directory += bytes([index]) + base128(len(ttf[tag]))
+			stream += ttf[tag]
+
+	compressed = brotli(stream)
+	length = 48 + len(directory) + len(compressed)
+	length += -length % 4
+	header = struct.pack(
+		">4sIIHHIIHHIIIII", b"wOF2", 0x00010000, length, len(order), 0, len(ttf_font),
+		len(compressed), 1, 0, 0, 0, 0, 0, 0,
+	)
+	woff2 = header + directory + compressed
+	woff2 += b"\0" * (length - len(woff2))
+
+	here = os.path.dirname(os.path.abspath(__file__))
+	open(os.path.join(here, "dejavu-serif-subset.ttf"), "wb").write(ttf_font)
+	open(os.path.join(here, "dejavu-serif-subset.woff2"), "wb").write(woff2)
+
+
+if __name__ == "__main__":
+	main()
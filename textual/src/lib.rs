@@ -1 +1 @@
-
+pub mod woff;
@@ -0,0 +1,957 @@
+//! WOFF and WOFF2 are TrueType and OpenType fonts packed up for the web. The
+//! font parsers only read the plain sfnt inside, so [decode] unpacks them.
+
+use std::{borrow::Cow, io::Read};
+
+use thiserror::Error;
+
+/// Refuse fonts that unpack to more than this. Real fonts are a few megabytes
+/// at most, something bigger is likely built to eat our memory.
+const MAX_SIZE: usize = 64 * 1024 * 1024;
+
+const WOFF: u32 = tag(b"wOFF");
+const WOFF2: u32 = tag(b"wOF2");
+const COLLECTION: u32 = tag(b"ttcf");
+
+const GLYF: u32 = tag(b"glyf");
+const LOCA: u32 = tag(b"loca");
+const HMTX: u32 = tag(b"hmtx");
+const HHEA: u32 = tag(b"hhea");
+const MAXP: u32 = tag(b"maxp");
+const HEAD: u32 = tag(b"head");
+
+/// The tables WOFF2 can name with a single byte, in the order of that byte.
+const KNOWN_TAGS: [&[u8; 4]; 63] = [
+	b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post", b"cvt ", b"fpgm",
+	b"glyf", b"loca", b"prep", b"CFF ", b"VORG", b"EBDT", b"EBLC", b"gasp", b"hdmx", b"kern",
+	b"LTSH", b"PCLT", b"VDMX", b"vhea", b"vmtx", b"BASE", b"GDEF", b"GPOS", b"GSUB", b"EBSC",
+	b"JSTF", b"MATH", b"CBDT", b"CBLC", b"COLR", b"CPAL", b"SVG ", b"sbix", b"acnt", b"avar",
+	b"bdat", b"bloc", b"bsln", b"cvar", b"fdsc", b"feat", b"fmtx", b"fvar", b"gvar", b"hsty",
+	b"just", b"lcar", b"mort", b"morx", b"opbd", b"prop", b"trak", b"Zapf", b"Silf", b"Glat",
+	b"Gloc", b"Feat", b"Sill",
+];
+
+const fn tag(name: &[u8; 4]) -> u32 {
+	u32::from_be_bytes(*name)
+}
+
+#[derive(Debug, Error)]
+pub enum WoffError {
+	#[error("the font ends before it should")]
+	Truncated,
+	#[error("the font is malformed: {0}")]
+	Malformed(&'static str),
+	#[error("the font uses something we don't support: {0}")]
+	Unsupported(&'static str),
+	#[error("the font would be bigger than we allow")]
+	TooLarge,
+	#[error("unable to decompress the font: {0}")]
+	Decompress(#[from] std::io::Error),
+}
+
+/// Whether `data` is a WOFF or WOFF2 font.
+pub fn is_woff(data: &[u8]) -> bool {
+	matches!(signature(data), Some(WOFF) | Some(WOFF2))
+}
+
+/// Unpack a WOFF or WOFF2 font into the sfnt it was made from. Anything else
+/// is given back as it is; it's up to the font parser to decide if it's a font.
+pub fn decode(data: &[u8]) -> Result<Cow<'_, [u8]>, WoffError> {
+	match signature(data) {
+		Some(WOFF) => woff(data).map(Cow::Owned),
+		Some(WOFF2) => woff2(data).map(Cow::Owned),
+		_ => Ok(Cow::Borrowed(data)),
+	}
+}
+
+fn signature(data: &[u8]) -> Option<u32> {
+	Reader::new(data).u32().ok()
+}
+
+fn woff(data: &[u8]) -> Result<Vec<u8>, WoffError> {
+	let mut header = Reader::new(data);
+	header.skip(4)?; // signature
+	let flavor = header.u32()?;
+	header.skip(4)?; // length
+	let num_tables = header.u16()?;
+	if num_tables == 0 {
+		return Err(WoffError::Malformed("there are no tables"));
+	}
+	// Reserved, the sfnt size and version, and where the metadata and private
+	// data are. We don't keep either.
+	header.skip(2 + 4 + 2 + 2 + 4 * 5)?;
+
+	let mut size = 0;
+	let mut tables = Vec::with_capacity(num_tables as usize);
+	for _ in 0..num_tables {
+		let tag = header.u32()?;
+		let offset = header.u32()? as usize;
+		let compressed_length = header.u32()? as usize;
+		let length = header.u32()? as usize;
+		header.skip(4)?; // checksum, which we work out again anyway
+
+		size += length;
+		if size > MAX_SIZE {
+			return Err(WoffError::TooLarge);
+		}
+
+		let compressed = Reader::new(data).at(offset)?.bytes(compressed_length)?;
+		let table = if compressed_length < length {
+			let mut table = Vec::with_capacity(length);
+			flate2::read::ZlibDecoder::new(compressed)
+				.take(length as u64)
+				.read_to_end(&mut table)?;
+
+			if table.len() != length {
+				return Err(WoffError::Malformed("a table is the wrong size"));
+			}
+			table
+		} else if compressed_length == length {
+			compressed.to_vec()
+		} else {
+			return Err(WoffError::Malformed("a table is bigger compressed"));
+		};
+
+		tables.push((tag, table));
+	}
+
+	Ok(sfnt(flavor, tables))
+}
+
+struct Entry {
+	tag: u32,
+	length: usize,
+	transformed: bool,
+}
+
+fn woff2(data: &[u8]) -> Result<Vec<u8>, WoffError> {
+	let mut header = Reader::new(data);
+	header.skip(4)?; // signature
+	let flavor = header.u32()?;
+	if flavor == COLLECTION {
+		return Err(WoffError::Unsupported("font collections"));
+	}
+	header.skip(4)?; // length
+	let num_tables = header.u16()?;
+	if num_tables == 0 {
+		return Err(WoffError::Malformed("there are no tables"));
+	}
+	header.skip(2 + 4)?; // reserved and the sfnt size
+	let compressed_length = header.u32()? as usize;
+	// The version, and where the metadata and private data are
+	header.skip(2 + 2 + 4 * 5)?;
+
+	let mut size = 0;
+	let mut entries = Vec::with_capacity(num_tables as usize);
+	for _ in 0..num_tables {
+		let flags = header.u8()?;
+		let tag = match flags & 0x3f {
+			0x3f => header.u32()?,
+			index => tag(KNOWN_TAGS[index as usize]),
+		};
+
+		// For glyf and loca, version 0 is their transform and 3 is none. For
+		// everything else it's the other way around.
+		let version = flags >> 6;
+		let transformed = match tag {
+			GLYF | LOCA => version == 0,
+			_ => version != 0,
+		};
+
+		let length = header.base128()? as usize;
+		let length = if transformed {
+			match tag {
+				GLYF | LOCA | HMTX => header.base128()? as usize,
+				_ => return Err(WoffError::Unsupported("a table transform")),
+			}
+		} else {
+			length
+		};
+
+		size += length;
+		if size > MAX_SIZE {
+			return Err(WoffError::TooLarge);
+		}
+
+		entries.push(Entry {
+			tag,
+			length,
+			transformed,
+		});
+	}
+
+	let compressed = header.bytes(compressed_length)?;
+	let mut stream = Vec::with_capacity(size);
+	brotli_decompressor::Decompressor::new(compressed, 4096)
+		.take(size as u64)
+		.read_to_end(&mut stream)?;
+
+	if stream.len() != size {
+		return Err(WoffError::Malformed("the tables are the wrong size"));
+	}
+
+	let mut stream = Reader::new(&stream);
+	let mut tables = Vec::with_capacity(entries.len());
+	let mut transformed_glyf = None;
+	let mut transformed_hmtx = None;
+	for entry in &entries {
+		let table = stream.bytes(entry.length)?;
+
+		match entry.tag {
+			GLYF if entry.transformed => transformed_glyf = Some(table),
+			// It's empty, we make loca when we make glyf
+			LOCA if entry.transformed => (),
+			HMTX if entry.transformed => transformed_hmtx = Some(table),
+			tag => tables.push((tag, table.to_vec())),
+		}
+	}
+
+	let has_loca = entries.iter().any(|entry| entry.tag == LOCA);
+	let x_mins = match transformed_glyf {
+		Some(_) if !has_loca => return Err(WoffError::Malformed("glyf without loca")),
+		Some(table) => {
+			let glyphs = glyf(table)?;
+			tables.push((GLYF, glyphs.glyf));
+			tables.push((LOCA, glyphs.loca));
+			Some(glyphs.x_mins)
+		}
+		None => None,
+	};
+
+	if let Some(table) = transformed_hmtx {
+		let x_mins = x_mins.ok_or(WoffError::Unsupported("hmtx without a transformed glyf"))?;
+		let find = |tag| {
+			tables
+				.iter()
+				.find(|(t, _)| *t == tag)
+				.map(|(_, table)| Reader::new(table))
+		};
+
+		let num_hmetrics = find(HHEA)
+			.ok_or(WoffError::Malformed("hmtx without hhea"))?
+			.at(34)?
+			.u16()?;
+
+		let num_glyphs = find(MAXP)
+			.ok_or(WoffError::Malformed("hmtx without maxp"))?
+			.at(4)?
+			.u16()?;
+
+		let hmtx = hmtx(table, num_glyphs as usize, num_hmetrics as usize, &x_mins)?;
+		tables.push((HMTX, hmtx));
+	}
+
+	Ok(sfnt(flavor, tables))
+}
+
+/// What we get from undoing the glyf transform.
+struct Glyphs {
+	glyf: Vec<u8>,
+	loca: Vec<u8>,
+	/// The left edge of every glyph, for hmtx.
+	x_mins: Vec<i16>,
+}
+
+/// Rebuild glyf, and loca, from the seven streams WOFF2 splits them into.
+fn glyf(table: &[u8]) -> Result<Glyphs, WoffError> {
+	let mut header = Reader::new(table);
+	header.skip(2)?; // reserved
+	let options = header.u16()?;
+	let num_glyphs = header.u16()? as usize;
+	let index_format = header.u16()?;
+
+	let mut sizes = [0; 7];
+	for size in &mut sizes {
+		*size = header.u32()? as usize;
+	}
+
+	let mut stream = |size| header.bytes(size).map(Reader::new);
+	let mut contour_counts = stream(sizes[0])?;
+	let mut point_counts = stream(sizes[1])?;
+	let mut flags = stream(sizes[2])?;
+	let mut coordinates = stream(sizes[3])?;
+	let mut composites = stream(sizes[4])?;
+	let mut boxes = stream(sizes[5])?;
+	let mut instructions = stream(sizes[6])?;
+	let overlaps = match options & 1 {
+		0 => None,
+		_ => Some(stream(num_glyphs.div_ceil(8))?.bytes(num_glyphs.div_ceil(8))?),
+	};
+
+	let has_box = boxes.bytes(4 * num_glyphs.div_ceil(32))?;
+	let bit = |bits: &[u8], index: usize| bits[index / 8] & (0x80 >> (index % 8)) != 0;
+
+	let mut glyf = vec![];
+	let mut offsets = Vec::with_capacity(num_glyphs + 1);
+	let mut x_mins = Vec::with_capacity(num_glyphs);
+	for index in 0..num_glyphs {
+		offsets.push(glyf.len());
+
+		let contours = contour_counts.i16()?;
+		let bbox = match bit(has_box, index) {
+			true => Some(boxes.bytes(8)?),
+			false => None,
+		};
+
+		match contours {
+			0 if bbox.is_some() => {
+				return Err(WoffError::Malformed("an empty glyph has a bounding box"))
+			}
+			0 => x_mins.push(0),
+			-1 => {
+				let bbox = bbox.ok_or(WoffError::Malformed("a composite has no bounding box"))?;
+				let (components, has_instructions) = components(&mut composites)?;
+
+				glyf.extend_from_slice(&contours.to_be_bytes());
+				glyf.extend_from_slice(bbox);
+				glyf.extend_from_slice(components);
+
+				if has_instructions {
+					let length = coordinates.uint255()?;
+					glyf.extend_from_slice(&length.to_be_bytes());
+					glyf.extend_from_slice(instructions.bytes(length as usize)?);
+				}
+
+				x_mins.push(i16::from_be_bytes([bbox[0], bbox[1]]));
+			}
+			contours if contours > 0 => {
+				let mut end_points = Vec::with_capacity(contours as usize);
+				let mut count = 0;
+				for _ in 0..contours {
+					count += point_counts.uint255()? as usize;
+					if count == 0 || count > u16::MAX as usize + 1 {
+						return Err(WoffError::Malformed(
+							"a contour has the wrong number of points",
+						));
+					}
+					end_points.push((count - 1) as u16);
+				}
+
+				let mut points = Vec::with_capacity(count);
+				let (mut x, mut y) = (0i32, 0i32);
+				for _ in 0..count {
+					let flag = flags.u8()?;
+					let (dx, dy) = triplet(flag & 0x7f, &mut coordinates)?;
+					let too_far = || WoffError::Malformed("a point is too far out");
+					x = x.checked_add(dx).ok_or_else(too_far)?;
+					y = y.checked_add(dy).ok_or_else(too_far)?;
+					points.push(Point {
+						x,
+						y,
+						on_curve: flag & 0x80 == 0,
+					});
+				}
+
+				let length = coordinates.uint255()?;
+				let bbox = match bbox {
+					Some(bbox) => bbox.to_vec(),
+					None => bounds(&points)?,
+				};
+
+				glyf.extend_from_slice(&contours.to_be_bytes());
+				glyf.extend_from_slice(&bbox);
+				for end in end_points {
+					glyf.extend_from_slice(&end.to_be_bytes());
+				}
+				glyf.extend_from_slice(&length.to_be_bytes());
+				glyf.extend_from_slice(instructions.bytes(length as usize)?);
+
+				let overlap = overlaps.is_some_and(|bits| bit(bits, index));
+				write_points(&mut glyf, &points, overlap)?;
+
+				x_mins.push(i16::from_be_bytes([bbox[0], bbox[1]]));
+			}
+			_ => return Err(WoffError::Malformed("a glyph has negative contours")),
+		}
+
+		// Keeps every offset even, for the short loca format
+		while glyf.len() % 4 != 0 {
+			glyf.push(0);
+		}
+
+		if glyf.len() > MAX_SIZE {
+			return Err(WoffError::TooLarge);
+		}
+	}
+	offsets.push(glyf.len());
+
+	let mut loca = vec![];
+	for offset in offsets {
+		match index_format {
+			0 => {
+				let offset = u16::try_from(offset / 2)
+					.map_err(|_| WoffError::Malformed("glyf is too big for a short loca"))?;
+				loca.extend_from_slice(&offset.to_be_bytes());
+			}
+			_ => loca.extend_from_slice(&(offset as u32).to_be_bytes()),
+		}
+	}
+
+	Ok(Glyphs { glyf, loca, x_mins })
+}
+
+struct Point {
+	x: i32,
+	y: i32,
+	on_curve: bool,
+}
+
+/// The bounding box of a glyph as xMin, yMin, xMax, yMax.
+fn bounds(points: &[Point]) -> Result<Vec<u8>, WoffError> {
+	let x_min = points.iter().map(|p| p.x).min().unwrap_or(0);
+	let y_min = points.iter().map(|p| p.y).min().unwrap_or(0);
+	let x_max = points.iter().map(|p| p.x).max().unwrap_or(0);
+	let y_max = points.iter().map(|p| p.y).max().unwrap_or(0);
+
+	let mut bbox = Vec::with_capacity(8);
+	for value in [x_min, y_min, x_max, y_max] {
+		let value =
+			i16::try_from(value).map_err(|_| WoffError::Malformed("a point is too far out"))?;
+		bbox.extend_from_slice(&value.to_be_bytes());
+	}
+	Ok(bbox)
+}
+
+/// Write points as glyf wants them: flags, then x, then y, every coordinate
+/// relative to the one before it, as small as they'll go.
+fn write_points(glyf: &mut Vec<u8>, points: &[Point], overlap: bool) -> Result<(), WoffError> {
+	const ON_CURVE: u8 = 0x01;
+	const X_SHORT: u8 = 0x02;
+	const Y_SHORT: u8 = 0x04;
+	const REPEAT: u8 = 0x08;
+	// Means the short value is positive, or the long value is the same as the last
+	const X_SAME: u8 = 0x10;
+	const Y_SAME: u8 = 0x20;
+	const OVERLAP: u8 = 0x40;
+
+	fn delta(
+		delta: i32,
+		short: u8,
+		same: u8,
+		flag: &mut u8,
+		out: &mut Vec<u8>,
+	) -> Result<(), WoffError> {
+		if delta == 0 {
+			*flag |= same;
+		} else if delta.abs() < 256 {
+			*flag |= short;
+			if delta > 0 {
+				*flag |= same;
+			}
+			out.push(delta.unsigned_abs() as u8);
+		} else {
+			let delta =
+				i16::try_from(delta).map_err(|_| WoffError::Malformed("a point is too far out"))?;
+			out.extend_from_slice(&delta.to_be_bytes());
+		}
+		Ok(())
+	}
+
+	let mut flags = Vec::with_capacity(points.len());
+	let mut xs = vec![];
+	let mut ys = vec![];
+	let (mut last_x, mut last_y) = (0, 0);
+	for (index, point) in points.iter().enumerate() {
+		let mut flag = if point.on_curve { ON_CURVE } else { 0 };
+		if overlap && index == 0 {
+			flag |= OVERLAP;
+		}
+
+		delta(point.x - last_x, X_SHORT, X_SAME, &mut flag, &mut xs)?;
+		delta(point.y - last_y, Y_SHORT, Y_SAME, &mut flag, &mut ys)?;
+		last_x = point.x;
+		last_y = point.y;
+
+		flags.push(flag);
+	}
+
+	let mut index = 0;
+	while index < flags.len() {
+		let flag = flags[index];
+		let repeats = flags[index + 1..]
+			.iter()
+			.take(255)
+			.take_while(|next| **next == flag)
+			.count();
+
+		if repeats > 0 {
+			glyf.extend_from_slice(&[flag | REPEAT, repeats as u8]);
+		} else {
+			glyf.push(flag);
+		}
+		index += 1 + repeats;
+	}
+
+	glyf.extend_from_slice(&xs);
+	glyf.extend_from_slice(&ys);
+	Ok(())
+}
+
+/// A composite glyph's components are kept as they are, we only need to find
+/// where they end and whether instructions follow.
+fn components<'a>(composites: &mut Reader<'a>) -> Result<(&'a [u8], bool), WoffError> {
+	const ARGS_ARE_WORDS: u16 = 0x0001;
+	const HAS_SCALE: u16 = 0x0008;
+	const MORE_COMPONENTS: u16 = 0x0020;
+	const HAS_XY_SCALE: u16 = 0x0040;
+	const HAS_TWO_BY_TWO: u16 = 0x0080;
+	const HAS_INSTRUCTIONS: u16 = 0x0100;
+
+	let start = composites.position;
+	let mut has_instructions = false;
+	loop {
+		let flags = composites.u16()?;
+		composites.skip(2)?; // glyph index
+
+		let arguments = if flags & ARGS_ARE_WORDS != 0 { 4 } else { 2 };
+		let transform = if flags & HAS_SCALE != 0 {
+			2
+		} else if flags & HAS_XY_SCALE != 0 {
+			4
+		} else if flags & HAS_TWO_BY_TWO != 0 {
+			8
+		} else {
+			0
+		};
+		composites.skip(arguments + transform)?;
+
+		has_instructions |= flags & HAS_INSTRUCTIONS != 0;
+		if flags & MORE_COMPONENTS == 0 {
+			break;
+		}
+	}
+
+	Ok((
+		&composites.data[start..composites.position],
+		has_instructions,
+	))
+}
+
+/// Decode a point's movement from its flag, and the one to four bytes it says
+/// follow it.
+fn triplet(flag: u8, coordinates: &mut Reader) -> Result<(i32, i32), WoffError> {
+	let flag = flag as i32;
+	let sign = |flag: i32, value: i32| if flag & 1 != 0 { value } else { -value };
+	let mut byte = || coordinates.u8().map(i32::from);
+
+	Ok(match flag {
+		0..=9 => (0, sign(flag, ((flag & 14) << 7) + byte()?)),
+		10..=19 => (sign(flag, (((flag - 10) & 14) << 7) + byte()?), 0),
+		20..=83 => {
+			let b0 = flag - 20;
+			let b1 = byte()?;
+			(
+				sign(flag, 1 + (b0 & 0x30) + (b1 >> 4)),
+				sign(flag >> 1, 1 + ((b0 & 0x0c) << 2) + (b1 & 0x0f)),
+			)
+		}
+		84..=119 => {
+			let b0 = flag - 84;
+			let (b1, b2) = (byte()?, byte()?);
+			(
+				sign(flag, 1 + ((b0 / 12) << 8) + b1),
+				sign(flag >> 1, 1 + (((b0 % 12) >> 2) << 8) + b2),
+			)
+		}
+		120..=123 => {
+			let (b1, b2, b3) = (byte()?, byte()?, byte()?);
+			(
+				sign(flag, (b1 << 4) + (b2 >> 4)),
+				sign(flag >> 1, ((b2 & 0x0f) << 8) + b3),
+			)
+		}
+		_ => {
+			let (b1, b2, b3, b4) = (byte()?, byte()?, byte()?, byte()?);
+			(sign(flag, (b1 << 8) + b2), sign(flag >> 1, (b3 << 8) + b4))
+		}
+	})
+}
+
+/// Rebuild hmtx. The transform drops left side bearings that are the same as
+/// the glyph's xMin, so those come from glyf.
+fn hmtx(
+	table: &[u8],
+	num_glyphs: usize,
+	num_hmetrics: usize,
+	x_mins: &[i16],
+) -> Result<Vec<u8>, WoffError> {
+	if num_hmetrics == 0 || num_hmetrics > num_glyphs || x_mins.len() != num_glyphs {
+		return Err(WoffError::Malformed("hmtx doesn't agree with glyf"));
+	}
+
+	let mut table = Reader::new(table);
+	let flags = table.u8()?;
+
+	let mut advances = Vec::with_capacity(num_hmetrics);
+	for _ in 0..num_hmetrics {
+		advances.push(table.u16()?);
+	}
+
+	let mut bearings = Vec::with_capacity(num_glyphs);
+	for (index, x_min) in x_mins.iter().enumerate() {
+		// The first bit is for glyphs with an advance, the second for the rest
+		let omitted = match index < num_hmetrics {
+			true => flags & 1 != 0,
+			false => flags & 2 != 0,
+		};
+
+		bearings.push(if omitted { *x_min } else { table.i16()? });
+	}
+
+	let mut hmtx = Vec::with_capacity(num_hmetrics * 2 + num_glyphs * 2);
+	for (index, bearing) in bearings.iter().enumerate() {
+		if let Some(advance) = advances.get(index) {
+			hmtx.extend_from_slice(&advance.to_be_bytes());
+		}
+		hmtx.extend_from_slice(&bearing.to_be_bytes());
+	}
+
+	Ok(hmtx)
+}
+
+/// Put the tables together into a font, with the directory and checksums it
+/// needs.
+fn sfnt(flavor: u32, mut tables: Vec<(u32, Vec<u8>)>) -> Vec<u8> {
+	tables.sort_by_key(|(tag, _)| *tag);
+
+	// Both formats count tables in a u16, so these fit too. There's always
+	// at least one table, which keeps the range shift from going negative.
+	let num_tables = tables.len() as u32;
+	let entry_selector = num_tables.ilog2();
+	let search_range = 16 * (1 << entry_selector);
+
+	let mut font = vec![];
+	font.extend_from_slice(&flavor.to_be_bytes());
+	for value in [
+		num_tables,
+		search_range,
+		entry_selector,
+		num_tables * 16 - search_range,
+	] {
+		font.extend_from_slice(&(value as u16).to_be_bytes());
+	}
+
+	let mut offset = 12 + tables.len() * 16;
+	let mut head = None;
+	for (tag, table) in &mut tables {
+		// The font's checksum goes in head, so the head checksum doesn't count it
+		if *tag == HEAD && table.len() >= 12 {
+			table[8..12].fill(0);
+			head = Some(offset);
+		}
+
+		font.extend_from_slice(&tag.to_be_bytes());
+		font.extend_from_slice(&checksum(table).to_be_bytes());
+		font.extend_from_slice(&(offset as u32).to_be_bytes());
+		font.extend_from_slice(&(table.len() as u32).to_be_bytes());
+
+		offset += (table.len() + 3) & !3;
+	}
+
+	for (_, table) in &tables {
+		font.extend_from_slice(table);
+		font.resize((font.len() + 3) & !3, 0);
+	}
+
+	if let Some(head) = head {
+		let adjustment = 0xB1B0AFBAu32.wrapping_sub(checksum(&font));
+		font[head + 8..head + 12].copy_from_slice(&adjustment.to_be_bytes());
+	}
+
+	font
+}
+
+fn checksum(data: &[u8]) -> u32 {
+	data.chunks(4).fold(0u32, |sum, chunk| {
+		let mut word = [0; 4];
+		word[..chunk.len()].copy_from_slice(chunk);
+		sum.wrapping_add(u32::from_be_bytes(word))
+	})
+}
+
+/// Reads big-endian values, as fonts have them, and errors instead of going
+/// off the end.
+struct Reader<'a> {
+	data: &'a [u8],
+	position: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn new(data: &'a [u8]) -> Self {
+		Self { data, position: 0 }
+	}
+
+	fn at(mut self, position: usize) -> Result<Self, WoffError> {
+		if position > self.data.len() {
+			return Err(WoffError::Truncated);
+		}
+
+		self.position = position;
+		Ok(self)
+	}
+
+	fn bytes(&mut self, length: usize) -> Result<&'a [u8], WoffError> {
+		let end = self
+			.position
+			.checked_add(length)
+			.ok_or(WoffError::Truncated)?;
+		let bytes = self
+			.data
+			.get(self.position..end)
+			.ok_or(WoffError::Truncated)?;
+
+		self.position = end;
+		Ok(bytes)
+	}
+
+	fn skip(&mut self, length: usize) -> Result<(), WoffError> {
+		self.bytes(length).map(|_| ())
+	}
+
+	fn u8(&mut self) -> Result<u8, WoffError> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16, WoffError> {
+		let bytes = self.bytes(2)?;
+		Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+	}
+
+	fn i16(&mut self) -> Result<i16, WoffError> {
+		self.u16().map(|value| value as i16)
+	}
+
+	fn u32(&mut self) -> Result<u32, WoffError> {
+		let bytes = self.bytes(4)?;
+		Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+	}
+
+	/// A number in up to five bytes, seven bits at a time.
+	fn base128(&mut self) -> Result<u32, WoffError> {
+		let mut value: u32 = 0;
+		for index in 0..5 {
+			let byte = self.u8()?;
+
+			if index == 0 && byte == 0x80 {
+				return Err(WoffError::Malformed("a number has leading zeros"));
+			}
+
+			if value & 0xFE00_0000 != 0 {
+				return Err(WoffError::Malformed("a number is too big"));
+			}
+
+			value = (value << 7) | (byte & 0x7f) as u32;
+			if byte & 0x80 == 0 {
+				return Ok(value);
+			}
+		}
+
+		Err(WoffError::Malformed("a number is too long"))
+	}
+
+	/// A number in one byte if it's small, or up to three if it isn't.
+	fn uint255(&mut self) -> Result<u16, WoffError> {
+		const LOWEST_UCODE: u16 = 253;
+
+		match self.u8()? {
+			253 => self.u16(),
+			254 => Ok(self.u8()? as u16 + LOWEST_UCODE * 2),
+			255 => Ok(self.u8()? as u16 + LOWEST_UCODE),
+			value => Ok(value as u16),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	use std::io::Write;
+
+	#[test]
+	fn base128() {
+		assert_eq!(Reader::new(&[0x3f]).base128().unwrap(), 63);
+		assert_eq!(Reader::new(&[0x81, 0x00]).base128().unwrap(), 128);
+		assert_eq!(
+			Reader::new(&[0x8f, 0xff, 0xff, 0xff, 0x7f])
+				.base128()
+				.unwrap(),
+			u32::MAX
+		);
+		assert!(Reader::new(&[0x80, 0x01]).base128().is_err());
+		assert!(Reader::new(&[0x90, 0x80, 0x80, 0x80, 0x00])
+			.base128()
+			.is_err());
+		assert!(Reader::new(&[0x81]).base128().is_err());
+	}
+
+	#[test]
+	fn uint255() {
+		assert_eq!(Reader::new(&[252]).uint255().unwrap(), 252);
+		assert_eq!(Reader::new(&[255, 0]).uint255().unwrap(), 253);
+		assert_eq!(Reader::new(&[254, 0]).uint255().unwrap(), 506);
+		assert_eq!(Reader::new(&[253, 0x03, 0x20]).uint255().unwrap(), 800);
+	}
+
+	#[test]
+	fn triplets() {
+		let decode = |flag, bytes: &[u8]| triplet(flag, &mut Reader::new(bytes)).unwrap();
+
+		assert_eq!(decode(0, &[10]), (0, -10));
+		assert_eq!(decode(3, &[10]), (0, 256 + 10));
+		assert_eq!(decode(11, &[10]), (10, 0));
+		assert_eq!(decode(23, &[0x21]), (3, 2));
+		assert_eq!(decode(84, &[1, 2]), (-2, -3));
+		assert_eq!(decode(121, &[0x12, 0x34, 0x56]), (0x123, -0x456));
+		assert_eq!(decode(127, &[0x12, 0x34, 0x56, 0x78]), (0x1234, 0x5678));
+		assert!(triplet(127, &mut Reader::new(&[0x12])).is_err());
+	}
+
+	#[test]
+	fn points_round_trip() {
+		let points = [
+			(10, 10, true),
+			(20, 20, true),
+			(300, 700, false),
+			(1000, -50, true),
+		]
+		.into_iter()
+		.map(|(x, y, on_curve)| Point { x, y, on_curve })
+		.collect::<Vec<_>>();
+
+		let mut glyf = vec![];
+		write_points(&mut glyf, &points, false).unwrap();
+
+		// The first two points both move a little up and right, so their flag repeats
+		let flags = [0x37 | 0x08, 1, 0x00, 0x01];
+		let xs = [10, 10, 0x01, 0x18, 0x02, 0xbc];
+		let ys = [10, 10, 0x02, 0xa8, 0xfd, 0x12];
+		assert_eq!(glyf, [&flags[..], &xs, &ys].concat());
+		assert_eq!(bounds(&points).unwrap(), [0, 10, 255, 206, 3, 232, 2, 188]);
+	}
+
+	#[test]
+	fn points_stay_in_range() {
+		// One glyph of one contour, every point 65535 further up and right
+		let count = 40_000u16;
+		let mut table = vec![0, 0, 0, 0, 0, 1, 0, 0];
+		for size in [2, 3, count as u32, count as u32 * 4 + 1, 0, 4, 0] {
+			table.extend_from_slice(&size.to_be_bytes());
+		}
+		table.extend_from_slice(&1i16.to_be_bytes());
+		table.push(253);
+		table.extend_from_slice(&count.to_be_bytes());
+		table.extend(std::iter::repeat_n(127, count as usize));
+		table.extend(std::iter::repeat_n(0xff, count as usize * 4));
+		table.push(0);
+		table.extend_from_slice(&[0; 4]);
+
+		assert!(matches!(
+			glyf(&table),
+			Err(WoffError::Malformed("a point is too far out"))
+		));
+	}
+
+	/// A table out of an sfnt's directory.
+	fn table(font: &[u8], tag: u32) -> &[u8] {
+		let count = Reader::new(font).at(4).unwrap().u16().unwrap();
+		let mut directory = Reader::new(font).at(12).unwrap();
+		for _ in 0..count {
+			let (found, _, offset, length) = (
+				directory.u32().unwrap(),
+				directory.u32().unwrap(),
+				directory.u32().unwrap() as usize,
+				directory.u32().unwrap() as usize,
+			);
+			if found == tag {
+				return &font[offset..offset + length];
+			}
+		}
+		panic!("no {:?} table", tag.to_be_bytes());
+	}
+
+	#[test]
+	fn woff2_matches_its_ttf() {
+		// Made by fixtures/make-woff2.py, with glyf and hmtx transformed
+		let woff2 = include_bytes!("../fixtures/dejavu-serif-subset.woff2");
+		let ttf = include_bytes!("../fixtures/dejavu-serif-subset.ttf");
+
+		let font = decode(woff2).unwrap();
+		for tag in [GLYF, LOCA, HMTX] {
+			assert_eq!(table(&font, tag), table(ttf, tag));
+		}
+		assert_eq!(&font[..], &ttf[..]);
+	}
+
+	#[test]
+	fn other_fonts_pass_through() {
+		let data = b"\x00\x01\x00\x00rest of a font";
+		assert!(!is_woff(data));
+		assert!(matches!(decode(data).unwrap(), Cow::Borrowed(_)));
+	}
+
+	#[test]
+	fn woff_tables() {
+		let head = vec![0x11; 54];
+		let name = b"a name table that compresses a a a a a a a a a a a a a".to_vec();
+
+		let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::best());
+		encoder.write_all(&name).unwrap();
+		let compressed = encoder.finish().unwrap();
+		assert!(compressed.len() < name.len());
+
+		let mut woff = vec![];
+		woff.extend_from_slice(b"wOFF\x00\x01\x00\x00");
+		woff.extend_from_slice(&[0; 4]);
+		woff.extend_from_slice(&2u16.to_be_bytes());
+		woff.extend_from_slice(&[0; 30]);
+		let tables = [
+			(b"name", &compressed, name.len()),
+			(b"head", &head, head.len()),
+		];
+		let mut offset = 44 + 20 * tables.len();
+		for (tag, data, length) in tables {
+			woff.extend_from_slice(tag);
+			woff.extend_from_slice(&(offset as u32).to_be_bytes());
+			woff.extend_from_slice(&(data.len() as u32).to_be_bytes());
+			woff.extend_from_slice(&(length as u32).to_be_bytes());
+			woff.extend_from_slice(&[0; 4]);
+			offset += data.len();
+		}
+		woff.extend_from_slice(&compressed);
+		woff.extend_from_slice(&head);
+
+		assert!(is_woff(&woff));
+		let font = decode(&woff).unwrap();
+
+		let mut empty = woff[..44].to_vec();
+		empty[12..14].copy_from_slice(&0u16.to_be_bytes());
+		assert!(matches!(
+			decode(&empty),
+			Err(WoffError::Malformed("there are no tables"))
+		));
+
+		let mut reader = Reader::new(&font);
+		assert_eq!(reader.u32().unwrap(), 0x00010000);
+		assert_eq!(reader.u16().unwrap(), 2);
+		assert_eq!(reader.bytes(6).unwrap(), &[0, 32, 0, 1, 0, 0]);
+
+		// Sorted by tag, so head is first
+		assert_eq!(reader.u32().unwrap(), tag(b"head"));
+		reader.skip(4).unwrap();
+		let offset = reader.u32().unwrap() as usize;
+		assert_eq!(offset, 44);
+		assert_eq!(&font[offset..offset + 8], &head[..8]);
+
+		reader.skip(4).unwrap();
+		assert_eq!(reader.u32().unwrap(), tag(b"name"));
+		reader.skip(4).unwrap();
+		let offset = reader.u32().unwrap() as usize;
+		assert_eq!(&font[offset..offset + name.len()], &name[..]);
+
+		// The whole font sums to the magic number once head's adjustment is in
+		assert_eq!(checksum(&font), 0xB1B0AFBA);
+
+		assert!(matches!(decode(&woff[..60]), Err(WoffError::Truncated)));
+	}
+}